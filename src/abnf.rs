@@ -0,0 +1,695 @@
+/*!
+ * Import of ABNF (RFC 5234) grammars
+ *
+ * Supported: rules (= and =/), alternation /, concatenation,
+ * repetition (*, n*m, n), groups ( ), optional [ ], quoted strings
+ * (case-insensitive, %s"..." case-sensitive, %i"..."), %b %d %x values,
+ * ranges and sequences, ; comments and the core rules (ALPHA, DIGIT, ...)
+ * when they are referenced but not defined. Prose values (<...>)
+ * are reported as errors.
+ *
+ * ABNF describes characters, so every terminal of the imported grammar
+ * matches a token of one character (a regex like [aA] or [\u{30}-\u{39}]).
+ * Rule names are kept as written, with '-' replaced by '_', references
+ * are matched ignoring case.
+ */
+
+use std::collections::HashMap;
+
+use grammar::{Grammar,GrammarError,Production,Component,RuleId,identifiers};
+use tokenize::Position;
+
+// RFC 5234, appendix B.1
+const CORE_RULES : &str = r#"
+ALPHA  = %x41-5A / %x61-7A
+BIT    = "0" / "1"
+CHAR   = %x01-7F
+CR     = %x0D
+CRLF   = CR LF
+CTL    = %x00-1F / %x7F
+DIGIT  = %x30-39
+DQUOTE = %x22
+HEXDIG = DIGIT / "A" / "B" / "C" / "D" / "E" / "F"
+HTAB   = %x09
+LF     = %x0A
+LWSP   = *(WSP / CRLF WSP)
+OCTET  = %x00-FF
+SP     = %x20
+VCHAR  = %x21-7E
+WSP    = SP / HTAB
+"#;
+
+// the largest upper bound of a repetition (n*m)
+const MAX_REPEAT : usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Name(String),
+    // = or =/ (true)
+    Defined(bool),
+    Slash,
+    Open,
+    Close,
+    OpenOpt,
+    CloseOpt,
+    Star,
+    Number(usize),
+    // regex patterns, one per character
+    Chars(Vec<String>),
+    Prose(String),
+}
+
+struct Token {
+    tok : Tok,
+    pos : Position,
+}
+
+enum Element {
+    Rule(String, Position),
+    Chars(Vec<String>),
+    Group(Vec<Vec<Repetition>>),
+    Optional(Vec<Vec<Repetition>>),
+}
+
+struct Repetition {
+    min : usize,
+    max : Option<usize>,
+    element : Element,
+}
+
+// rule name as first written, alternatives
+type Rule = (String, Vec<Vec<Repetition>>);
+
+// pattern matching the character c
+fn char_pattern(c : char, ignore_case : bool) -> String {
+    let lower = c.to_ascii_lowercase();
+    let upper = c.to_ascii_uppercase();
+    if ignore_case && lower != upper {
+        format!("[{}{}]", lower, upper)
+    } else if c.is_ascii_alphanumeric() {
+        c.to_string()
+    } else {
+        format!("\\u{{{:X}}}", c as u32)
+    }
+}
+
+struct Lexer<'a> {
+    chars : ::std::iter::Peekable<::std::str::Chars<'a>>,
+    pos : Position,
+    tokens : Vec<Token>,
+    errors : Vec<GrammarError>,
+}
+
+impl<'a> Lexer<'a> {
+
+    fn new(input : &'a str) -> Lexer<'a> {
+        Lexer {
+            chars : input.chars().peekable(),
+            pos : Position::new(),
+            tokens : Vec::new(),
+            errors : Vec::new(),
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().cloned()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if let Some(ch) = c {
+            self.pos.update(ch);
+        }
+        c
+    }
+
+    fn error(&mut self, pos : Position, expected : &str, found : String) {
+        self.errors.push(GrammarError::new(pos, expected, found));
+    }
+
+    // digits of the given base
+    fn number(&mut self, base : u32) -> Option<u32> {
+        let mut n : Option<u32> = None;
+        while let Some(d) = self.peek().and_then(|c| c.to_digit(base)) {
+            self.bump();
+            n = Some(n.unwrap_or(0).saturating_mul(base).saturating_add(d));
+        }
+        n
+    }
+
+    // after '%': b, d or x value, range or sequence
+    fn num_val(&mut self, pos : Position) -> Option<Tok> {
+        let base = match self.bump().map(|c| c.to_ascii_lowercase()) {
+            Some('b') => 2,
+            Some('d') => 10,
+            Some('x') => 16,
+            c => {
+                let found = c.map_or("end of input".to_string(), |c| format!("'%{}'", c));
+                self.error(pos, "%b, %d or %x", found);
+                return None;
+            }
+        };
+        let first = match self.number(base) {
+            Some(n) => n,
+            None => {
+                self.error(pos, "digits of the value", "end of value".to_string());
+                return None;
+            }
+        };
+        let mut values = vec![first];
+        match self.peek() {
+            Some('-') => {
+                self.bump();
+                let last = match self.number(base) {
+                    Some(n) => n,
+                    None => {
+                        self.error(pos, "end of value range", "'-'".to_string());
+                        return None;
+                    }
+                };
+                let lo = ::std::char::from_u32(first);
+                let hi = ::std::char::from_u32(last);
+                return match (lo, hi) {
+                    (Some(lo), Some(hi)) if lo <= hi => {
+                        let p = format!("[\\u{{{:X}}}-\\u{{{:X}}}]", lo as u32, hi as u32);
+                        Some(Tok::Chars(vec![p]))
+                    }
+                    _ => {
+                        self.error(pos, "valid value range", format!("{}-{}", first, last));
+                        None
+                    }
+                };
+            }
+            Some('.') => {
+                while self.peek() == Some('.') {
+                    self.bump();
+                    match self.number(base) {
+                        Some(n) => values.push(n),
+                        None => {
+                            self.error(pos, "value after '.'", "'.'".to_string());
+                            return None;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        let mut patterns = Vec::new();
+        for v in values {
+            match ::std::char::from_u32(v) {
+                Some(c) => patterns.push(char_pattern(c, false)),
+                None => {
+                    self.error(pos.clone(), "character value", format!("{}", v));
+                    return None;
+                }
+            }
+        }
+        Some(Tok::Chars(patterns))
+    }
+
+    // after the opening '"'
+    fn char_val(&mut self, pos : Position, ignore_case : bool) -> Option<Tok> {
+        let mut patterns = Vec::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Some(Tok::Chars(patterns)),
+                Some('\n') | None => {
+                    self.error(pos, "'\"' to end the string", "end of line".to_string());
+                    return None;
+                }
+                Some(c) => patterns.push(char_pattern(c, ignore_case)),
+            }
+        }
+    }
+
+    fn lex(mut self) -> (Vec<Token>, Vec<GrammarError>) {
+        while let Some(c) = self.peek() {
+            let pos = self.pos.clone();
+            let tok = match c {
+                ' ' | '\t' | '\r' | '\n' => {
+                    self.bump();
+                    continue;
+                }
+                ';' => {
+                    while self.peek().is_some() && self.peek() != Some('\n') {
+                        self.bump();
+                    }
+                    continue;
+                }
+                '=' => {
+                    self.bump();
+                    if self.peek() == Some('/') {
+                        self.bump();
+                        Some(Tok::Defined(true))
+                    } else {
+                        Some(Tok::Defined(false))
+                    }
+                }
+                '/' => { self.bump(); Some(Tok::Slash) }
+                '(' => { self.bump(); Some(Tok::Open) }
+                ')' => { self.bump(); Some(Tok::Close) }
+                '[' => { self.bump(); Some(Tok::OpenOpt) }
+                ']' => { self.bump(); Some(Tok::CloseOpt) }
+                '*' => { self.bump(); Some(Tok::Star) }
+                '"' => {
+                    self.bump();
+                    self.char_val(pos.clone(), true)
+                }
+                '%' => {
+                    self.bump();
+                    match self.peek() {
+                        Some('s') | Some('S') | Some('i') | Some('I') => {
+                            // %s"..." is case-sensitive, %i"..." is not (RFC 7405)
+                            let sensitive = self.bump().map(|c| c.to_ascii_lowercase()) == Some('s');
+                            if self.peek() == Some('"') {
+                                self.bump();
+                                self.char_val(pos.clone(), !sensitive)
+                            } else {
+                                self.error(pos.clone(), "'\"' after %s or %i", "no string".to_string());
+                                None
+                            }
+                        }
+                        _ => self.num_val(pos.clone()),
+                    }
+                }
+                '<' => {
+                    self.bump();
+                    let mut text = String::new();
+                    while let Some(c) = self.bump() {
+                        if c == '>' {
+                            break;
+                        }
+                        text.push(c);
+                    }
+                    Some(Tok::Prose(text))
+                }
+                '0'..='9' => self.number(10).map(|n| Tok::Number(n as usize)),
+                c if c.is_ascii_alphabetic() => {
+                    let mut name = String::new();
+                    while let Some(c) = self.peek() {
+                        if !c.is_ascii_alphanumeric() && c != '-' {
+                            break;
+                        }
+                        name.push(c);
+                        self.bump();
+                    }
+                    Some(Tok::Name(name))
+                }
+                c => {
+                    self.bump();
+                    self.error(pos.clone(), "ABNF element", format!("'{}'", c));
+                    None
+                }
+            };
+            if let Some(tok) = tok {
+                self.tokens.push(Token { tok, pos });
+            }
+        }
+        (self.tokens, self.errors)
+    }
+}
+
+// errors are recorded in Parser::errors, Err only unwinds to the rule level
+type ParseResult<T> = Result<T, ()>;
+
+struct Parser {
+    tokens : Vec<Token>,
+    next : usize,
+    errors : Vec<GrammarError>,
+    // rules in order, lowercase name -> index
+    rules : Vec<Rule>,
+    index : HashMap<String, usize>,
+}
+
+impl Parser {
+
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.next).map(|t| &t.tok)
+    }
+
+    // true at the end or at a name in the first column
+    fn at_rule_start(&self) -> bool {
+        match self.tokens.get(self.next) {
+            Some(t) => t.pos.col == 0,
+            None => true,
+        }
+    }
+
+    fn error<T>(&mut self, expected : &str) -> ParseResult<T> {
+        let e = match self.tokens.get(self.next) {
+            Some(t) => {
+                let found = match t.tok {
+                    Tok::Name(ref n) => format!("'{}'", n),
+                    Tok::Defined(false) => "'='".to_string(),
+                    Tok::Defined(true) => "'=/'".to_string(),
+                    Tok::Slash => "'/'".to_string(),
+                    Tok::Open => "'('".to_string(),
+                    Tok::Close => "')'".to_string(),
+                    Tok::OpenOpt => "'['".to_string(),
+                    Tok::CloseOpt => "']'".to_string(),
+                    Tok::Star => "'*'".to_string(),
+                    Tok::Number(n) => format!("'{}'", n),
+                    Tok::Chars(_) => "string".to_string(),
+                    Tok::Prose(ref p) => format!("<{}>", p),
+                };
+                GrammarError::new(t.pos.clone(), expected, found)
+            }
+            None => {
+                let pos = self.tokens.last().map_or(Position::new(), |t| t.pos.clone());
+                GrammarError::new(pos, expected, "end of input")
+            }
+        };
+        self.errors.push(e);
+        Err(())
+    }
+
+    fn parse(&mut self) {
+        while self.next < self.tokens.len() {
+            let start = self.next;
+            if self.parse_rule().is_err() {
+                // skip to the next rule
+                self.next = start + 1;
+                while !self.at_rule_start() {
+                    self.next += 1;
+                }
+            }
+        }
+    }
+
+    // name ('=' | '=/') alternation
+    fn parse_rule(&mut self) -> ParseResult<()> {
+        let pos = self.tokens[self.next].pos.clone();
+        let name = match self.peek() {
+            Some(Tok::Name(n)) if pos.col == 0 => n.clone(),
+            _ => return self.error("rule name at the start of a line"),
+        };
+        self.next += 1;
+        let incremental = match self.peek() {
+            Some(&Tok::Defined(incremental)) => incremental,
+            _ => return self.error("'=' or '=/'"),
+        };
+        self.next += 1;
+        let alts = self.alternation()?;
+        if !self.at_rule_start() {
+            return self.error("end of rule");
+        }
+        let key = name.to_lowercase();
+        match self.index.get(&key).cloned() {
+            Some(i) if incremental => self.rules[i].1.extend(alts),
+            None if !incremental => {
+                self.index.insert(key, self.rules.len());
+                self.rules.push((name, alts));
+            }
+            Some(_) => {
+                let e = GrammarError::new(pos, "'=/' to add alternatives", format!("'{}' defined again", name));
+                self.errors.push(e);
+            }
+            None => {
+                let e = GrammarError::new(pos, "rule defined before '=/'", format!("'{}'", name));
+                self.errors.push(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn alternation(&mut self) -> ParseResult<Vec<Vec<Repetition>>> {
+        let mut alts = vec![self.concatenation()?];
+        while self.peek() == Some(&Tok::Slash) {
+            self.next += 1;
+            alts.push(self.concatenation()?);
+        }
+        Ok(alts)
+    }
+
+    fn concatenation(&mut self) -> ParseResult<Vec<Repetition>> {
+        let mut reps = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some(&Tok::Slash) | Some(&Tok::Close) | Some(&Tok::CloseOpt) => break,
+                _ if self.at_rule_start() => break,
+                _ => reps.push(self.repetition()?),
+            }
+        }
+        if reps.is_empty() {
+            return self.error("element");
+        }
+        Ok(reps)
+    }
+
+    // [n] ['*' [m]] element
+    fn repetition(&mut self) -> ParseResult<Repetition> {
+        let count = match self.peek() {
+            Some(&Tok::Number(n)) => {
+                self.next += 1;
+                Some(n)
+            }
+            _ => None,
+        };
+        let (min, max) = if self.peek() == Some(&Tok::Star) {
+            self.next += 1;
+            let max = match self.peek() {
+                Some(&Tok::Number(m)) => {
+                    self.next += 1;
+                    Some(m)
+                }
+                _ => None,
+            };
+            (count.unwrap_or(0), max)
+        } else {
+            (count.unwrap_or(1), Some(count.unwrap_or(1)))
+        };
+        if max.map_or(min, |m| m.max(min)) > MAX_REPEAT {
+            return self.error(&format!("repetition count of at most {}", MAX_REPEAT));
+        }
+        if max.is_some_and(|m| m < min) {
+            return self.error("repetition with min <= max");
+        }
+        let element = self.element()?;
+        Ok(Repetition { min, max, element })
+    }
+
+    fn element(&mut self) -> ParseResult<Element> {
+        let pos = match self.tokens.get(self.next) {
+            Some(t) => t.pos.clone(),
+            None => return self.error("element"),
+        };
+        let element = match self.peek().cloned() {
+            Some(Tok::Name(n)) => Element::Rule(n, pos),
+            Some(Tok::Chars(patterns)) => Element::Chars(patterns),
+            Some(Tok::Open) => {
+                self.next += 1;
+                let alts = self.alternation()?;
+                if self.peek() != Some(&Tok::Close) {
+                    return self.error("')' to end the group");
+                }
+                Element::Group(alts)
+            }
+            Some(Tok::OpenOpt) => {
+                self.next += 1;
+                let alts = self.alternation()?;
+                if self.peek() != Some(&Tok::CloseOpt) {
+                    return self.error("']' to end the option");
+                }
+                Element::Optional(alts)
+            }
+            _ => return self.error("rule name, string, value, group or option"),
+        };
+        self.next += 1;
+        Ok(element)
+    }
+}
+
+// rules of an ABNF document
+fn parse_abnf(input : &str) -> (Vec<Rule>, HashMap<String, usize>, Vec<GrammarError>) {
+    let (tokens, errors) = Lexer::new(input).lex();
+    let mut p = Parser {
+        tokens,
+        next : 0,
+        errors,
+        rules : Vec::new(),
+        index : HashMap::new(),
+    };
+    p.parse();
+    (p.rules, p.index, p.errors)
+}
+
+// rule references (name as written, position) in alternatives
+fn references(alts : &[Vec<Repetition>], refs : &mut Vec<(String, Position)>) {
+    for reps in alts {
+        for rep in reps {
+            match rep.element {
+                Element::Rule(ref n, ref pos) => refs.push((n.clone(), pos.clone())),
+                Element::Group(ref a) | Element::Optional(ref a) => references(a, refs),
+                Element::Chars(_) => {}
+            }
+        }
+    }
+}
+
+// builds the productions of the rules into a Grammar
+struct Builder<'a> {
+    g : Grammar,
+    // lowercase name -> name of the definition in the grammar
+    names : &'a HashMap<String, String>,
+}
+
+impl<'a> Builder<'a> {
+
+    fn alternatives(&mut self, parent : &str, alts : &[Vec<Repetition>]) -> Vec<Production> {
+        alts.iter().map(|reps| {
+            let mut p = Production::new();
+            for rep in reps {
+                let coms = self.repetition(parent, rep);
+                p.components.extend(coms);
+            }
+            p
+        }).collect()
+    }
+
+    // a single component for a sequence
+    fn unit(&mut self, parent : &str, mut coms : Vec<Component>) -> Component {
+        if coms.len() == 1 {
+            return coms.pop().unwrap();
+        }
+        let mut p = Production::new();
+        p.components = coms;
+        self.g.add_helper(parent, vec![p])
+    }
+
+    fn element(&mut self, parent : &str, element : &Element) -> Vec<Component> {
+        match *element {
+            Element::Rule(ref n, _) => {
+                let name = self.names[&n.to_lowercase()].clone();
+                vec![Component::new(RuleId::Nonterminal(name))]
+            }
+            Element::Chars(ref patterns) => {
+                patterns.iter().map(|p| Component::new(RuleId::Regex(p.clone()))).collect()
+            }
+            Element::Group(ref alts) if alts.len() == 1 => {
+                let mut coms = Vec::new();
+                for rep in &alts[0] {
+                    let c = self.repetition(parent, rep);
+                    coms.extend(c);
+                }
+                coms
+            }
+            Element::Group(ref alts) => {
+                let prods = self.alternatives(parent, alts);
+                vec![self.g.add_helper(parent, prods)]
+            }
+            Element::Optional(ref alts) => {
+                let prods = self.alternatives(parent, alts);
+                let unit = if prods.len() == 1 {
+                    let coms = prods.into_iter().next().unwrap().components;
+                    self.unit(parent, coms)
+                } else {
+                    self.g.add_helper(parent, prods)
+                };
+                vec![self.g.add_optional(parent, unit)]
+            }
+        }
+    }
+
+    fn repetition(&mut self, parent : &str, rep : &Repetition) -> Vec<Component> {
+        let coms = self.element(parent, &rep.element);
+        if rep.min == 1 && rep.max == Some(1) {
+            return coms;
+        }
+        if rep.max == Some(0) {
+            return Vec::new();
+        }
+        let unit = self.unit(parent, coms);
+        let mut result = Vec::new();
+        match rep.max {
+            None => {
+                // n*element: n-1 copies and element+
+                for _ in 1..rep.min {
+                    result.push(unit.clone());
+                }
+                if rep.min == 0 {
+                    result.push(self.g.add_star(parent, unit));
+                } else {
+                    result.push(self.g.add_plus(parent, unit));
+                }
+            }
+            Some(max) => {
+                for _ in 0..rep.min {
+                    result.push(unit.clone());
+                }
+                // nested optionals, (x (x)?)? instead of x? x? which
+                // would give several trees for one x
+                let mut tail : Option<Component> = None;
+                for _ in rep.min..max {
+                    let com = match tail {
+                        None => unit.clone(),
+                        Some(t) => self.unit(parent, vec![unit.clone(), t]),
+                    };
+                    tail = Some(self.g.add_optional(parent, com));
+                }
+                if let Some(t) = tail {
+                    result.push(t);
+                }
+            }
+        }
+        result
+    }
+}
+
+/**
+ * Import an ABNF document as a Grammar
+ *
+ * The rule names are the nonterminals, compile(&g) compiles the result.
+ * Core rules that are referenced but not defined are added.
+ */
+pub fn load_abnf_str(input : &str) -> Result<Grammar, Vec<GrammarError>> {
+    let (mut rules, mut index, mut errors) = parse_abnf(input);
+
+    // add the core rules that are used, and the ones they use
+    let (core, core_index, _) = parse_abnf(CORE_RULES);
+    let mut core : Vec<Option<Rule>> = core.into_iter().map(Some).collect();
+    let mut checked = 0;
+    while checked < rules.len() {
+        let mut refs = Vec::new();
+        references(&rules[checked].1, &mut refs);
+        checked += 1;
+        for (name, _) in refs {
+            let key = name.to_lowercase();
+            if index.contains_key(&key) {
+                continue;
+            }
+            if let Some(&i) = core_index.get(&key) {
+                if let Some(rule) = core[i].take() {
+                    index.insert(key, rules.len());
+                    rules.push(rule);
+                }
+            }
+        }
+    }
+
+    let mut refs = Vec::new();
+    for rule in &rules {
+        references(&rule.1, &mut refs);
+    }
+    for (name, pos) in refs {
+        if !index.contains_key(&name.to_lowercase()) {
+            errors.push(GrammarError::new(pos, "defined rule", format!("'{}'", name)));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let ids = identifiers(rules.iter().map(|r| &r.0));
+    let names : HashMap<String, String> = index.iter()
+        .map(|(key, &i)| (key.clone(), ids[&rules[i].0].clone()))
+        .collect();
+    let mut b = Builder { g : Grammar::new(), names : &names };
+    for (name, alts) in &rules {
+        let name = &ids[name];
+        let prods = b.alternatives(name, alts);
+        for prod in prods {
+            b.g.add_rule(name, prod);
+        }
+    }
+    Ok(b.g)
+}
@@ -0,0 +1,435 @@
+/*!
+ * Static checks on a grammar
+ */
+
+use std::fmt;
+use std::hash::Hash;
+use std::collections::{HashMap,HashSet,VecDeque};
+
+use grammar::{Grammar,RuleId,Production};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Diagnostic {
+    // unquoted terminal that looks like a nonterminal name, or a
+    // nonterminal without productions; used_in is None for the start symbol
+    UndefinedSymbol { name : String, used_in : Option<String> },
+    // nonterminal that can not be reached from the start symbol
+    Unreachable { name : String },
+    // nonterminal that derives no sequence of terminals
+    Unproductive { name : String },
+    // production 'index' of 'nonterm' is identical to an earlier one
+    DuplicateProduction { nonterm : String, index : usize },
+    // nonterminals that derive themselves as the leftmost symbol
+    // (cycle[0] -> cycle[1] -> ... -> cycle[0]), the VM only expands
+    // them while tokens are left to match
+    LeftRecursion { cycle : Vec<String> },
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Diagnostic::UndefinedSymbol { ref name, used_in : Some(ref nt) } => {
+                write!(f, "undefined symbol {} used in {}", name, nt)
+            }
+            Diagnostic::UndefinedSymbol { ref name, used_in : None } => {
+                write!(f, "undefined start symbol {}", name)
+            }
+            Diagnostic::Unreachable { ref name } => {
+                write!(f, "nonterminal {} is unreachable", name)
+            }
+            Diagnostic::Unproductive { ref name } => {
+                write!(f, "nonterminal {} derives no terminal string", name)
+            }
+            Diagnostic::DuplicateProduction { ref nonterm, index } => {
+                write!(f, "production {} of {} is a duplicate", index, nonterm)
+            }
+            Diagnostic::LeftRecursion { ref cycle } => {
+                write!(f, "left recursion: {} -> {}", cycle.join(" -> "), cycle[0])
+            }
+        }
+    }
+}
+
+// unquoted names that are probably meant as nonterminals:
+// Capitalized identifiers and namespaced names
+fn looks_like_nonterminal(name : &str) -> bool {
+    let ident = name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == ':');
+    let upper = name.chars().next().is_some_and(|c| c.is_uppercase());
+    ident && (upper || name.contains("::"))
+}
+
+// productions of all nonterminals, sorted by nonterminal name
+fn sorted_rules(g : &Grammar) -> Vec<(String, Vec<Production>)> {
+    let mut nts = g.nonterminals();
+    nts.sort();
+    nts.into_iter().map(|nt| {
+        let prods = g.iter_over_nonterm(&nt);
+        (nt, prods)
+    }).collect()
+}
+
+fn same_production(a : &Production, b : &Production) -> bool {
+    a.name == b.name && a.components.len() == b.components.len()
+        && a.components.iter().zip(b.components.iter())
+            .all(|(x, y)| x.name == y.name && x.rule == y.rule)
+}
+
+/**
+ * Terminal that can start or follow a nonterminal
+ *
+ * Grammar uses the terminal strings, regex patterns and class names,
+ * CompiledGrammar the string, regex and class indexes
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Lookahead<T> {
+    Terminal(T),
+    Regex(T),
+    // token class (<NAME>)
+    Class(T),
+    // end of input (only in FOLLOW sets)
+    End,
+}
+
+// production component for the set computations
+pub enum Symbol<T> {
+    Nonterminal(T),
+    Terminal(Lookahead<T>),
+}
+
+// nonterminal -> right hand sides of its productions
+pub type Rules<T> = Vec<(T, Vec<Vec<Symbol<T>>>)>;
+
+// nonterminals that derive the empty sequence
+pub fn nullable_of<T>(rules : &Rules<T>) -> HashSet<T>
+    where T : Clone + Eq + Hash {
+    let mut nullable : HashSet<T> = HashSet::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (nt, rhss) in rules {
+            if nullable.contains(nt) {
+                continue;
+            }
+            let derives_empty = rhss.iter().any(|rhs| {
+                rhs.iter().all(|sym| match *sym {
+                    Symbol::Nonterminal(ref n) => nullable.contains(n),
+                    _ => false,
+                })
+            });
+            if derives_empty {
+                nullable.insert(nt.clone());
+                changed = true;
+            }
+        }
+    }
+    nullable
+}
+
+// add FIRST of the sequence to 'set', returns true if the sequence is nullable
+fn first_of_seq<T>(seq : &[Symbol<T>],
+                   nullable : &HashSet<T>,
+                   first : &HashMap<T, HashSet<Lookahead<T>>>,
+                   set : &mut HashSet<Lookahead<T>>) -> bool
+    where T : Clone + Eq + Hash {
+    for sym in seq {
+        match *sym {
+            Symbol::Terminal(ref t) => {
+                set.insert(t.clone());
+                return false;
+            }
+            Symbol::Nonterminal(ref n) => {
+                if let Some(f) = first.get(n) {
+                    set.extend(f.iter().cloned());
+                }
+                if !nullable.contains(n) {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+// nonterminal -> terminals that can start it
+pub fn first_of<T>(rules : &Rules<T>, nullable : &HashSet<T>) -> HashMap<T, HashSet<Lookahead<T>>>
+    where T : Clone + Eq + Hash {
+    let mut first : HashMap<T, HashSet<Lookahead<T>>> = HashMap::new();
+    for (nt, _) in rules {
+        first.insert(nt.clone(), HashSet::new());
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (nt, rhss) in rules {
+            let mut set = first[nt].clone();
+            for rhs in rhss {
+                first_of_seq(rhs, nullable, &first, &mut set);
+            }
+            if set.len() != first[nt].len() {
+                first.insert(nt.clone(), set);
+                changed = true;
+            }
+        }
+    }
+    first
+}
+
+// nonterminal -> terminals that can follow it when parsing starts with 'start'
+pub fn follow_of<T>(rules : &Rules<T>,
+                    nullable : &HashSet<T>,
+                    first : &HashMap<T, HashSet<Lookahead<T>>>,
+                    start : &T) -> HashMap<T, HashSet<Lookahead<T>>>
+    where T : Clone + Eq + Hash {
+    let mut follow : HashMap<T, HashSet<Lookahead<T>>> = HashMap::new();
+    for (nt, _) in rules {
+        follow.insert(nt.clone(), HashSet::new());
+    }
+    if let Some(set) = follow.get_mut(start) {
+        set.insert(Lookahead::End);
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (nt, rhss) in rules {
+            for rhs in rhss {
+                for (i, sym) in rhs.iter().enumerate() {
+                    if let Symbol::Nonterminal(ref n) = *sym {
+                        let mut set = match follow.get(n) {
+                            Some(set) => set.clone(),
+                            None => continue,
+                        };
+                        if first_of_seq(&rhs[i + 1..], nullable, first, &mut set) {
+                            set.extend(follow[nt].iter().cloned());
+                        }
+                        if set.len() != follow[n].len() {
+                            follow.insert(n.clone(), set);
+                            changed = true;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    follow
+}
+
+fn grammar_rules(g : &Grammar) -> Rules<String> {
+    sorted_rules(g).into_iter().map(|(nt, prods)| {
+        let rhss = prods.iter().map(|p| {
+            // predicates match no tokens
+            p.components.iter().filter(|c| c.predicate.is_none()).map(|c| match c.rule {
+                RuleId::Nonterminal(ref s) => Symbol::Nonterminal(s.clone()),
+                RuleId::Terminal(ref s) => Symbol::Terminal(Lookahead::Terminal(s.clone())),
+                RuleId::Regex(ref s) => Symbol::Terminal(Lookahead::Regex(s.clone())),
+                RuleId::Class(ref s) => Symbol::Terminal(Lookahead::Class(s.clone())),
+            }).collect()
+        }).collect();
+        (nt, rhss)
+    }).collect()
+}
+
+fn productive_set(rules : &[(String, Vec<Production>)]) -> HashSet<String> {
+    let mut productive : HashSet<String> = HashSet::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (nt, prods) in rules {
+            if productive.contains(nt) {
+                continue;
+            }
+            let derives = prods.iter().any(|p| {
+                p.components.iter().all(|c| match c.rule {
+                    RuleId::Nonterminal(ref s) => productive.contains(s),
+                    _ => true,
+                })
+            });
+            if derives {
+                productive.insert(nt.clone());
+                changed = true;
+            }
+        }
+    }
+    productive
+}
+
+// nt -> nonterminals that can be the leftmost symbol of a production
+fn left_corners(rules : &[(String, Vec<Production>)], nullable : &HashSet<String>)
+    -> HashMap<String, Vec<String>> {
+    let mut edges : HashMap<String, Vec<String>> = HashMap::new();
+    for (nt, prods) in rules {
+        let mut targets : Vec<String> = Vec::new();
+        for p in prods {
+            for c in &p.components {
+                match c.rule {
+                    RuleId::Nonterminal(ref s) => {
+                        if !targets.contains(s) {
+                            targets.push(s.clone());
+                        }
+                        if c.predicate.is_none() && !nullable.contains(s) {
+                            break;
+                        }
+                    }
+                    _ => break,
+                }
+            }
+        }
+        targets.sort();
+        edges.insert(nt.clone(), targets);
+    }
+    edges
+}
+
+// shortest path from 'from' back to 'from', without the final node
+fn find_cycle(edges : &HashMap<String, Vec<String>>, from : &str) -> Option<Vec<String>> {
+    let mut prev : HashMap<String, String> = HashMap::new();
+    let mut queue : VecDeque<String> = VecDeque::new();
+    queue.push_back(from.to_string());
+    while let Some(n) = queue.pop_front() {
+        for t in edges.get(&n).map_or(&[][..], |v| &v[..]) {
+            if t == from {
+                let mut cycle = vec![n.clone()];
+                let mut cur = n.clone();
+                while cur != from {
+                    cur = prev[&cur].clone();
+                    cycle.push(cur.clone());
+                }
+                cycle.reverse();
+                return Some(cycle);
+            }
+            if !prev.contains_key(t) {
+                prev.insert(t.clone(), n.clone());
+                queue.push_back(t.clone());
+            }
+        }
+    }
+    None
+}
+
+impl Grammar {
+
+    /**
+     * Check the grammar, start is the symbol parsing begins with
+     */
+    pub fn analyze(&self, start : &str) -> Vec<Diagnostic> {
+        analyze(self, start)
+    }
+
+    /**
+     * Nonterminals that can derive the empty sequence
+     */
+    pub fn nullable(&self) -> HashSet<String> {
+        nullable_of(&grammar_rules(self))
+    }
+
+    /**
+     * FIRST sets: nonterminal -> terminals that can start it
+     */
+    pub fn first_sets(&self) -> HashMap<String, HashSet<Lookahead<String>>> {
+        let rules = grammar_rules(self);
+        first_of(&rules, &nullable_of(&rules))
+    }
+
+    /**
+     * FOLLOW sets: nonterminal -> terminals that can follow it,
+     * Lookahead::End if it can end the input parsed from start
+     */
+    pub fn follow_sets(&self, start : &str) -> HashMap<String, HashSet<Lookahead<String>>> {
+        let rules = grammar_rules(self);
+        let nullable = nullable_of(&rules);
+        let first = first_of(&rules, &nullable);
+        follow_of(&rules, &nullable, &first, &start.to_string())
+    }
+}
+
+fn analyze(g : &Grammar, start : &str) -> Vec<Diagnostic> {
+    let rules = sorted_rules(g);
+    let mut diags : Vec<Diagnostic> = Vec::new();
+
+    // undefined symbols
+    let mut bare = g.bare_terminals();
+    bare.retain(|n| looks_like_nonterminal(n));
+    if !g.is_nonterminal(start) {
+        diags.push(Diagnostic::UndefinedSymbol { name : start.to_string(), used_in : None });
+    }
+    for (nt, prods) in &rules {
+        let mut seen : HashSet<&String> = HashSet::new();
+        for p in prods {
+            for c in &p.components {
+                let undefined = match c.rule {
+                    RuleId::Terminal(ref s) => bare.contains(s),
+                    RuleId::Nonterminal(ref s) => !g.is_nonterminal(s),
+                    _ => false,
+                };
+                let name = match c.rule {
+                    RuleId::Terminal(ref s) | RuleId::Nonterminal(ref s) => s,
+                    RuleId::Regex(ref s) | RuleId::Class(ref s) => s,
+                };
+                if undefined && seen.insert(name) {
+                    diags.push(Diagnostic::UndefinedSymbol {
+                        name : name.clone(),
+                        used_in : Some(nt.clone()),
+                    });
+                }
+            }
+        }
+    }
+
+    // unreachable from start
+    let mut reachable : HashSet<String> = HashSet::new();
+    let mut queue : VecDeque<String> = VecDeque::new();
+    if g.is_nonterminal(start) {
+        reachable.insert(start.to_string());
+        queue.push_back(start.to_string());
+    }
+    while let Some(nt) = queue.pop_front() {
+        for p in g.iter_over_nonterm(&nt) {
+            for c in &p.components {
+                if let RuleId::Nonterminal(ref s) = c.rule {
+                    if reachable.insert(s.clone()) {
+                        queue.push_back(s.clone());
+                    }
+                }
+            }
+        }
+    }
+    for (nt, _) in &rules {
+        if !reachable.contains(nt) {
+            diags.push(Diagnostic::Unreachable { name : nt.clone() });
+        }
+    }
+
+    // unproductive
+    let productive = productive_set(&rules);
+    for (nt, _) in &rules {
+        if !productive.contains(nt) {
+            diags.push(Diagnostic::Unproductive { name : nt.clone() });
+        }
+    }
+
+    // duplicate productions
+    for (nt, prods) in &rules {
+        for (i, p) in prods.iter().enumerate() {
+            if prods[..i].iter().any(|q| same_production(p, q)) {
+                diags.push(Diagnostic::DuplicateProduction { nonterm : nt.clone(), index : i });
+            }
+        }
+    }
+
+    // left recursion, reported once per set of mutually recursive nonterminals
+    let nullable = nullable_of(&grammar_rules(g));
+    let edges = left_corners(&rules, &nullable);
+    let mut reported : HashSet<String> = HashSet::new();
+    for (nt, _) in &rules {
+        if reported.contains(nt) {
+            continue;
+        }
+        if let Some(cycle) = find_cycle(&edges, nt) {
+            for n in &cycle {
+                reported.insert(n.clone());
+            }
+            diags.push(Diagnostic::LeftRecursion { cycle });
+        }
+    }
+
+    diags
+}
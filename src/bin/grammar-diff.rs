@@ -0,0 +1,113 @@
+/*!
+ * grammar-diff: compare two grammars
+ *
+ *   grammar-diff [--start NAME] [--samples N] [--seed N] OLD NEW
+ *
+ * Prints the added (+), removed (-) and changed (~) nonterminals, then
+ * the sampled inputs that only one of the grammars accepts. The
+ * start nonterminal defaults to the first %start of OLD. Files ending
+ * in .abnf, .y or .ebnf are read with the matching front-end.
+ *
+ * Exits with 0 if the grammars are the same, 1 if they differ and 2
+ * on errors.
+ */
+
+extern crate rustparser;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use rustparser::Grammar;
+use rustparser::{load_grammar_file, load_abnf_str, load_yacc_str, load_ebnf_str};
+use rustparser::sample_differences;
+
+const USAGE : &str = "usage: grammar-diff [--start NAME] [--samples N] [--seed N] OLD NEW";
+
+fn fail(message : &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(2);
+}
+
+fn load(path : &str) -> Grammar {
+    let read = || match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => fail(&format!("{}: {}", path, e)),
+    };
+    let result = if path.ends_with(".abnf") {
+        load_abnf_str(&read())
+    } else if path.ends_with(".y") {
+        load_yacc_str(&read())
+    } else if path.ends_with(".ebnf") {
+        load_ebnf_str(&read())
+    } else {
+        load_grammar_file(path)
+    };
+    match result {
+        Ok(g) => g,
+        Err(errors) => {
+            for e in errors {
+                eprintln!("{}: {}", path, e);
+            }
+            process::exit(2);
+        }
+    }
+}
+
+fn number(value : Option<String>, option : &str) -> u64 {
+    match value.map(|v| v.parse::<u64>()) {
+        Some(Ok(n)) => n,
+        _ => fail(&format!("{} needs a number\n{}", option, USAGE)),
+    }
+}
+
+fn main() {
+    let mut start : Option<String> = None;
+    let mut samples = 100;
+    let mut seed = 1;
+    let mut files = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--start" => start = Some(args.next().unwrap_or_else(|| fail(USAGE))),
+            "--samples" => samples = number(args.next(), "--samples") as usize,
+            "--seed" => seed = number(args.next(), "--seed"),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => files.push(arg),
+        }
+    }
+    if files.len() != 2 {
+        fail(USAGE);
+    }
+    let old = load(&files[0]);
+    let new = load(&files[1]);
+
+    let diff = old.diff(&new);
+    print!("{}", diff);
+    let mut differ = !diff.is_empty();
+
+    let start = start.or_else(|| old.start_symbols().first().cloned());
+    match start {
+        Some(start) => {
+            let differences = match sample_differences(&old, &new, &start, samples, seed) {
+                Ok(differences) => differences,
+                Err(errors) => {
+                    for e in errors {
+                        eprintln!("{}", e);
+                    }
+                    process::exit(2);
+                }
+            };
+            for sample in differences {
+                let file = if sample.first_only { &files[0] } else { &files[1] };
+                println!("only {} accepts: {}", file, sample.tokens.join(" "));
+                differ = true;
+            }
+        }
+        None => eprintln!("no start nonterminal (--start NAME), inputs not sampled"),
+    }
+    process::exit(if differ { 1 } else { 0 });
+}
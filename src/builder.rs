@@ -0,0 +1,93 @@
+/*!
+ * Building grammars in code
+ *
+ *   let g = rule("Expr").seq(["Term", "'+'", "Expr"]).event("add")
+ *       .rule("Expr").seq(["Term"])
+ *       .rule("Term").seq(["/[0-9]+/"])
+ *       .build()?;
+ *   let cg = compile(&g)?;
+ */
+
+use grammar::{Grammar,GrammarError,Production,load_grammar_parts};
+
+pub struct GrammarBuilder {
+    // nonterminal, items and production (event name, doc)
+    // of each alternative in order
+    rules : Vec<(String, Vec<String>, Production)>,
+    // start nonterminals
+    starts : Vec<String>,
+}
+
+impl Default for GrammarBuilder {
+    fn default() -> GrammarBuilder {
+        GrammarBuilder::new()
+    }
+}
+
+impl GrammarBuilder {
+
+    pub fn new() -> GrammarBuilder {
+        GrammarBuilder { rules : Vec::new(), starts : Vec::new() }
+    }
+
+    // start a new alternative of nonterminal 'nonterm'
+    pub fn rule(mut self, nonterm : &str) -> GrammarBuilder {
+        self.rules.push((nonterm.to_string(), Vec::new(), Production::new()));
+        self
+    }
+
+    // the last alternative of the nonterminal
+    fn current(&mut self) -> &mut (String, Vec<String>, Production) {
+        if self.rules.is_empty() {
+            // reported as a missing nonterminal name by build()
+            self.rules.push((String::new(), Vec::new(), Production::new()));
+        }
+        self.rules.last_mut().unwrap()
+    }
+
+    /**
+     * Append items to the current alternative
+     *
+     * Items are written as in a grammar file: names, quoted
+     * terminals, /regex/, [class], groups, ?, *, + and (name)
+     */
+    pub fn seq<I, S>(mut self, items : I) -> GrammarBuilder
+        where I : IntoIterator<Item = S>, S : AsRef<str> {
+        for item in items {
+            self.current().1.push(item.as_ref().to_string());
+        }
+        self
+    }
+
+    // event name of the current alternative (`name` in a grammar file)
+    pub fn event(mut self, name : &str) -> GrammarBuilder {
+        self.current().2.name = Some(name.to_string());
+        self
+    }
+
+    // documentation of the current alternative
+    pub fn doc(mut self, text : &str) -> GrammarBuilder {
+        self.current().2.doc = Some(text.to_string());
+        self
+    }
+
+    // declare a start nonterminal (%start in a grammar file)
+    pub fn start(mut self, nonterm : &str) -> GrammarBuilder {
+        self.starts.push(nonterm.to_string());
+        self
+    }
+
+    /**
+     * Validate the rules and resolve the names like load_grammar_str
+     *
+     * GrammarError::nonterm is the nonterminal of the rule with the error
+     */
+    pub fn build(self) -> Result<Grammar, Vec<GrammarError>> {
+        load_grammar_parts(&self.rules, &self.starts)
+    }
+}
+
+// start a builder with an alternative of 'nonterm'
+pub fn rule(nonterm : &str) -> GrammarBuilder {
+    GrammarBuilder::new().rule(nonterm)
+}
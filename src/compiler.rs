@@ -1,9 +1,11 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::error::Error;
+use std::collections::{HashMap,HashSet};
 use std::path::Path;
-use std::io::prelude::*;
-use grammar::{RuleId,load_grammar_str};
+use grammar::{Grammar,RuleId,GrammarError,Assoc,Predicate,Normalization,load_grammar_str,load_grammar_file};
+use regex::Regex;
+use tokenize::Position;
+use matchers::Matchers;
+use lexer::{Lexer, Lexeme, LexError};
+use analysis::{Lookahead,Symbol,Rules,nullable_of,first_of,follow_of};
 
 #[derive(Debug, Clone)]
 pub enum Opcode {
@@ -17,7 +19,37 @@ pub enum Opcode {
     // Match:
     //   validx - value to match
     //   nameidx - variable name
-    Match { validx : usize, nameidx : Option<usize> },
+    //   fold - compare case folded ('value'i or %normalize casefold)
+    Match { validx : usize, nameidx : Option<usize>, fold : bool },
+    // MatchRegex:
+    //   reidx - index of the regular expression
+    //   nameidx - variable name
+    MatchRegex { reidx : usize, nameidx : Option<usize> },
+    // MatchClass:
+    //   classidx - index of the token class name
+    //   nameidx - variable name
+    MatchClass { classidx : usize, nameidx : Option<usize> },
+    // And: continue if nonterminal ntidx matches at the current
+    // token (no tokens are consumed, nothing is added to the tree)
+    And { ntidx: usize },
+    // Not: continue if nonterminal ntidx does not match
+    Not { ntidx: usize },
+}
+
+/**
+ * Nonterminal a parse can start with, see CompiledGrammar::entry_point
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EntryPoint {
+    // string index of the nonterminal
+    ntidx : usize,
+}
+
+impl EntryPoint {
+    // string index of the nonterminal name
+    pub fn index(&self) -> usize {
+        self.ntidx
+    }
 }
 
 pub struct CompiledGrammar {
@@ -25,15 +57,46 @@ pub struct CompiledGrammar {
     nt_names : HashMap<usize, Vec<usize>>,
     pub strings : Vec<String>,
     opcodes : Vec<Opcode>,
+    // regular expressions used by MatchRegex
+    regexes : Vec<Regex>,
+    // token class names used by MatchClass
+    classes : Vec<String>,
+    // string indexes of generated nonterminals
+    // (not reported to the StreamingHandler)
+    hidden : HashSet<usize>,
+    // nonterminals declared with %start
+    entry_points : Vec<EntryPoint>,
+    // Return address -> precedence level and associativity
+    // of the production
+    precedence : HashMap<usize, (usize, Assoc)>,
+    // address -> least number of tokens matched from the address
+    // to the Return (usize::MAX if nothing can be matched)
+    min_tokens : Vec<usize>,
+    // %normalize options of the grammar
+    normalization : Normalization,
+    // lexer of the %token and %skip declarations
+    lexer : Lexer,
+    // functions of the <NAME> terminals (compile_with_matchers)
+    matchers : Option<Matchers>,
 }
 
 impl CompiledGrammar {
 
+    #[allow(clippy::new_without_default)]
     pub fn new() -> CompiledGrammar {
         CompiledGrammar {
             strings : Vec::new(),
             opcodes : Vec::new(),
+            regexes : Vec::new(),
+            classes : Vec::new(),
             nt_names : HashMap::new(),
+            hidden : HashSet::new(),
+            entry_points : Vec::new(),
+            precedence : HashMap::new(),
+            min_tokens : Vec::new(),
+            normalization : Normalization::default(),
+            lexer : Lexer::new(),
+            matchers : None,
         }
     }
 
@@ -46,6 +109,36 @@ impl CompiledGrammar {
        self.strings[idx].clone()
     }
 
+    // return the regular expression used by MatchRegex
+    pub fn regex(&self, reidx: usize) -> &Regex {
+        &self.regexes[reidx]
+    }
+
+    pub fn regex_count(&self) -> usize {
+        self.regexes.len()
+    }
+
+    // token class names used by MatchClass, by class index
+    pub fn classes(&self) -> Vec<String> {
+        self.classes.clone()
+    }
+
+    // functions registered for the token classes
+    pub fn matchers(&self) -> Option<&Matchers> {
+        self.matchers.as_ref()
+    }
+
+    // lexer built from the %token and %skip declarations
+    // and the literal terminals
+    pub fn lexer(&self) -> &Lexer {
+        &self.lexer
+    }
+
+    // tokens of text (see vm::parse_str)
+    pub fn lex(&self, text : &str) -> Result<Vec<Lexeme>, LexError> {
+        self.lexer.lex(text)
+    }
+
     // return a list of addresses associated with a nonterm name
     // TODO: remove the .clone()
     pub fn lookup_nonterm_idx(&self, ntidx: usize) -> Vec<usize> {
@@ -56,6 +149,7 @@ impl CompiledGrammar {
     }
 
     // print all opcodes in this grammar
+    #[allow(clippy::explicit_counter_loop)]
     pub fn display(&self) {
         let mut ip = 0;
         for op in &self.opcodes {
@@ -64,10 +158,154 @@ impl CompiledGrammar {
         }
     }
 
+    // string indexes of nonterminals hidden from the parse tree events
+    pub fn hidden(&self) -> HashSet<usize> {
+        self.hidden.clone()
+    }
+
+    // the nonterminals declared with %start
+    pub fn entry_points(&self) -> Vec<EntryPoint> {
+        self.entry_points.clone()
+    }
+
+    // handle to start parsing with nonterminal 'name', only declared
+    // start nonterminals if the grammar has a %start declaration
+    pub fn entry_point(&self, name : &str) -> Option<EntryPoint> {
+        let ntidx = self.lookup_string(name)?;
+        let entry = EntryPoint { ntidx };
+        if self.entry_points.is_empty() {
+            if self.nt_names.contains_key(&ntidx) { Some(entry) } else { None }
+        } else if self.entry_points.contains(&entry) {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+
+    // address of the Return that ends the production containing 'ip'
+    pub fn return_addr(&self, ip : usize) -> usize {
+        let mut addr = ip;
+        loop {
+            if let Opcode::Return { .. } = self.opcodes[addr] {
+                return addr;
+            }
+            addr += 1;
+        }
+    }
+
+    // how terminals and tokens are normalized before matching
+    pub fn normalization(&self) -> Normalization {
+        self.normalization
+    }
+
+    // true if some terminal only matches with the token text
+    // (/regex/ and <NAME> terminals, see Input::text)
+    pub fn needs_token_text(&self) -> bool {
+        self.opcodes.iter().any(|op| matches!(*op, Opcode::MatchRegex { .. } | Opcode::MatchClass { .. }))
+    }
+
+    // literal terminals of the Match opcodes: string index and fold
+    pub fn literals(&self) -> Vec<(usize, bool)> {
+        let mut literals = Vec::new();
+        for op in &self.opcodes {
+            if let Opcode::Match { validx, fold, .. } = *op {
+                if !literals.contains(&(validx, fold)) {
+                    literals.push((validx, fold));
+                }
+            }
+        }
+        literals
+    }
+
+    // least number of tokens the opcodes from 'ip' to the Return match
+    pub fn min_tokens(&self, ip : usize) -> usize {
+        self.min_tokens[ip]
+    }
+
+    // fill min_tokens, the nonterminals first (fixpoint iteration)
+    fn compute_min_tokens(&mut self) {
+        let tail = |opcodes : &[Opcode], addr : usize, nts : &HashMap<usize, usize>| {
+            let mut n : usize = 0;
+            for op in &opcodes[addr..] {
+                n = n.saturating_add(match *op {
+                    Opcode::Return { .. } => break,
+                    Opcode::Fork { ntidx, .. } => nts.get(&ntidx).cloned().unwrap_or(usize::MAX),
+                    Opcode::Match { .. } | Opcode::MatchRegex { .. } | Opcode::MatchClass { .. } => 1,
+                    Opcode::And { .. } | Opcode::Not { .. } => 0,
+                });
+            }
+            n
+        };
+        let mut nts : HashMap<usize, usize> = HashMap::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (&ntidx, addrs) in &self.nt_names {
+                let min = addrs.iter().map(|&a| tail(&self.opcodes, a, &nts)).min().unwrap_or(usize::MAX);
+                if min < nts.get(&ntidx).cloned().unwrap_or(usize::MAX) {
+                    nts.insert(ntidx, min);
+                    changed = true;
+                }
+            }
+        }
+        self.min_tokens = (0..self.opcodes.len()).map(|ip| tail(&self.opcodes, ip, &nts)).collect();
+    }
+
+    // precedence of the production ending with the Return at 'addr'
+    pub fn precedence(&self, addr : usize) -> Option<(usize, Assoc)> {
+        self.precedence.get(&addr).cloned()
+    }
+
     pub fn lookup_string(&self, s: &str) -> Option<usize> {
         self.strings.iter().position(|x| x == s)
     }
 
+    // right hand sides of all productions, read back from the opcodes
+    fn rules(&self) -> Rules<usize> {
+        let mut ntidxs : Vec<usize> = self.nt_names.keys().cloned().collect();
+        ntidxs.sort();
+        ntidxs.into_iter().map(|ntidx| {
+            let rhss = self.nt_names[&ntidx].iter().map(|&addr| {
+                let mut rhs = Vec::new();
+                let mut ip = addr;
+                loop {
+                    match self.opcodes[ip] {
+                        Opcode::Return { .. } => break,
+                        Opcode::Fork { ntidx, .. } => rhs.push(Symbol::Nonterminal(ntidx)),
+                        Opcode::Match { validx, .. } => rhs.push(Symbol::Terminal(Lookahead::Terminal(validx))),
+                        Opcode::MatchRegex { reidx, .. } => rhs.push(Symbol::Terminal(Lookahead::Regex(reidx))),
+                        Opcode::MatchClass { classidx, .. } => rhs.push(Symbol::Terminal(Lookahead::Class(classidx))),
+                        // predicates match no tokens
+                        Opcode::And { .. } | Opcode::Not { .. } => {}
+                    }
+                    ip += 1;
+                }
+                rhs
+            }).collect();
+            (ntidx, rhss)
+        }).collect()
+    }
+
+    // string indexes of the nonterminals that can derive the empty sequence
+    pub fn nullable(&self) -> HashSet<usize> {
+        nullable_of(&self.rules())
+    }
+
+    // FIRST sets by string index, terminals are Match validx / MatchRegex reidx
+    // / MatchClass classidx
+    pub fn first_sets(&self) -> HashMap<usize, HashSet<Lookahead<usize>>> {
+        let rules = self.rules();
+        first_of(&rules, &nullable_of(&rules))
+    }
+
+    // FOLLOW sets by string index when parsing starts with nonterminal 'start'
+    pub fn follow_sets(&self, start : usize) -> HashMap<usize, HashSet<Lookahead<usize>>> {
+        let rules = self.rules();
+        let nullable = nullable_of(&rules);
+        let first = first_of(&rules, &nullable);
+        follow_of(&rules, &nullable, &first, &start)
+    }
+
     fn add_string(&mut self, s : &str) -> usize {
         let idx = self.strings.iter().position(|x| x == s);
         match idx {
@@ -99,6 +337,7 @@ impl CompiledGrammar {
     //
     // name: optional name for the production
     //
+    #[allow(clippy::ptr_arg, clippy::redundant_field_names)]
     fn op_return(&mut self, ntname: &String, name : Option<&String>) {
         let nameidx = name.map(|s| self.add_string(s));
         let ntnameidx = self.add_string(ntname);
@@ -123,27 +362,163 @@ impl CompiledGrammar {
         });
     }
 
+    //
+    // Generate AND or NOT instruction
+    //
+    // nonterm_name - nonterm to test at the current token
+    //
+    fn op_predicate(&mut self, nonterm_name : &str, predicate : Predicate) {
+        let ntidx = self.add_string(nonterm_name);
+        self.opcodes.push(match predicate {
+            Predicate::And => Opcode::And { ntidx },
+            Predicate::Not => Opcode::Not { ntidx },
+        });
+    }
+
     //
     // Generate MATCH instruction
     //
     // value - value to be matched
     // var_name_opt - name for the value
+    // fold - compare case folded
     //
-    fn op_match(&mut self, value : &str, var_name_opt : Option<&String>) {
+    fn op_match(&mut self, value : &str, var_name_opt : Option<&String>, fold : bool) {
         let value_id = self.add_string(value);
-        let var_name_id = var_name_opt.map(|v| { self.add_string(&v) });
-        self.opcodes.push(Opcode::Match { validx: value_id, nameidx: var_name_id } );
+        let var_name_id = var_name_opt.map(|v| self.add_string(v));
+        self.opcodes.push(Opcode::Match { validx: value_id, nameidx: var_name_id, fold } );
+    }
+
+    //
+    // Generate MATCHREGEX instruction
+    //
+    // pattern - regular expression
+    // var_name_opt - name for the value
+    //
+    // fails with the reason if the pattern is not valid
+    //
+    fn op_match_regex(&mut self, pattern : &str, var_name_opt : Option<&String>) -> Result<(), String> {
+        let reidx = match self.regexes.iter().position(|r| r.as_str() == pattern) {
+            Some(i) => i,
+            None => {
+                self.regexes.push(Regex::new(pattern)?);
+                self.regexes.len() - 1
+            }
+        };
+        let var_name_id = var_name_opt.map(|v| self.add_string(v));
+        self.opcodes.push(Opcode::MatchRegex { reidx, nameidx: var_name_id } );
+        Ok(())
+    }
+
+    //
+    // Generate MATCHCLASS instruction
+    //
+    // class - token class name
+    // var_name_opt - name for the value
+    //
+    fn op_match_class(&mut self, class : &str, var_name_opt : Option<&String>) {
+        let classidx = match self.classes.iter().position(|c| c == class) {
+            Some(i) => i,
+            None => {
+                self.classes.push(class.to_string());
+                self.classes.len() - 1
+            }
+        };
+        let var_name_id = var_name_opt.map(|v| self.add_string(v));
+        self.opcodes.push(Opcode::MatchClass { classidx, nameidx: var_name_id } );
     }
 
 }
 
-pub fn compile_grammar(gs : &str) -> CompiledGrammar {
+pub fn compile_grammar(gs : &str) -> Result<CompiledGrammar, Vec<GrammarError>> {
     // compile string to a structured grammar
-    let g = load_grammar_str(gs);
+    let g = load_grammar_str(gs)?;
+    compile(&g)
+}
+
+// error for an invalid regular expression of rule 'nonterm'
+fn regex_error(nonterm : Option<&str>, pattern : &str, why : String) -> GrammarError {
+    let mut e = GrammarError::new(Position::new(), "regular expression",
+                                  format!("/{}/ ({})", pattern, why));
+    e.nonterm = nonterm.map(|s| s.to_string());
+    e
+}
+
+//
+// compile a grammar loaded from a file or built in code
+//
+// grammars built with the Grammar methods are not checked by a loader,
+// invalid regular expressions (in rules, %token and %skip) are errors;
+// every <NAME> terminal must be declared with %token (see
+// compile_with_matchers)
+//
+pub fn compile(g : &Grammar) -> Result<CompiledGrammar, Vec<GrammarError>> {
+    compile_classes(g, None)
+}
+
+//
+// compile a grammar whose token classes are matched by 'matchers'
+//
+// every <NAME> of the grammar must be registered or declared with
+// %token (registered functions come first); the compiled grammar keeps
+// the matchers for the run functions
+//
+pub fn compile_with_matchers(g : &Grammar, matchers : Matchers) -> Result<CompiledGrammar, Vec<GrammarError>> {
+    compile_classes(g, Some(matchers))
+}
+
+// errors for the unknown token classes 'unknown' (class, rule),
+// at the <NAME> terminals the loader found
+fn class_errors(g : &Grammar, unknown : &[(String, String)]) -> Vec<GrammarError> {
+    let expected = "token class declared with %token or registered";
+    let uses = g.class_uses();
+    let mut errors = Vec::new();
+    for (class, nt) in unknown {
+        if errors.iter().any(|e : &GrammarError| e.found == format!("<{}>", class)) {
+            continue;
+        }
+        let mut found = false;
+        for (c, rule, pos, file) in uses.iter().filter(|u| &u.0 == class) {
+            let mut e = GrammarError::new(pos.clone(), expected, format!("<{}>", c));
+            e.nonterm = Some(rule.clone());
+            e.file = file.clone();
+            errors.push(e);
+            found = true;
+        }
+        if !found {
+            // built in code, no position
+            let mut e = GrammarError::new(Position::new(), expected, format!("<{}>", class));
+            e.nonterm = Some(nt.clone());
+            errors.push(e);
+        }
+    }
+    errors
+}
+
+fn compile_classes(g : &Grammar, matchers : Option<Matchers>) -> Result<CompiledGrammar, Vec<GrammarError>> {
     let mut cg = CompiledGrammar::new();
+    let mut errors = Vec::new();
+    // <NAME> terminals without a matcher or %token: (class, rule)
+    let mut unknown = Vec::new();
+    cg.normalization = g.normalization();
+    for (name, pattern) in g.token_rules() {
+        match Regex::new(&pattern) {
+            Ok(re) => cg.lexer.add_token(&name, re),
+            Err(why) => errors.push(regex_error(Some(&name), &pattern, why)),
+        }
+    }
+    for pattern in g.skips() {
+        match Regex::new(&pattern) {
+            Ok(re) => cg.lexer.add_skip(re),
+            Err(why) => errors.push(regex_error(None, &pattern, why)),
+        }
+    }
 
     // compile nonterminals
     for nt in g.nonterminals() {
+        if g.is_helper(&nt) {
+            let idx = cg.add_string(&nt);
+            cg.hidden.insert(idx);
+        }
         // return all productions for this nonterm
         let prods = g.iter_over_nonterm(&nt);
         for prod in &prods {
@@ -153,35 +528,71 @@ pub fn compile_grammar(gs : &str) -> CompiledGrammar {
             for com in &prod.components {
                 // production component is either a terminal or a non-terminal
                 match com.rule {
+                    RuleId::Nonterminal(ref s) if com.predicate.is_some() => {
+                        cg.op_predicate(s, com.predicate.unwrap());
+                    }
                     RuleId::Nonterminal(ref s) => {
                         // nonterminal -> fork instruction
                         cg.op_fork(s, com.name.as_ref());
                     }
                     RuleId::Terminal(ref s) => {
-                        cg.op_match(s, com.name.as_ref());
+                        let fold = com.ignore_case || g.normalization().case_fold;
+                        cg.lexer.add_literal(s, fold);
+                        cg.op_match(s, com.name.as_ref(), fold);
+                    }
+                    RuleId::Regex(ref s) => {
+                        if let Err(why) = cg.op_match_regex(s, com.name.as_ref()) {
+                            errors.push(regex_error(Some(&nt), s, why));
+                        }
+                    }
+                    RuleId::Class(ref s) => {
+                        if !g.is_token(s) && !matchers.as_ref().is_some_and(|m| m.contains(s)) {
+                            unknown.push((s.clone(), nt.clone()));
+                        }
+                        cg.op_match_class(s, com.name.as_ref());
                     }
                 }
             }
+            // the %prec terminal or the last terminal with a precedence level
+            let prec = match prod.precedence {
+                Some(ref terminal) => g.terminal_precedence(terminal),
+                None => prod.components.iter().rev().filter_map(|com| match com.rule {
+                    RuleId::Terminal(ref s) => g.terminal_precedence(s),
+                    _ => None,
+                }).next(),
+            };
+            if let Some(p) = prec {
+                let addr = cg.opcodes.len();
+                cg.precedence.insert(addr, p);
+            }
             cg.op_return(&nt, prod.name.as_ref());
         }
     }
 
-    cg
+    cg.compute_min_tokens();
+
+    for nt in g.start_symbols() {
+        if !g.is_nonterminal(&nt) {
+            let mut e = GrammarError::new(Position::new(), "defined start nonterminal", format!("'{}'", nt));
+            e.nonterm = Some(nt.clone());
+            errors.push(e);
+            continue;
+        }
+        let ntidx = cg.add_string(&nt);
+        cg.entry_points.push(EntryPoint { ntidx });
+    }
+
+    errors.extend(class_errors(g, &unknown));
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    cg.matchers = matchers;
+    Ok(cg)
 }
 
-pub fn compile_grammar_file<S : Into<String>>(filename: S) -> CompiledGrammar
+// compile a grammar file, included files are relative to it
+pub fn compile_grammar_file<P : AsRef<Path>>(filename: P) -> Result<CompiledGrammar, Vec<GrammarError>>
 {
-    let name = filename.into();
-    let path = Path::new(name.as_str());
-    let mut file = match File::open(&path) {
-        Err(why) => panic!("couldn't open file: {}", Error::description(&why)),
-        Ok(file) => file
-    };
-    let mut s = String::new();
-    match file.read_to_string(&mut s) {
-        Err(why) => {
-            panic!("Error reading file: {}", Error::description(&why));
-        },
-        Ok(_) => compile_grammar(&s)
-    }
+    let g = load_grammar_file(filename)?;
+    compile(&g)
 }
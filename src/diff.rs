@@ -0,0 +1,364 @@
+/*!
+ * Comparing two grammars
+ *
+ * Grammar::diff lists the nonterminals that were added, removed or
+ * changed, and the directives (%start, %normalize, %left, %right,
+ * %nonassoc, %token, %skip) that differ. Productions are compared by
+ * their source text, so the formatting of the grammar files, the order
+ * of the rules and the numbering of helper nonterminals do not matter;
+ * the order of precedence levels and tokens does.
+ *
+ * sample_differences generates token sequences from each grammar
+ * and reports those that the other grammar does not accept.
+ */
+
+use std::fmt;
+use std::collections::{HashMap, HashSet};
+
+use grammar::{Grammar, GrammarError, Production, RuleId};
+use compiler::{compile, CompiledGrammar};
+use vm::run_entry;
+use regex::Regex;
+
+// productions of a nonterminal that is in both grammars
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductionChanges {
+    pub nonterm : String,
+    // source text of the productions only in the second grammar
+    pub added : Vec<String>,
+    // source text of the productions only in the first grammar
+    pub removed : Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrammarDiff {
+    // nonterminals only in the second grammar
+    pub added : Vec<String>,
+    // nonterminals only in the first grammar
+    pub removed : Vec<String>,
+    pub changed : Vec<ProductionChanges>,
+    // directive lines only in the second grammar
+    pub added_directives : Vec<String>,
+    // directive lines only in the first grammar
+    pub removed_directives : Vec<String>,
+}
+
+impl GrammarDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+            && self.added_directives.is_empty() && self.removed_directives.is_empty()
+    }
+}
+
+//
+// The directives first, then one line per nonterminal, the productions
+// of a changed one follow:
+//
+//   + %left '%'
+//   + Comment
+//   - Remark
+//   ~ Expr
+//       + : Expr '%' Term
+//       - : Expr '/' Term
+//
+impl fmt::Display for GrammarDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.added_directives {
+            writeln!(f, "+ {}", line)?;
+        }
+        for line in &self.removed_directives {
+            writeln!(f, "- {}", line)?;
+        }
+        for nt in &self.added {
+            writeln!(f, "+ {}", nt)?;
+        }
+        for nt in &self.removed {
+            writeln!(f, "- {}", nt)?;
+        }
+        for changes in &self.changed {
+            writeln!(f, "~ {}", changes.nonterm)?;
+            for (sign, prods) in [("+", &changes.added), ("-", &changes.removed)] {
+                for prod in prods {
+                    let space = if prod.is_empty() { "" } else { " " };
+                    writeln!(f, "    {} :{}{}", sign, space, prod)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// nonterminals written in the grammar source (no helpers)
+fn source_nonterminals(g : &Grammar) -> Vec<String> {
+    g.nonterminals().into_iter().filter(|nt| !g.is_helper(nt)).collect()
+}
+
+fn production_sources(g : &Grammar, nt : &str) -> Vec<String> {
+    g.iter_over_nonterm(nt).iter().map(|p| g.production_source(p)).collect()
+}
+
+// directive lines (added, removed); lines in both grammars whose order
+// changed count as removed and added
+fn directive_changes(ours : &[String], theirs : &[String]) -> (Vec<String>, Vec<String>) {
+    let common = |a : &[String], b : &[String]| -> Vec<String> {
+        a.iter().filter(|l| b.contains(l)).cloned().collect()
+    };
+    let (ours_common, theirs_common) = (common(ours, theirs), common(theirs, ours));
+    let moved = |l : &String| -> bool {
+        let i = ours_common.iter().position(|c| c == l);
+        i.is_some() && i != theirs_common.iter().position(|c| c == l)
+    };
+    let added = theirs.iter().filter(|l| !ours.contains(l) || moved(l)).cloned().collect();
+    let removed = ours.iter().filter(|l| !theirs.contains(l) || moved(l)).cloned().collect();
+    (added, removed)
+}
+
+impl Grammar {
+
+    /**
+     * Nonterminals and productions that differ from 'other'
+     * (added means only in 'other')
+     */
+    pub fn diff(&self, other : &Grammar) -> GrammarDiff {
+        let ours = source_nonterminals(self);
+        let theirs = source_nonterminals(other);
+        let (added_directives, removed_directives) = directive_changes(&self.directives().concat(),
+                                                                       &other.directives().concat());
+        let mut diff = GrammarDiff { added : Vec::new(), removed : Vec::new(), changed : Vec::new(),
+                                     added_directives, removed_directives };
+        for nt in &theirs {
+            if !ours.contains(nt) {
+                diff.added.push(nt.clone());
+            }
+        }
+        for nt in &ours {
+            if !theirs.contains(nt) {
+                diff.removed.push(nt.clone());
+                continue;
+            }
+            let before = production_sources(self, nt);
+            let after = production_sources(other, nt);
+            let changes = ProductionChanges {
+                nonterm : nt.clone(),
+                added : after.iter().filter(|p| !before.contains(p)).cloned().collect(),
+                removed : before.iter().filter(|p| !after.contains(p)).cloned().collect(),
+            };
+            if !changes.added.is_empty() || !changes.removed.is_empty() {
+                diff.changed.push(changes);
+            }
+        }
+        diff
+    }
+}
+
+// token sequence accepted by only one of the grammars
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleDifference {
+    pub tokens : Vec<String>,
+    // true if the first grammar accepts the tokens, false for the second
+    pub first_only : bool,
+}
+
+// nonterminals deeper than this take the shortest way to terminals
+const MAX_DEPTH : usize = 6;
+// longer samples are dropped, parsing nested left recursion
+// gets slow quickly
+const MAX_TOKENS : usize = 16;
+
+// xorshift64*, the samples depend only on the seed
+struct Random {
+    state : u64,
+}
+
+impl Random {
+    fn new(seed : u64) -> Random {
+        Random { state : seed ^ 0x9e37_79b9_7f4a_7c15 }
+    }
+
+    fn below(&mut self, n : usize) -> usize {
+        if self.state == 0 {
+            self.state = 1;
+        }
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as usize % n
+    }
+}
+
+//
+// Generates token sequences from a grammar. Regexes and %token classes
+// give a shortest text they match (Regex::example); classes matched by
+// functions can not be generated, productions with them are never
+// chosen. Predicates are left out, the samples are checked by parsing
+// them.
+//
+struct Sampler<'a> {
+    grammar : &'a Grammar,
+    // least depth of a derivation tree of the nonterminal,
+    // missing if it only derives terminals that can not be generated
+    height : HashMap<String, usize>,
+    // token for each regex and %token class that can be generated
+    regexes : HashMap<String, String>,
+    classes : HashMap<String, String>,
+}
+
+// shortest text of a pattern, None if it is invalid or matches nothing
+fn regex_example(pattern : &str) -> Option<String> {
+    Regex::new(pattern).ok().and_then(|re| re.example())
+}
+
+impl<'a> Sampler<'a> {
+    fn new(grammar : &'a Grammar) -> Sampler<'a> {
+        let mut sampler = Sampler { grammar, height : HashMap::new(), regexes : HashMap::new(), classes : HashMap::new() };
+        let nts = grammar.nonterminals();
+        for (name, pattern) in grammar.token_rules() {
+            if let Some(example) = regex_example(&pattern) {
+                sampler.classes.insert(name, example);
+            }
+        }
+        for nt in &nts {
+            for prod in grammar.iter_over_nonterm(nt) {
+                for com in prod.components {
+                    if let RuleId::Regex(pattern) = com.rule {
+                        if let Some(example) = regex_example(&pattern) {
+                            sampler.regexes.insert(pattern, example);
+                        }
+                    }
+                }
+            }
+        }
+        loop {
+            let mut changed = false;
+            for nt in &nts {
+                let best = grammar.iter_over_nonterm(nt).iter()
+                    .filter_map(|p| sampler.production_height(p))
+                    .min();
+                if let Some(h) = best {
+                    if sampler.height.get(nt).map_or(true, |&old| h < old) {
+                        sampler.height.insert(nt.clone(), h);
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        sampler
+    }
+
+    fn production_height(&self, prod : &Production) -> Option<usize> {
+        let mut height = 0;
+        for com in &prod.components {
+            if com.predicate.is_some() {
+                continue;
+            }
+            match com.rule {
+                RuleId::Terminal(_) => {}
+                RuleId::Nonterminal(ref nt) => height = height.max(*self.height.get(nt)?),
+                RuleId::Regex(_) | RuleId::Class(_) => {
+                    self.example(&com.rule)?;
+                }
+            }
+        }
+        Some(height + 1)
+    }
+
+    // token generated for a regex or token class
+    fn example(&self, rule : &RuleId) -> Option<&String> {
+        match *rule {
+            RuleId::Regex(ref pattern) => self.regexes.get(pattern),
+            RuleId::Class(ref name) => self.classes.get(name),
+            _ => None,
+        }
+    }
+
+    // false if the sample got too long
+    fn generate(&self, nt : &str, depth : usize, random : &mut Random, tokens : &mut Vec<String>) -> bool {
+        let prods : Vec<(Production, usize)> = self.grammar.iter_over_nonterm(nt).into_iter()
+            .filter_map(|p| self.production_height(&p).map(|h| (p, h)))
+            .collect();
+        let prods : Vec<&(Production, usize)> = if depth < MAX_DEPTH {
+            prods.iter().collect()
+        } else {
+            let least = prods.iter().map(|&(_, h)| h).min().unwrap_or(0);
+            prods.iter().filter(|&&(_, h)| h == least).collect()
+        };
+        if prods.is_empty() {
+            return false;
+        }
+        let (ref prod, _) = *prods[random.below(prods.len())];
+        for com in &prod.components {
+            if com.predicate.is_some() {
+                continue;
+            }
+            let token = match com.rule {
+                RuleId::Terminal(ref s) => s,
+                RuleId::Nonterminal(ref s) => {
+                    if !self.generate(s, depth + 1, random, tokens) {
+                        return false;
+                    }
+                    continue;
+                }
+                ref rule => match self.example(rule) {
+                    Some(example) => example,
+                    None => return false,
+                },
+            };
+            if tokens.len() == MAX_TOKENS {
+                return false;
+            }
+            tokens.push(token.clone());
+        }
+        true
+    }
+
+    fn sample(&self, start : &str, random : &mut Random) -> Option<Vec<String>> {
+        self.height.get(start)?;
+        let mut tokens = Vec::new();
+        if self.generate(start, 0, random, &mut tokens) {
+            Some(tokens)
+        } else {
+            None
+        }
+    }
+}
+
+fn accepts(cg : &CompiledGrammar, start : &str, tokens : &[String]) -> bool {
+    match cg.entry_point(start) {
+        Some(entry) => run_entry(entry, cg, tokens, tokens.len()).count() > 0,
+        None => false,
+    }
+}
+
+/**
+ * Generates up to 'count' inputs from each grammar, starting with the
+ * nonterminal 'start', and returns those that only one grammar accepts.
+ * The same seed gives the same samples. Fails if a grammar does not
+ * compile.
+ */
+pub fn sample_differences(first : &Grammar, second : &Grammar, start : &str,
+                          count : usize, seed : u64) -> Result<Vec<SampleDifference>, Vec<GrammarError>> {
+    let compiled = [compile(first)?, compile(second)?];
+    let samplers = [Sampler::new(first), Sampler::new(second)];
+    let mut random = Random::new(seed);
+    let mut seen : HashSet<Vec<String>> = HashSet::new();
+    let mut differences = Vec::new();
+    for _ in 0..count {
+        for side in 0..2 {
+            let tokens = match samplers[side].sample(start, &mut random) {
+                Some(tokens) => tokens,
+                None => continue,
+            };
+            if !seen.insert(tokens.clone()) {
+                continue;
+            }
+            // predicates were skipped, the grammar may reject its own sample
+            if accepts(&compiled[side], start, &tokens) && !accepts(&compiled[1 - side], start, &tokens) {
+                differences.push(SampleDifference { tokens, first_only : side == 0 });
+            }
+        }
+    }
+    Ok(differences)
+}
@@ -0,0 +1,409 @@
+/*!
+ * W3C EBNF front-end (the notation of the XML specification)
+ *
+ *   [1] document ::= prolog element Misc*
+ *   Name         ::= NameStartChar (NameChar)*
+ *   NameStartChar ::= ":" | [A-Z] | "_" | [#xC0-#xD6]
+ *
+ * Supported: ::= rules (optionally numbered), | alternation,
+ * concatenation, ( ) groups, ? * + operators, "..." and '...' strings,
+ * #xN characters, [...] and [^...] classes with #xN and ranges,
+ * /* */ comments. The A - B difference is reported as an error.
+ *
+ * EBNF describes characters, so every terminal of the imported grammar
+ * matches a token of one character: strings are split into terminals
+ * of one character each, #xN characters and classes are regex terminals.
+ * Names with '-' or '.' have them replaced by '_'.
+ */
+
+use std::collections::{HashMap,HashSet};
+
+use grammar::{Grammar,GrammarError,Production,Component,RuleId,identifiers};
+use tokenize::Position;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Name(String),
+    // ::=
+    Defined,
+    Pipe,
+    Minus,
+    Question,
+    Star,
+    Plus,
+    Open,
+    Close,
+    // "..." or '...' without the quotes
+    Literal(String),
+    // [...] as written, without the brackets
+    Class(String),
+    // #xN
+    Char(char),
+    Other(char),
+}
+
+struct Token {
+    tok : Tok,
+    pos : Position,
+}
+
+// pattern matching the character c
+fn char_pattern(c : char) -> String {
+    if c.is_ascii_alphanumeric() {
+        c.to_string()
+    } else {
+        format!("\\u{{{:X}}}", c as u32)
+    }
+}
+
+// regex pattern of a [...] class, #xN are characters
+fn class_pattern(class : &str) -> Result<String, String> {
+    let mut chars : Vec<char> = Vec::new();
+    let mut it = class.chars().peekable();
+    while let Some(c) = it.next() {
+        if c == '#' && it.peek() == Some(&'x') {
+            it.next();
+            let mut hex = String::new();
+            while let Some(&h) = it.peek() {
+                if !h.is_ascii_hexdigit() {
+                    break;
+                }
+                hex.push(h);
+                it.next();
+            }
+            let value = u32::from_str_radix(&hex, 16).ok().and_then(::std::char::from_u32);
+            match value {
+                Some(v) => chars.push(v),
+                None => return Err(format!("#x{}", hex)),
+            }
+        } else {
+            chars.push(c);
+        }
+    }
+    let mut pattern = String::from("[");
+    let mut i = 0;
+    if chars.first() == Some(&'^') {
+        pattern.push('^');
+        i = 1;
+    }
+    while i < chars.len() {
+        // '-' between two chars is a range
+        if chars[i] == '-' && i > 0 && i + 1 < chars.len() && !pattern.ends_with('[') && !pattern.ends_with('^') {
+            pattern.push('-');
+        } else {
+            pattern.push_str(&char_pattern(chars[i]));
+        }
+        i += 1;
+    }
+    pattern.push(']');
+    Ok(pattern)
+}
+
+fn lex(input : &str) -> (Vec<Token>, Vec<GrammarError>) {
+    let mut tokens : Vec<Token> = Vec::new();
+    let mut errors : Vec<GrammarError> = Vec::new();
+    let chars : Vec<char> = input.chars().collect();
+    let mut pos = Position::new();
+    let mut i = 0;
+    // advance over n chars
+    fn skip(chars : &[char], i : &mut usize, pos : &mut Position, n : usize) {
+        for _ in 0..n {
+            if *i < chars.len() {
+                pos.update(chars[*i]);
+                *i += 1;
+            }
+        }
+    }
+    while i < chars.len() {
+        let start = pos.clone();
+        let c = chars[i];
+        let rest : String = chars[i..chars.len().min(i + 3)].iter().collect();
+        let tok = if c.is_whitespace() {
+            skip(&chars, &mut i, &mut pos, 1);
+            continue;
+        } else if rest.starts_with("/*") {
+            let mut n = 2;
+            while i + n < chars.len() && !(chars[i + n - 1] == '*' && chars[i + n] == '/' && n > 2) {
+                n += 1;
+            }
+            if i + n >= chars.len() {
+                errors.push(GrammarError::new(start.clone(), "'*/' to end the comment", "end of input"));
+            }
+            skip(&chars, &mut i, &mut pos, n + 1);
+            continue;
+        } else if rest == "::=" {
+            skip(&chars, &mut i, &mut pos, 3);
+            Tok::Defined
+        } else if c == '"' || c == '\'' || c == '[' {
+            let close = if c == '[' { ']' } else { c };
+            let mut n = 1;
+            while i + n < chars.len() && chars[i + n] != close && chars[i + n] != '\n' {
+                n += 1;
+            }
+            let text : String = chars[i + 1..i + n].iter().collect();
+            if i + n >= chars.len() || chars[i + n] != close {
+                let expected = format!("'{}'", close);
+                errors.push(GrammarError::new(start.clone(), &expected, "end of line"));
+            }
+            skip(&chars, &mut i, &mut pos, n + 1);
+            if c == '[' { Tok::Class(text) } else { Tok::Literal(text) }
+        } else if c == '#' && chars.get(i + 1) == Some(&'x') {
+            let mut n = 2;
+            while i + n < chars.len() && chars[i + n].is_ascii_hexdigit() {
+                n += 1;
+            }
+            let hex : String = chars[i + 2..i + n].iter().collect();
+            skip(&chars, &mut i, &mut pos, n);
+            match u32::from_str_radix(&hex, 16).ok().and_then(::std::char::from_u32) {
+                Some(ch) => Tok::Char(ch),
+                None => {
+                    errors.push(GrammarError::new(start, "character value", format!("#x{}", hex)));
+                    continue;
+                }
+            }
+        } else if c.is_alphanumeric() || c == '_' {
+            let mut n = 0;
+            while i + n < chars.len() && (chars[i + n].is_alphanumeric() || chars[i + n] == '_'
+                                          || chars[i + n] == '-' || chars[i + n] == '.') {
+                n += 1;
+            }
+            let name : String = chars[i..i + n].iter().collect();
+            skip(&chars, &mut i, &mut pos, n);
+            Tok::Name(name)
+        } else {
+            skip(&chars, &mut i, &mut pos, 1);
+            match c {
+                '|' => Tok::Pipe,
+                '-' => Tok::Minus,
+                '?' => Tok::Question,
+                '*' => Tok::Star,
+                '+' => Tok::Plus,
+                '(' => Tok::Open,
+                ')' => Tok::Close,
+                _ => Tok::Other(c),
+            }
+        };
+        tokens.push(Token { tok, pos : start });
+    }
+    (tokens, errors)
+}
+
+// errors are recorded in Parser::errors, Err only unwinds to the rule level
+type ParseResult<T> = Result<T, ()>;
+
+struct Parser {
+    tokens : Vec<Token>,
+    next : usize,
+    errors : Vec<GrammarError>,
+    grammar : Grammar,
+    // referenced names and where
+    references : Vec<(String, Position)>,
+    // name -> name in the grammar
+    ids : HashMap<String, String>,
+}
+
+impl Parser {
+
+    fn peek_at(&self, n : usize) -> Option<&Tok> {
+        self.tokens.get(self.next + n).map(|t| &t.tok)
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.peek_at(0)
+    }
+
+    fn error<T>(&mut self, expected : &str) -> ParseResult<T> {
+        let e = match self.tokens.get(self.next) {
+            Some(t) => {
+                let found = match t.tok {
+                    Tok::Name(ref n) => format!("'{}'", n),
+                    Tok::Defined => "'::='".to_string(),
+                    Tok::Pipe => "'|'".to_string(),
+                    Tok::Minus => "'-'".to_string(),
+                    Tok::Question => "'?'".to_string(),
+                    Tok::Star => "'*'".to_string(),
+                    Tok::Plus => "'+'".to_string(),
+                    Tok::Open => "'('".to_string(),
+                    Tok::Close => "')'".to_string(),
+                    Tok::Literal(ref l) => format!("\"{}\"", l),
+                    Tok::Class(ref c) => format!("[{}]", c),
+                    Tok::Char(c) => format!("#x{:X}", c as u32),
+                    Tok::Other(c) => format!("'{}'", c),
+                };
+                GrammarError::new(t.pos.clone(), expected, found)
+            }
+            None => {
+                let pos = self.tokens.last().map_or(Position::new(), |t| t.pos.clone());
+                GrammarError::new(pos, expected, "end of input")
+            }
+        };
+        self.errors.push(e);
+        Err(())
+    }
+
+    // true at 'Name ::=' or '[n] Name ::=', the start of a rule
+    fn at_rule(&self) -> bool {
+        match (self.peek(), self.peek_at(1), self.peek_at(2)) {
+            (Some(&Tok::Name(_)), Some(&Tok::Defined), _) => true,
+            (Some(Tok::Class(n)), Some(&Tok::Name(_)), Some(&Tok::Defined)) => {
+                n.chars().all(|c| c.is_ascii_digit())
+            }
+            _ => false,
+        }
+    }
+
+    fn parse(&mut self) {
+        while self.peek().is_some() {
+            if self.parse_rule().is_err() {
+                self.next += 1;
+                while self.peek().is_some() && !self.at_rule() {
+                    self.next += 1;
+                }
+            }
+        }
+    }
+
+    fn parse_rule(&mut self) -> ParseResult<()> {
+        if !self.at_rule() {
+            return self.error("rule name and '::='");
+        }
+        if let Some(&Tok::Class(_)) = self.peek() {
+            // rule number
+            self.next += 1;
+        }
+        let name = match self.peek() {
+            Some(Tok::Name(n)) => self.ids[n].clone(),
+            _ => unreachable!(),
+        };
+        self.next += 2;
+        let prods = self.alternatives(&name)?;
+        match self.peek() {
+            None => {}
+            _ if self.at_rule() => {}
+            _ => return self.error("'|', item or the next rule"),
+        }
+        for prod in prods {
+            self.grammar.add_rule(&name, prod);
+        }
+        Ok(())
+    }
+
+    fn alternatives(&mut self, nonterm : &str) -> ParseResult<Vec<Production>> {
+        let mut prods = Vec::new();
+        loop {
+            let mut prod = Production::new();
+            loop {
+                match self.peek() {
+                    None | Some(&Tok::Pipe) | Some(&Tok::Close) => break,
+                    Some(&Tok::Minus) => return self.error("item (A - B is not supported)"),
+                    _ if self.at_rule() => break,
+                    _ => {
+                        let coms = self.item(nonterm)?;
+                        prod.components.extend(coms);
+                    }
+                }
+            }
+            prods.push(prod);
+            if self.peek() != Some(&Tok::Pipe) {
+                return Ok(prods);
+            }
+            self.next += 1;
+        }
+    }
+
+    // a single component for a sequence
+    fn unit(&mut self, nonterm : &str, mut coms : Vec<Component>) -> Component {
+        if coms.len() == 1 {
+            return coms.pop().unwrap();
+        }
+        let mut p = Production::new();
+        p.components = coms;
+        self.grammar.add_helper(nonterm, vec![p])
+    }
+
+    // components of an item, one per character of a string
+    fn item(&mut self, nonterm : &str) -> ParseResult<Vec<Component>> {
+        let pos = self.tokens[self.next].pos.clone();
+        let mut coms = match self.peek().cloned() {
+            Some(Tok::Name(n)) => {
+                self.next += 1;
+                let name = self.ids[&n].clone();
+                self.references.push((n, pos));
+                vec![Component::new(RuleId::Nonterminal(name))]
+            }
+            Some(Tok::Literal(l)) => {
+                self.next += 1;
+                l.chars().map(|c| Component::new(RuleId::Terminal(c.to_string()))).collect()
+            }
+            Some(Tok::Char(c)) => {
+                self.next += 1;
+                vec![Component::new(RuleId::Regex(char_pattern(c)))]
+            }
+            Some(Tok::Class(c)) => {
+                let pattern = match class_pattern(&c) {
+                    Ok(p) => p,
+                    Err(v) => return self.error(&format!("character class ({} is not a character)", v)),
+                };
+                self.next += 1;
+                vec![Component::new(RuleId::Regex(pattern))]
+            }
+            Some(Tok::Open) => {
+                self.next += 1;
+                let mut prods = self.alternatives(nonterm)?;
+                if self.peek() != Some(&Tok::Close) {
+                    return self.error("')' to end the group");
+                }
+                self.next += 1;
+                if prods.len() == 1 {
+                    prods.pop().unwrap().components
+                } else {
+                    vec![self.grammar.add_helper(nonterm, prods)]
+                }
+            }
+            _ => return self.error("item"),
+        };
+        while let Some(operator) = self.peek().cloned() {
+            if !matches!(operator, Tok::Question | Tok::Star | Tok::Plus) {
+                break;
+            }
+            let com = self.unit(nonterm, coms);
+            coms = vec![match operator {
+                Tok::Question => self.grammar.add_optional(nonterm, com),
+                Tok::Star => self.grammar.add_star(nonterm, com),
+                _ => self.grammar.add_plus(nonterm, com),
+            }];
+            self.next += 1;
+        }
+        Ok(coms)
+    }
+}
+
+/**
+ * Load a W3C EBNF grammar
+ */
+pub fn load_ebnf_str(input : &str) -> Result<Grammar, Vec<GrammarError>> {
+    let (tokens, errors) = lex(input);
+    let ids = identifiers(tokens.iter().filter_map(|t| match t.tok {
+        Tok::Name(ref n) => Some(n),
+        _ => None,
+    }));
+    let mut p = Parser {
+        tokens,
+        next : 0,
+        errors,
+        grammar : Grammar::new(),
+        references : Vec::new(),
+        ids,
+    };
+    p.parse();
+    let defined : HashSet<String> = p.grammar.nonterminals().into_iter().collect();
+    for (name, pos) in &p.references {
+        if !defined.contains(&p.ids[name]) {
+            p.errors.push(GrammarError::new(pos.clone(), "defined symbol", format!("'{}'", name)));
+        }
+    }
+    if !p.errors.is_empty() {
+        return Err(p.errors);
+    }
+    Ok(p.grammar)
+}
@@ -1,43 +1,131 @@
-/**
+/*!
  * Simple parser for a BNF-like grammar
  */
 
 use std::fmt;
-use std::collections::{HashMap,VecDeque};
+use std::mem;
+use std::error::Error;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path,PathBuf};
+use std::collections::{HashMap,HashSet};
 
-use tokenize::{Tokenizer,Token};
+use tokenize::{Tokenizer,Token,Position,LexerConfig,unescape};
+use regex::Regex;
 
-#[derive(PartialEq)]
-enum State {
-    Nonterminal,      // -> FirstComponent
-    FirstComponent,   // ':|;' -> Nonterminal|Components
-    Components,       // -> Nonterminal
-                      // ` -> EventName
-                      // '(' - ComponentName
-    ComponentName,    // str -> ComponentNameEnd
-    ComponentNameEnd, // ')' -> Components
-    EventName,        // String -> EventNameEnd
-    EventNameEnd,     // ` -> ComponentsEnd
-    ComponentsEnd,    // ; -> Nonterminal
+/**
+ * Error found while loading a grammar
+ *
+ * file     - grammar file, None for grammars loaded from a string
+ * nonterm  - nonterminal of the rule with the error, for grammars
+ *            built in code and errors found by the compiler
+ * pos      - position of the offending token
+ * expected - description of what the loader expected
+ * found    - the offending token or "end of input"
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrammarError {
+    pub file : Option<String>,
+    pub nonterm : Option<String>,
+    pub pos : Position,
+    pub expected : String,
+    pub found : String,
 }
 
-type NontermId = usize;
+impl GrammarError {
+    pub fn new<E, F>(pos : Position, expected : E, found : F) -> GrammarError
+        where E : Into<String>, F : Into<String> {
+        GrammarError {
+            file : None,
+            nonterm : None,
+            pos,
+            expected : expected.into(),
+            found : found.into()
+        }
+    }
+}
 
-#[derive(Debug, Clone)]
+impl fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file)?;
+        }
+        write!(f, "{}:{}: expected {}, found {}",
+               self.pos.line, self.pos.col, self.expected, self.found)?;
+        if let Some(ref nonterm) = self.nonterm {
+            write!(f, " in rule {}", nonterm)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for GrammarError {}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum RuleId {
     Terminal(String),
     Nonterminal(String),
+    // regular expression matched against the token text
+    // ('/[0-9]+/' is stored as '[0-9]+', '[a-z]' as '[a-z]')
+    Regex(String),
+    // token class matched by a function of the Matchers registry
+    // ('<NUMBER>' is stored as 'NUMBER')
+    Class(String),
 }
 
-#[derive(Debug, Clone)]
+/**
+ * Associativity of a precedence level (%left, %right, %nonassoc)
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Assoc {
+    Left,
+    Right,
+    NonAssoc,
+}
+
+/**
+ * Syntactic predicate, &X or !X: the nonterminal is matched at the
+ * current token without consuming tokens or adding to the parse tree;
+ * X does not match where its own predicate is being evaluated at the
+ * same token (S : !S 'a' ;)
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Predicate {
+    // &X, X must match
+    And,
+    // !X, X must not match
+    Not,
+}
+
+/**
+ * Grammar-wide normalization of terminals and tokens before they are
+ * compared (%normalize casefold, nfc)
+ *
+ * case_fold - compare case folded text, as if every literal had the
+ *             i modifier ('select'i)
+ * nfc       - compare text in Unicode normalization form C, for
+ *             Latin, Greek and Cyrillic letters with combining marks
+ *             and Hangul only (see normalize::nfc)
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Normalization {
+    pub case_fold : bool,
+    pub nfc : bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Component {
     pub rule : RuleId,
     pub name : Option<String>,
+    // Some if the component is a predicate on the nonterminal 'rule'
+    pub predicate : Option<Predicate>,
+    // terminal matched ignoring case ('select'i)
+    pub ignore_case : bool,
 }
 
 impl Component {
     pub fn new(r : RuleId) -> Component {
-        Component { rule : r, name : None }
+        Component { rule : r, name : None, predicate : None, ignore_case : false }
     }
 }
 
@@ -47,11 +135,16 @@ pub struct Production {
     pub name : Option<String>,
     // list of components for this production
     pub components : Vec<Component>,
+    // text of the comments before the production
+    pub doc : Option<String>,
+    // terminal whose precedence the production has (%prec 'x'),
+    // instead of the precedence of its last terminal
+    pub precedence : Option<String>,
 }
 
 impl Production {
     pub fn new() -> Production {
-        Production { name : None, components : Vec::new() }
+        Production { name : None, components : Vec::new(), doc : None, precedence : None }
     }
 }
 
@@ -79,20 +172,56 @@ pub struct Grammar {
     // nonterm name -> list of production IDs
     nonterm_prod_map : HashMap<String, ProductionIDList>,
 
+    // nonterm names in the order they were added, so that
+    // the compiled grammar depends only on the source
+    order : Vec<String>,
+
     // nonterms: id -> production
     productions : HashMap<ProductionId, Production>,
+
+    // generated nonterminals for groups and ?, *, + operators
+    helpers : HashSet<String>,
+
+    // nonterm name -> text of the comments before the rule
+    docs : HashMap<String, String>,
+
+    // unquoted names that did not resolve to a nonterminal
+    bare_terminals : HashSet<String>,
+
+    // template name -> source text of the definition
+    templates : HashMap<String, String>,
+
+    // nonterminals expanded from templates ("List<Item, ','>")
+    instances : HashSet<String>,
+
+    // nonterminals declared with %start
+    starts : Vec<String>,
+
+    // precedence levels of terminals, lowest first
+    precedence : Vec<(Assoc, Vec<String>)>,
+
+    // %normalize options
+    normalization : Normalization,
+
+    // %token declarations: name and regular expression
+    token_rules : Vec<(String, String)>,
+
+    // %skip regular expressions
+    skips : Vec<String>,
+
+    // <NAME> terminals of the loaded rules: class name, rule,
+    // position and file
+    class_uses : Vec<(String, String, Position, Option<String>)>,
 }
 
 impl Grammar {
 
+    // nonterminals in the order they were added
     pub fn nonterminals(&self) -> Vec<String> {
-        let mut v : Vec<String> = Vec::new();
-        for k in self.nonterm_prod_map.keys() {
-            v.push(k.clone());
-        }
-        v
+        self.order.clone()
     }
 
+    #[allow(clippy::single_match)]
     pub fn iter_over_nonterm(&self, name : &str) -> Vec<Production> {
         let mut prods : Vec<Production> = Vec::new();
         let pids_opt = self.nonterm_prod_map.get(name);
@@ -113,24 +242,56 @@ impl Grammar {
         prods
     }
 
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Grammar {
         Grammar {
             prod_seq_no : 0,
             nonterm_prod_map : HashMap::new(),
+            order : Vec::new(),
             productions : HashMap::new(),
+            helpers : HashSet::new(),
+            docs : HashMap::new(),
+            bare_terminals : HashSet::new(),
+            templates : HashMap::new(),
+            instances : HashSet::new(),
+            starts : Vec::new(),
+            precedence : Vec::new(),
+            normalization : Normalization::default(),
+            token_rules : Vec::new(),
+            skips : Vec::new(),
+            class_uses : Vec::new(),
         }
     }
 
+    // documentation comment of a nonterminal
+    pub fn doc(&self, name : &str) -> Option<&str> {
+        self.docs.get(name).map(|s| s.as_str())
+    }
+
+    pub fn set_doc(&mut self, name : &str, doc : &str) {
+        self.docs.insert(name.to_string(), doc.to_string());
+    }
+
     fn create_prodlist() -> ProductionIDList {
         ProductionIDList::new()
     }
 
+    // production list of a nonterminal, created on first use
+    fn prodlist(&mut self, name : &str) -> &mut ProductionIDList {
+        if !self.nonterm_prod_map.contains_key(name) {
+            self.order.push(name.to_string());
+        }
+        self.nonterm_prod_map.entry(name.to_string())
+            .or_insert_with(Grammar::create_prodlist)
+    }
+
     /*
      * Add a rule to the grammar structure
      *
      * nonterm_name - nonterminal
      * prod         - production
      */
+    #[allow(clippy::ptr_arg)]
     pub fn add_rule(
         &mut self,
         nonterm_name : &String,
@@ -142,15 +303,319 @@ impl Grammar {
 
         // create or update mapping
         //   nonterm_name -> productions list [..., prod_id]
-        let prodlist = self.nonterm_prod_map.entry(nonterm_name.clone())
-            .or_insert_with(Grammar::create_prodlist);
-        prodlist.push(prod_id);
+        self.prodlist(nonterm_name).push(prod_id);
+    }
+
+    /*
+     * Remove production 'index' of a nonterminal
+     *
+     * Helper nonterminals that no production refers to any more
+     * are removed too.
+     */
+    pub fn remove_production(&mut self, nonterm_name : &str, index : usize) -> Production {
+        let prod_id = self.prodlist(nonterm_name).remove(index);
+        let prod = self.productions.remove(&prod_id).unwrap();
+        self.remove_unused_helpers();
+        prod
+    }
+
+    // remove the helpers that can not be reached from the other nonterminals
+    fn remove_unused_helpers(&mut self) {
+        let mut used : HashSet<String> = HashSet::new();
+        let mut stack : Vec<String> = self.order.iter()
+            .filter(|nt| !self.helpers.contains(*nt))
+            .cloned()
+            .collect();
+        while let Some(nt) = stack.pop() {
+            for prod in self.iter_over_nonterm(&nt) {
+                for com in prod.components {
+                    if let RuleId::Nonterminal(s) = com.rule {
+                        if self.helpers.contains(&s) && used.insert(s.clone()) {
+                            stack.push(s);
+                        }
+                    }
+                }
+            }
+        }
+        let unused : Vec<String> = self.order.iter()
+            .filter(|nt| self.helpers.contains(*nt) && !used.contains(*nt))
+            .cloned()
+            .collect();
+        for nt in unused {
+            // the name stays in helpers, add_helper numbers
+            // helpers by their count
+            self.order.retain(|n| *n != nt);
+            for prod_id in self.nonterm_prod_map.remove(&nt).unwrap_or_default() {
+                self.productions.remove(&prod_id);
+            }
+        }
+    }
+
+    // terminals written without quotes, e.g. a misspelled nonterminal
+    pub fn bare_terminals(&self) -> Vec<String> {
+        self.bare_terminals.iter().cloned().collect()
+    }
+
+    // nonterminals declared with %start, in order
+    pub fn start_symbols(&self) -> Vec<String> {
+        self.starts.clone()
+    }
+
+    // declare a start nonterminal
+    pub fn add_start(&mut self, name : &str) {
+        if !self.starts.iter().any(|s| s == name) {
+            self.starts.push(name.to_string());
+        }
+    }
+
+    // precedence levels, lowest first, terminals as in RuleId::Terminal
+    pub fn precedence(&self) -> Vec<(Assoc, Vec<String>)> {
+        self.precedence.clone()
+    }
+
+    // add a precedence level above the levels added so far
+    pub fn add_precedence(&mut self, assoc : Assoc, terminals : &[String]) {
+        self.precedence.push((assoc, terminals.to_vec()));
+    }
+
+    // how terminals and tokens are normalized before matching
+    pub fn normalization(&self) -> Normalization {
+        self.normalization
+    }
+
+    pub fn set_normalization(&mut self, normalization : Normalization) {
+        self.normalization = normalization;
+    }
+
+    // %token declarations (name, regular expression), in order
+    pub fn token_rules(&self) -> Vec<(String, String)> {
+        self.token_rules.clone()
+    }
+
+    // declare a token, unquoted references to the name become
+    // RuleId::Class terminals matched by the regular expression;
+    // fails with the problem of an invalid pattern
+    pub fn add_token(&mut self, name : &str, pattern : &str) -> Result<(), String> {
+        Regex::new(pattern)?;
+        self.token_rules.push((name.to_string(), pattern.to_string()));
+        Ok(())
+    }
+
+    pub fn is_token(&self, name : &str) -> bool {
+        self.token_rules.iter().any(|t| t.0 == name)
+    }
+
+    // where the loader found <NAME> terminals, see add_class_use
+    pub fn class_uses(&self) -> Vec<(String, String, Position, Option<String>)> {
+        self.class_uses.clone()
+    }
+
+    // record the <NAME> terminal 'class' of rule 'nonterm' at 'pos' of
+    // 'file', compile reports unknown classes there
+    pub fn add_class_use(&mut self, class : &str, nonterm : &str, pos : Position, file : Option<String>) {
+        self.class_uses.push((class.to_string(), nonterm.to_string(), pos, file));
+    }
+
+    // %skip regular expressions, in order
+    pub fn skips(&self) -> Vec<String> {
+        self.skips.clone()
+    }
+
+    // source lines of the directives by group: %start, %normalize,
+    // the precedence levels and the tokens (empty groups are left out)
+    pub fn directives(&self) -> Vec<Vec<String>> {
+        let mut groups = Vec::new();
+        if !self.starts.is_empty() {
+            groups.push(vec![format!("%start {}", self.starts.join(", "))]);
+        }
+        let mut options = Vec::new();
+        if self.normalization.case_fold {
+            options.push("casefold");
+        }
+        if self.normalization.nfc {
+            options.push("nfc");
+        }
+        if !options.is_empty() {
+            groups.push(vec![format!("%normalize {}", options.join(", "))]);
+        }
+        let levels : Vec<String> = self.precedence.iter().map(|&(assoc, ref terminals)| {
+            let directive = match assoc {
+                Assoc::Left => "%left",
+                Assoc::Right => "%right",
+                Assoc::NonAssoc => "%nonassoc",
+            };
+            let terminals : Vec<String> = terminals.iter().map(|t| terminal_source(t, false)).collect();
+            format!("{} {}", directive, terminals.join(" "))
+        }).collect();
+        let mut tokens : Vec<String> = self.token_rules.iter()
+            .map(|(name, pattern)| format!("%token {} {}", name, regex_source(pattern)))
+            .collect();
+        tokens.extend(self.skips.iter().map(|pattern| format!("%skip {}", regex_source(pattern))));
+        groups.push(levels);
+        groups.push(tokens);
+        groups.retain(|g| !g.is_empty());
+        groups
+    }
+
+    // text the lexer skips, fails like add_token
+    pub fn add_skip(&mut self, pattern : &str) -> Result<(), String> {
+        Regex::new(pattern)?;
+        self.skips.push(pattern.to_string());
+        Ok(())
+    }
+
+    // level (0 is the lowest) and associativity of a terminal
+    pub fn terminal_precedence(&self, terminal : &str) -> Option<(usize, Assoc)> {
+        self.precedence.iter().enumerate()
+            .find(|&(_, (_, terms))| terms.iter().any(|t| t == terminal))
+            .map(|(level, &(assoc, _))| (level, assoc))
+    }
+
+    // true if the nonterminal is an expanded template reference
+    pub fn is_instance(&self, name : &str) -> bool {
+        self.instances.contains(name)
+    }
+
+    pub fn is_nonterminal(&self, name : &str) -> bool {
+        self.nonterm_prod_map.contains_key(name)
+    }
+
+    // replace references to nonterminals (old name -> new name)
+    fn rename_references(&mut self, names : &HashMap<String, String>) {
+        for (_, prod) in self.productions.iter_mut() {
+            for com in prod.components.iter_mut() {
+                let repl = match com.rule {
+                    RuleId::Nonterminal(ref s) => names.get(s).cloned(),
+                    _ => None,
+                };
+                if let Some(n) = repl {
+                    com.rule = RuleId::Nonterminal(n);
+                }
+            }
+        }
+    }
+
+    // true if the nonterminal was generated for a group or
+    // a ?, * or + operator
+    pub fn is_helper(&self, name : &str) -> bool {
+        self.helpers.contains(name)
+    }
+
+    /*
+     * Add a generated nonterminal with the given productions
+     *
+     * parent - nonterminal the helper is used in (part of the name)
+     *
+     * returns a component referring to the new nonterminal
+     */
+    pub fn add_helper(&mut self, parent : &str, prods : Vec<Production>) -> Component {
+        // '~' followed by a number can not be written as one
+        // token in a grammar file, so the name never collides
+        let name = format!("{}~{}", parent, self.helpers.len() + 1);
+        self.helpers.insert(name.clone());
+        // make sure the nonterminal exists even without productions
+        self.prodlist(&name);
+        for prod in prods {
+            self.add_rule(&name, prod);
+        }
+        Component::new(RuleId::Nonterminal(name))
+    }
+
+    // com? -> H : com | ;
+    pub fn add_optional(&mut self, parent : &str, com : Component) -> Component {
+        let mut p = Production::new();
+        p.components.push(com);
+        self.add_helper(parent, vec![p, Production::new()])
+    }
+
+    // com* -> H : com H | ;
+    pub fn add_star(&mut self, parent : &str, com : Component) -> Component {
+        let h = self.add_helper(parent, Vec::new());
+        let mut p = Production::new();
+        p.components.push(com);
+        p.components.push(h.clone());
+        self.add_rule(&Grammar::rule_name(&h), p);
+        self.add_rule(&Grammar::rule_name(&h), Production::new());
+        h
+    }
+
+    // &com or !com -> H : com ; (the component refers to H)
+    pub fn add_predicate(&mut self, parent : &str, predicate : Predicate, com : Component) -> Component {
+        let mut p = Production::new();
+        p.components.push(com);
+        let mut h = self.add_helper(parent, vec![p]);
+        h.predicate = Some(predicate);
+        h
+    }
+
+    // com+ -> H : com H | com ;
+    pub fn add_plus(&mut self, parent : &str, com : Component) -> Component {
+        let h = self.add_helper(parent, Vec::new());
+        let mut p = Production::new();
+        p.components.push(com.clone());
+        p.components.push(h.clone());
+        self.add_rule(&Grammar::rule_name(&h), p);
+        let mut p = Production::new();
+        p.components.push(com);
+        self.add_rule(&Grammar::rule_name(&h), p);
+        h
+    }
+
+    fn rule_name(com : &Component) -> String {
+        match com.rule {
+            RuleId::Terminal(ref s) => s.clone(),
+            RuleId::Nonterminal(ref s) => s.clone(),
+            RuleId::Regex(ref s) => s.clone(),
+            RuleId::Class(ref s) => s.clone(),
+        }
+    }
+
+    /*
+     * Prefix the references to names of a namespace in
+     * the productions added since first_id
+     *
+     * names      - rules defined in the namespace (unqualified)
+     * namespaces - namespaces nested in this one
+     * prefix     - namespace prefix ("html::")
+     */
+    fn qualify(
+        &mut self,
+        first_id : ProductionId,
+        names : &HashSet<String>,
+        namespaces : &HashSet<String>,
+        prefix : &str,
+    ) {
+        for (id, prod) in self.productions.iter_mut() {
+            if *id < first_id {
+                continue;
+            }
+            for com in prod.components.iter_mut() {
+                let repl = match com.rule {
+                    RuleId::Terminal(ref s) => {
+                        let nested = match s.find("::") {
+                            Some(i) => namespaces.contains(&s[..i]),
+                            None => false,
+                        };
+                        if names.contains(s) || nested {
+                            Some(RuleId::Terminal(format!("{}{}", prefix, s)))
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None
+                };
+                if let Some(r) = repl {
+                    com.rule = r;
+                }
+            }
+        }
     }
 
     /*
      * If a component is a terminal and there is
      * a nonterm named as the value, convert the
-     * component to a nonterm
+     * component to a nonterm (to a token class if
+     * there is a %token of the name)
      */
     fn resolve(&mut self) {
         for (_, prod) in self.productions.iter_mut() {
@@ -159,165 +624,1592 @@ impl Grammar {
                     RuleId::Terminal(ref s) => {
                         if self.nonterm_prod_map.contains_key(s) {
                             Some(RuleId::Nonterminal(s.clone()))
+                        } else if self.token_rules.iter().any(|t| t.0 == *s) {
+                            Some(RuleId::Class(s.clone()))
                         } else {
-                            let mut start = 0;
-                            let mut end = s.len();
-                            for (i, c) in s.char_indices() {
-                                if i == 0 && c == '\'' {
-                                    start = i + 1;
-                                }
-                                if i == s.len() - 1 && c == '\'' {
-                                    end = i;
-                                }
+                            if !s.starts_with('\'') && !s.starts_with('"') {
+                                self.bare_terminals.insert(s.clone());
                             }
-                            let ns = s[start..end].to_string();
-                            Some(RuleId::Terminal(ns))
+                            Some(RuleId::Terminal(terminal_value(s)))
                         }
                     }
                     _ => None
                 };
-                if repl.is_some() {
-                    val.rule = repl.unwrap();
+                if let Some(r) = repl {
+                    val.rule = r;
                 }
             }
         }
     }
 }
 
+// terminal as stored in RuleId::Terminal, 'x' and "x" are stored
+// as x with the escapes decoded (the parser reported bad ones)
+fn terminal_value(s : &str) -> String {
+    let quoted = s.len() > 1 && (s.starts_with('\'') && s.ends_with('\'')
+                                 || s.starts_with('"') && s.ends_with('"'));
+    if !quoted {
+        return s.to_string();
+    }
+    let inner = &s[1..s.len() - 1];
+    unescape(inner, '\\').unwrap_or_else(|_| inner.to_string())
+}
+
+// source text of a terminal, quoted ones with the escapes
+// terminal_value decodes
+fn terminal_source(value : &str, bare : bool) -> String {
+    if bare {
+        return value.to_string();
+    }
+    let mut s = String::from("'");
+    for c in value.chars() {
+        match c {
+            '\'' => s.push_str("\\'"),
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\t' => s.push_str("\\t"),
+            '\r' => s.push_str("\\r"),
+            c if c.is_control() => s.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => s.push(c),
+        }
+    }
+    s.push('\'');
+    s
+}
+
+// source text of a regular expression, '[class]' or '/regex/'
+fn regex_source(pattern : &str) -> String {
+    if pattern.starts_with('[') && pattern.ends_with(']')
+        && !pattern[1..pattern.len() - 1].contains(']') {
+        return pattern.to_string();
+    }
+    let mut s = String::from("/");
+    let mut escaped = false;
+    for c in pattern.chars() {
+        if c == '/' && !escaped {
+            s.push('\\');
+        }
+        escaped = c == '\\' && !escaped;
+        s.push(c);
+    }
+    s.push('/');
+    s
+}
+
+impl Grammar {
+
+    // source text of a component, helper nonterminals are
+    // written back as operators and groups
+    fn component_source(&self, com : &Component) -> String {
+        let s = match com.rule {
+            RuleId::Terminal(ref s) if com.ignore_case => format!("{}i", terminal_source(s, false)),
+            RuleId::Terminal(ref s) => terminal_source(s, self.bare_terminals.contains(s)),
+            RuleId::Regex(ref s) => regex_source(s),
+            RuleId::Class(ref s) => format!("<{}>", s),
+            RuleId::Nonterminal(ref s) if self.is_helper(s) => self.helper_source(s),
+            RuleId::Nonterminal(ref s) => s.clone(),
+        };
+        let s = match com.predicate {
+            Some(Predicate::And) => format!("&{}", s),
+            Some(Predicate::Not) => format!("!{}", s),
+            None => s,
+        };
+        match com.name {
+            Some(ref name) => format!("{} ({})", s, name),
+            None => s,
+        }
+    }
+
+    // operand of ?, * or +, a named component needs a group
+    // (x (name)? would name a group)
+    fn operand_source(&self, com : &Component) -> String {
+        match com.name {
+            Some(_) => format!("( {} )", self.component_source(com)),
+            None => self.component_source(com),
+        }
+    }
+
+    fn alternative_source(&self, prod : &Production) -> String {
+        let items : Vec<String> = prod.components.iter()
+            .map(|c| self.component_source(c))
+            .collect();
+        items.join(" ")
+    }
+
+    // source text of a production as Display writes it after ':' or '|',
+    // the same for every formatting of the grammar source
+    pub fn production_source(&self, prod : &Production) -> String {
+        let mut s = self.alternative_source(prod);
+        if let Some(ref terminal) = prod.precedence {
+            if !s.is_empty() {
+                s.push(' ');
+            }
+            s.push_str(&format!("%prec {}", terminal_source(terminal, false)));
+        }
+        if let Some(ref name) = prod.name {
+            if !s.is_empty() {
+                s.push(' ');
+            }
+            s.push_str(&format!("`{}`", name));
+        }
+        s
+    }
+
+    fn helper_source(&self, name : &str) -> String {
+        let prods = self.iter_over_nonterm(name);
+        let itself = Component::new(RuleId::Nonterminal(name.to_string()));
+        let repeated = prods.len() == 2 && prods[0].components.len() == 2
+            && prods[0].components[1] == itself;
+        if repeated && prods[1].components.is_empty() {
+            return format!("{}*", self.operand_source(&prods[0].components[0]));
+        }
+        if repeated && prods[1].components.len() == 1
+            && prods[1].components[0] == prods[0].components[0] {
+            return format!("{}+", self.operand_source(&prods[0].components[0]));
+        }
+        if prods.len() == 2 && prods[0].components.len() == 1 && prods[1].components.is_empty() {
+            return format!("{}?", self.operand_source(&prods[0].components[0]));
+        }
+        if prods.len() == 1 && prods[0].components.len() == 1 {
+            // a group of one component, ( x ) would be a component name
+            return self.component_source(&prods[0].components[0]);
+        }
+        let mut s = String::from("(");
+        for (i, prod) in prods.iter().enumerate() {
+            if i > 0 {
+                s.push_str(" |");
+            }
+            if !prod.components.is_empty() {
+                s.push(' ');
+                s.push_str(&self.alternative_source(prod));
+            }
+        }
+        s.push_str(" )");
+        s
+    }
+}
+
+/**
+ * Grammar source that load_grammar_str accepts
+ *
+ * The %start, %normalize, precedence and token declarations and
+ * templates come first, then the rules
+ * ordered by name. Expanded template
+ * references are written as the references, helper nonterminals as
+ * groups and operators, documentation as // comments.
+ */
 impl fmt::Display for Grammar {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Grammar<{} nonterms>", self.nonterm_prod_map.len())
+        for group in self.directives() {
+            for line in group {
+                writeln!(f, "{}", line)?;
+            }
+            writeln!(f)?;
+        }
+        let mut templates : Vec<&String> = self.templates.keys().collect();
+        templates.sort();
+        for name in templates {
+            writeln!(f, "{}", self.templates[name])?;
+            writeln!(f)?;
+        }
+
+        let mut nts : Vec<String> = self.nonterminals().into_iter()
+            .filter(|nt| !self.is_helper(nt) && !self.is_instance(nt))
+            .collect();
+        nts.sort();
+        for nt in nts {
+            if let Some(doc) = self.doc(&nt) {
+                for line in doc.lines() {
+                    writeln!(f, "// {}", line)?;
+                }
+            }
+            let prods = self.iter_over_nonterm(&nt);
+            if prods.is_empty() {
+                writeln!(f, "{} ;", nt)?;
+                writeln!(f)?;
+                continue;
+            }
+            writeln!(f, "{}", nt)?;
+            for (i, prod) in prods.iter().enumerate() {
+                if let Some(ref doc) = prod.doc {
+                    for line in doc.lines() {
+                        writeln!(f, "    // {}", line)?;
+                    }
+                }
+                write!(f, "    {}", if i == 0 { ":" } else { "|" })?;
+                let source = self.production_source(prod);
+                if !source.is_empty() {
+                    write!(f, " {}", source)?;
+                }
+                writeln!(f)?;
+            }
+            writeln!(f, "    ;")?;
+            writeln!(f)?;
+        }
+        Ok(())
     }
 }
 
-// load grammar from string and produce a grammar structure
-pub fn load_grammar_str(input_str : &str) -> Grammar {
-    let mut tokens : VecDeque<Token> = VecDeque::new();
-
-    {
-        let mut t = Tokenizer::new(|t| { tokens.push_back(t); });
-
-        // push chars from s into the tokenizer
-        for ch in input_str.chars() {
-            t.push(ch);
-        }
-
-        // signal eos to the tokenizer
-        t.finish();
-    }
-
-    let mut nonterminal : Option<String> = None;
-    let mut production = Production::new();
-
-    let mut grammar = Grammar::new();
-    // initial state
-    let mut state = State::Nonterminal;
-    let mut failed = false;
-    while !failed && !tokens.is_empty() {
-        let s = tokens.pop_front().unwrap();
-        let value = String::from(&input_str[s.beg.pos..s.end.pos]);
-        match state {
-            State::Nonterminal => {
-                nonterminal = Some(value.clone());
-                state = State::FirstComponent;
-            },
-            // expecting : then first component
-            State::FirstComponent => {
-                if value == ":" {
-                    state = State::Components;
-                } else if value == ";" {
-                    // finished one nonterminal
-                    // expect another nonterminal or eof
-                    state == State::Nonterminal;
-                } else {
-                    // error: exp : or ;
-                    println!("expected : or ;, not {}", value);
-                    failed = true;
+// true if the token is one of the characters with a special
+// meaning in the grammar language
+fn is_punct(value : &str) -> bool {
+    matches!(value, ":" | ";" | "|" | "(" | ")" | "`" | "?" | "*" | "+" | "&" | "!")
+}
+
+// associativity of a %left, %right or %nonassoc token
+fn precedence_directive(value : &str) -> Option<Assoc> {
+    match value {
+        "%left" => Some(Assoc::Left),
+        "%right" => Some(Assoc::Right),
+        "%nonassoc" => Some(Assoc::NonAssoc),
+        _ => None,
+    }
+}
+
+/*
+ * Names of another notation (ABNF, yacc, EBNF) as names a grammar
+ * file can use: chars other than letters, digits and '_' become '_',
+ * and '_' is appended while the result is taken by another name
+ */
+pub fn identifiers<'a, I>(names : I) -> HashMap<String, String>
+    where I : IntoIterator<Item = &'a String> {
+    let names : Vec<&String> = names.into_iter().collect();
+    let valid = |name : &str| name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    let mut taken : HashSet<String> = names.iter().filter(|n| valid(n)).map(|n| n.to_string()).collect();
+    let mut result = HashMap::new();
+    for name in names {
+        if result.contains_key(name) {
+            continue;
+        }
+        let mut id = name.clone();
+        if !valid(name) {
+            id = name.chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect();
+            while !taken.insert(id.clone()) {
+                id.push('_');
+            }
+        }
+        result.insert(name.clone(), id);
+    }
+    result
+}
+
+// quote a token value for error messages
+// (quoted terminals are shown as written)
+fn found(value : &str) -> String {
+    if value.starts_with('\'') || value.starts_with('"') {
+        value.to_string()
+    } else {
+        format!("'{}'", value)
+    }
+}
+
+/**
+ * Recursive descent parser for the grammar language
+ *
+ *   rule         : NAME ';' | NAME (':' | '|=' | '-=') alternatives ';'
+ *   alternatives : alternative ('|' alternative)*
+ *   alternative  : item* ('%prec' TERMINAL)? ('`' NAME '`')?
+ *   item         : ('&' | '!') item
+ *                | primary ('?' | '*' | '+')* ('(' NAME ')')?
+ *   primary      : SYMBOL | '<' NAME '>' | '(' alternatives ')'
+ *
+ * Groups and operators are replaced by generated helper nonterminals.
+ * Event names are only allowed on the alternatives of a rule.
+ * &item matches if item matches at the current token, !item if it
+ * does not; neither consumes tokens. 'x' and "x" are the same
+ * terminal; \n, \t, \r, \0 and \u{...} in quotes are decoded, other
+ * escaped chars stand for themselves ('it\'s'). A quoted terminal directly
+ * followed by i ('select'i) matches ignoring case. <NAME> is a token
+ * class, matched by the function registered for NAME (see Matchers and
+ * compile_with_matchers) or by the %token NAME declaration.
+ *
+ * Directives:
+ *
+ *   %include "file"         rules of file are added to the grammar
+ *   %include "file" as ns   rules of file are added as ns::Rule
+ *   %extends "file"         rules of file are inherited, see below
+ *   %start Doc, Fragment    nonterminals parsing can start with
+ *   %left '+' '-'           precedence level of terminals, later
+ *   %right '^'              levels bind tighter (also %nonassoc)
+ *   %normalize casefold     compare terminals and tokens case folded
+ *   %normalize nfc          ... in Unicode NFC (both: casefold, nfc)
+ *   %token NUMBER /[0-9]+/  token of the lexer of parse_str, NUMBER in a
+ *                           rule is a token class (<NUMBER>)
+ *   %skip /\s+/             text the lexer skips between tokens
+ *
+ * Included file names are relative to the including file.
+ * A file is included once per namespace, include cycles are errors.
+ * A rule may also be defined with a qualified name (ns::Rule : ...),
+ * which is how the rules of included files are printed, so that a
+ * printed grammar loads back without its included files.
+ * Start nonterminals must be defined.
+ *
+ * Extending a grammar:
+ *
+ *   %extends "json.g"
+ *   Value : Object | Array ;   replaces the inherited productions
+ *   Value |= 'undefined' ;     adds productions
+ *   Number -= '-' Digits ;     removes productions written the same way
+ *
+ * %extends comes before the rules. The rules of the file are added as
+ * with %include; a rule for an inherited nonterminal replaces its
+ * productions, later rules for it add productions as usual.
+ *
+ * The precedence of a production is the precedence of its last
+ * terminal that has one, or of the terminal after %prec
+ * (E : '-' E %prec 'UMINUS' ;). Parse trees where a production is the first
+ * or last child of a production with higher precedence, or of one with
+ * the same precedence and the other associativity, are dropped.
+ *
+ * Parse trees where a nonterminal derives itself over the same tokens
+ * (A : A | 'a' ;, or through nonterminals that match nothing) are dropped
+ * too, there would be infinitely many of them.
+ *
+ * Templates:
+ *
+ *   List<X, Sep> : X | X Sep List<X, Sep> ;
+ *   S : List<Item, ','> ;
+ *
+ * A template is expanded once per distinct list of arguments, after
+ * all files are parsed, into a nonterminal named like the reference
+ * ("List<Item, ','>"). The '<' must directly follow the name.
+ */
+struct Parser<'a> {
+    input : &'a str,
+    // byte offset of input in the file (template bodies)
+    base : usize,
+    tokens : Vec<Token>,
+    // comments skipped by the tokenizer
+    comments : Vec<Token>,
+    // index of the next token
+    next : usize,
+    // position reported for errors at the end of input
+    eos_pos : Position,
+    // file being parsed, None for strings
+    file : Option<PathBuf>,
+    // nonterminal reported with the errors (GrammarBuilder items)
+    nonterm : Option<String>,
+    // namespace prefix of the rules in this file ("" or "html::")
+    prefix : String,
+    // rules defined in this file, without the prefix
+    local : HashSet<String>,
+    // namespaces included in this file
+    namespaces : HashSet<String>,
+    // template parameter -> argument, while expanding a template
+    subst : HashMap<String, Component>,
+    shared : Shared,
+}
+
+// the largest number of template instances in a grammar
+// (guards against templates that expand forever)
+const MAX_INSTANCES : usize = 1000;
+
+// parameterized rule: Name<A, B> : ... ;
+#[derive(Clone)]
+struct Template {
+    params : Vec<String>,
+    // text of the body (after ':', up to and including ';') and
+    // the position of its first token
+    body : String,
+    body_pos : Position,
+    // false if the body has errors (reported once, at the definition)
+    valid : bool,
+    file : Option<PathBuf>,
+    // namespace of the template
+    prefix : String,
+}
+
+// reference to a template: Name<args>
+#[derive(Clone)]
+struct Instance {
+    // nonterminal name used until the instance is expanded
+    placeholder : String,
+    // template name as written
+    template : String,
+    args : Vec<Component>,
+    // namespace of the reference
+    prefix : String,
+    file : Option<PathBuf>,
+    pos : Position,
+}
+
+// state shared by a parser and the parsers of the files it includes
+struct Shared {
+    grammar : Grammar,
+    errors : Vec<GrammarError>,
+    // files being parsed, to detect include cycles
+    stack : Vec<PathBuf>,
+    // (file, prefix) pairs included so far
+    included : HashSet<(PathBuf, String)>,
+    // qualified template name -> template
+    templates : HashMap<String, Template>,
+    // template references in order of appearance
+    instances : Vec<Instance>,
+    // %start nonterminals and where they were declared
+    starts : Vec<(String, Position, Option<PathBuf>)>,
+    // nonterminals of extended grammars
+    inherited : HashSet<String>,
+    // inherited nonterminals the extending grammar has rules for
+    redefined : HashSet<String>,
+    // %token names and where they were declared
+    tokens : Vec<(String, Position, Option<PathBuf>)>,
+}
+
+impl Shared {
+    fn new() -> Shared {
+        Shared {
+            grammar : Grammar::new(),
+            errors : Vec::new(),
+            stack : Vec::new(),
+            included : HashSet::new(),
+            templates : HashMap::new(),
+            instances : Vec::new(),
+            starts : Vec::new(),
+            inherited : HashSet::new(),
+            redefined : HashSet::new(),
+            tokens : Vec::new(),
+        }
+    }
+}
+
+// errors are recorded in Parser::errors, Err only unwinds to the rule level
+type ParseResult<T> = Result<T, ()>;
+
+impl<'a> Parser<'a> {
+
+    fn new(input : &'a str, file : Option<PathBuf>, shared : Shared) -> Parser<'a> {
+        let mut tokens : Vec<Token> = Vec::new();
+        let comments;
+        let open;
+        {
+            let mut t = Tokenizer::with_config(LexerConfig::grammar(), |t| { tokens.push(t); });
+
+            // push chars from s into the tokenizer
+            for ch in input.chars() {
+                t.push(ch);
+            }
+
+            // signal eos to the tokenizer
+            open = t.finish().err();
+            comments = t.comments().clone();
+        }
+        // unbalanced input is not parsed at all
+        if open.is_some() {
+            tokens.clear();
+        }
+
+        let eos_pos = match tokens.last() {
+            Some(t) => t.end.clone(),
+            None => Position::new(),
+        };
+
+        let mut parser = Parser {
+            input,
+            base : 0,
+            tokens,
+            comments,
+            next : 0,
+            eos_pos,
+            file,
+            nonterm : None,
+            prefix : String::new(),
+            local : HashSet::new(),
+            namespaces : HashSet::new(),
+            subst : HashMap::new(),
+            shared,
+        };
+        if let Some(e) = open {
+            parser.push_error(GrammarError::new(e.pos().clone(), e.expected(), "end of input"));
+        }
+        parser
+    }
+
+    // input between two positions
+    fn text(&self, beg : &Position, end : &Position) -> &'a str {
+        &self.input[beg.pos - self.base..end.pos - self.base]
+    }
+
+    // value of the token n positions ahead
+    fn peek_at(&self, n : usize) -> Option<&'a str> {
+        self.tokens.get(self.next + n)
+            .map(|t| self.text(&t.beg, &t.end))
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.peek_at(0)
+    }
+
+    fn advance(&mut self) -> &'a str {
+        let value = self.peek().unwrap();
+        self.next += 1;
+        value
+    }
+
+    // record an error at the next token
+    fn error<T>(&mut self, expected : &str) -> ParseResult<T> {
+        let e = match self.tokens.get(self.next) {
+            Some(t) => {
+                let value = self.text(&t.beg, &t.end);
+                GrammarError::new(t.beg.clone(), expected, found(value))
+            }
+            None => GrammarError::new(self.eos_pos.clone(), expected, "end of input"),
+        };
+        self.push_error(e);
+        Err(())
+    }
+
+    fn push_error(&mut self, mut e : GrammarError) {
+        e.file = self.file.as_ref().map(|f| f.display().to_string());
+        e.nonterm = self.nonterm.clone();
+        self.shared.errors.push(e);
+    }
+
+    fn expect(&mut self, value : &str, expected : &str) -> ParseResult<()> {
+        if self.peek() == Some(value) {
+            self.next += 1;
+            Ok(())
+        } else {
+            self.error(expected)
+        }
+    }
+
+    // skip past the next ';'
+    fn recover(&mut self) {
+        while let Some(value) = self.peek() {
+            self.next += 1;
+            if value == ";" {
+                break;
+            }
+        }
+    }
+
+    fn parse(mut self) -> Result<Grammar, Vec<GrammarError>> {
+        self.parse_rules();
+        self.expand_instances();
+        self.check_starts();
+        self.check_tokens();
+        if !self.shared.errors.is_empty() {
+            return Err(self.shared.errors);
+        }
+        self.shared.grammar.resolve();
+        Ok(self.shared.grammar)
+    }
+
+    fn parse_rules(&mut self) {
+        let first_id = self.shared.grammar.prod_seq_no;
+        while self.peek().is_some() {
+            let r = if self.peek() == Some("%include") {
+                self.parse_include()
+            } else if self.peek() == Some("%extends") {
+                self.parse_extends()
+            } else if self.peek() == Some("%start") {
+                self.parse_start()
+            } else if self.peek() == Some("%normalize") {
+                self.parse_normalize()
+            } else if self.peek() == Some("%token") || self.peek() == Some("%skip") {
+                self.parse_token()
+            } else if let Some(assoc) = self.peek().and_then(precedence_directive) {
+                self.parse_precedence(assoc)
+            } else {
+                self.parse_rule()
+            };
+            if r.is_err() {
+                self.recover();
+            }
+        }
+        if !self.prefix.is_empty() {
+            self.shared.grammar.qualify(first_id, &self.local, &self.namespaces, &self.prefix);
+        }
+    }
+
+    // %start NAME (',' NAME)*
+    fn parse_start(&mut self) -> ParseResult<()> {
+        self.next += 1;
+        loop {
+            match self.peek() {
+                Some(v) if !is_punct(v) && v != "," && !v.starts_with('\'') && !v.starts_with('"') => {
+                    let pos = self.tokens[self.next].beg.clone();
+                    let name = self.parse_qualified();
+                    let name = format!("{}{}", self.prefix, name);
+                    self.shared.starts.push((name, pos, self.file.clone()));
                 }
-            },
-            State::Components => {
-                if value == "`" {
-                    state = State::EventName;
-                } else if value == "(" {
-                    state = State::ComponentName;
-                } else if value == "|" {
-                    let nonterm = nonterminal.as_ref().unwrap();
-                    grammar.add_rule(
-                        &nonterm,
-                        production
-                    );
-                    production = Production::new();
-                    // expect another production
-                    state = State::Components;
-                } else if value == ";" {
-                    let nonterm = nonterminal.as_ref().unwrap();
-                    grammar.add_rule(
-                        &nonterm,
-                        production
-                    );
-                    production = Production::new();
-                    // expect another nonterminal or eos
-                    state = State::Nonterminal;
-                } else {
-                    // save s to components for current nt
-                    production.components.push(
-                        Component::new(
-                            RuleId::Terminal(
-                                value.clone()))
-                    );
+                _ => return self.error("start nonterminal"),
+            }
+            if self.peek() != Some(",") {
+                break;
+            }
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    // %normalize OPTION (',' OPTION)*
+    fn parse_normalize(&mut self) -> ParseResult<()> {
+        self.next += 1;
+        let mut n = self.shared.grammar.normalization();
+        loop {
+            match self.peek() {
+                Some("casefold") => n.case_fold = true,
+                Some("nfc") => n.nfc = true,
+                _ => return self.error("normalization (casefold or nfc)"),
+            }
+            self.next += 1;
+            if self.peek() != Some(",") {
+                break;
+            }
+            self.next += 1;
+        }
+        self.shared.grammar.set_normalization(n);
+        Ok(())
+    }
+
+    // regular expression token ('/regex/' or '[class]'), as stored in RuleId::Regex
+    fn parse_regex(&mut self) -> ParseResult<String> {
+        let v = match self.peek() {
+            Some(v) if v.len() > 1 && (v.starts_with('/') || v.starts_with('[')) => v,
+            _ => return self.error("regular expression"),
+        };
+        let pattern = match v.strip_prefix('/') {
+            Some(p) => p.strip_suffix('/').unwrap_or(p),
+            None => v,
+        };
+        self.next += 1;
+        Ok(pattern.to_string())
+    }
+
+    // %token NAME REGEX or %skip REGEX
+    fn parse_token(&mut self) -> ParseResult<()> {
+        let skip = self.peek() == Some("%skip");
+        self.next += 1;
+        if skip {
+            let at = self.next;
+            let pattern = self.parse_regex()?;
+            if let Err(why) = self.shared.grammar.add_skip(&pattern) {
+                self.next = at;
+                return self.error(&format!("regular expression ({})", why));
+            }
+            return Ok(());
+        }
+        let pos = match self.tokens.get(self.next) {
+            Some(t) => t.beg.clone(),
+            None => self.eos_pos.clone(),
+        };
+        let name = match self.peek() {
+            Some(v) if !is_punct(v) && !v.starts_with('\'') && !v.starts_with('"')
+                && !v.starts_with('/') && !v.starts_with('[') => {
+                self.parse_name()
+            }
+            _ => return self.error("token name"),
+        };
+        if self.shared.grammar.is_token(&name) {
+            self.push_error(GrammarError::new(pos, "token name not declared before", found(&name)));
+            return Err(());
+        }
+        let at = self.next;
+        let pattern = self.parse_regex()?;
+        if let Err(why) = self.shared.grammar.add_token(&name, &pattern) {
+            self.next = at;
+            return self.error(&format!("regular expression ({})", why));
+        }
+        self.shared.tokens.push((name, pos, self.file.clone()));
+        Ok(())
+    }
+
+    // %left TERMINAL+ (also %right and %nonassoc)
+    fn parse_precedence(&mut self, assoc : Assoc) -> ParseResult<()> {
+        self.next += 1;
+        let mut terminals = Vec::new();
+        while let Some(v) = self.peek() {
+            if !v.starts_with('\'') && !v.starts_with('"') {
+                break;
+            }
+            self.check_escapes();
+            let terminal = terminal_value(v);
+            if self.shared.grammar.terminal_precedence(&terminal).is_some() || terminals.contains(&terminal) {
+                return self.error("terminal without a precedence level");
+            }
+            terminals.push(terminal);
+            self.next += 1;
+        }
+        if terminals.is_empty() {
+            return self.error("quoted terminal");
+        }
+        self.shared.grammar.add_precedence(assoc, &terminals);
+        Ok(())
+    }
+
+    // the quoted terminal after %prec, which needs a precedence level
+    fn parse_prec_terminal(&mut self) -> ParseResult<String> {
+        match self.peek() {
+            Some(v) if v.starts_with('\'') || v.starts_with('"') => {
+                self.check_escapes();
+                let terminal = terminal_value(v);
+                if self.shared.grammar.terminal_precedence(&terminal).is_none() {
+                    return self.error("terminal with a precedence level");
                 }
-            },
-            State::ComponentName => {
-                let last_com = production.components.last_mut().unwrap();
-                last_com.name = Some(value.clone());
-                state = State::ComponentNameEnd;
-            },
-            State::ComponentNameEnd => {
-                if value == ")" {
-                    state = State::Components;
-                } else {
-                    // report error
-                    println!("expecting ')' to end component name");
-                    failed = true;
+                self.next += 1;
+                Ok(terminal)
+            }
+            _ => self.error("quoted terminal"),
+        }
+    }
+
+    // report undefined start nonterminals
+    fn check_starts(&mut self) {
+        let starts = mem::take(&mut self.shared.starts);
+        for (name, pos, file) in starts {
+            if !self.shared.grammar.is_nonterminal(&name) {
+                let mut e = GrammarError::new(pos, "defined start nonterminal", found(&name));
+                e.file = file.map(|f| f.display().to_string());
+                self.shared.errors.push(e);
+            } else {
+                self.shared.grammar.add_start(&name);
+            }
+        }
+    }
+
+    // report malformed escapes in the quoted terminal at the next token
+    fn check_escapes(&mut self) {
+        let t = &self.tokens[self.next];
+        let text = self.text(&t.beg, &t.end);
+        if let Err(e) = unescape(&text[1..text.len() - 1], '\\') {
+            let mut pos = t.beg.clone();
+            for ch in text[..e.offset + 1].chars() {
+                pos.update(ch);
+            }
+            self.push_error(GrammarError::new(pos, "escape sequence", found(&e.sequence)));
+        }
+    }
+
+    // "file" after %include or %extends
+    fn parse_file_name(&mut self) -> ParseResult<String> {
+        match self.peek() {
+            Some(v) if v.len() > 1 && (v.starts_with('"') || v.starts_with('\'')) => {
+                self.next += 1;
+                Ok(v[1..v.len() - 1].to_string())
+            }
+            _ => self.error("quoted file name"),
+        }
+    }
+
+    // %extends "file"
+    fn parse_extends(&mut self) -> ParseResult<()> {
+        if !self.local.is_empty() {
+            return self.error("%extends before the rules");
+        }
+        let directive_pos = self.tokens[self.next].beg.clone();
+        self.next += 1;
+        let name = self.parse_file_name()?;
+        let prefix = self.prefix.clone();
+        self.include_file(directive_pos, &name, prefix);
+        // everything defined so far comes from extended files
+        for n in &self.local {
+            self.shared.inherited.insert(format!("{}{}", self.prefix, n));
+        }
+        Ok(())
+    }
+
+    // report tokens named like a nonterminal
+    fn check_tokens(&mut self) {
+        let tokens = mem::take(&mut self.shared.tokens);
+        for (name, pos, file) in tokens {
+            if self.shared.grammar.is_nonterminal(&name) {
+                let mut e = GrammarError::new(pos, "token name that is not a nonterminal", found(&name));
+                e.file = file.map(|f| f.display().to_string());
+                self.shared.errors.push(e);
+            }
+        }
+    }
+
+    // %include "file" [as NAME]
+    fn parse_include(&mut self) -> ParseResult<()> {
+        let directive_pos = self.tokens[self.next].beg.clone();
+        self.next += 1;
+        let name = self.parse_file_name()?;
+        let mut prefix = self.prefix.clone();
+        if self.peek() == Some("as") {
+            self.next += 1;
+            match self.peek() {
+                Some(v) if !is_punct(v) => {
+                    self.namespaces.insert(v.to_string());
+                    prefix = format!("{}{}::", self.prefix, v);
+                    self.next += 1;
                 }
-            },
-            State::EventName => {
-                production.name = Some(value.clone());
-                // save s
-                state = State::EventNameEnd;
-            },
-            State::ComponentsEnd => {
-                if value == ";" {
-                    let nonterm = nonterminal.as_ref().unwrap();
-                    grammar.add_rule(
-                        &nonterm,
-                        production
-                    );
-                    production = Production::new();
-                    // expect another nonterminal or eos
-                    state = State::Nonterminal;
-                } else if value == "|" {
-                    let nonterm = nonterminal.as_ref().unwrap();
-                    grammar.add_rule(
-                        &nonterm,
-                        production
-                    );
-                    production = Production::new();
-                    state = State::Components;
-                } else {
-                    println!("exp ';' not {}", value);
-                    failed = true;
+                _ => return self.error("namespace name"),
+            }
+        }
+        self.include_file(directive_pos, &name, prefix);
+        Ok(())
+    }
+
+    // parse file 'name' with the rules in namespace 'prefix'
+    fn include_file(&mut self, directive_pos : Position, name : &str, prefix : String) {
+        let path = match self.file {
+            Some(ref f) => f.parent().unwrap_or(Path::new("")).join(name),
+            None => PathBuf::from(name),
+        };
+        // compare files by canonical path, if it exists
+        let key = path.canonicalize().unwrap_or(path.clone());
+
+        if self.shared.stack.contains(&key) {
+            let mut chain : Vec<String> = self.shared.stack.iter()
+                .skip_while(|p| **p != key)
+                .map(|p| p.display().to_string())
+                .collect();
+            chain.push(key.display().to_string());
+            let found = format!("\"{}\" ({})", name, chain.join(" -> "));
+            self.push_error(GrammarError::new(directive_pos, "non-cyclic include", found));
+            return;
+        }
+        if !self.shared.included.insert((key.clone(), prefix.clone())) {
+            // already included in this namespace
+            return;
+        }
+
+        let content = match read_file(&path) {
+            Ok(s) => s,
+            Err(why) => {
+                let found = format!("\"{}\" ({})", path.display(), why);
+                self.push_error(GrammarError::new(directive_pos, "readable grammar file", found));
+                return;
+            }
+        };
+
+        let shared = mem::replace(&mut self.shared, Shared::new());
+        let mut child = Parser::new(&content, Some(path), shared);
+        child.shared.stack.push(key);
+        child.prefix = prefix.clone();
+        child.parse_rules();
+        child.shared.stack.pop();
+        if prefix == self.prefix {
+            // same namespace, the names are qualified with ours
+            for n in child.local.drain() {
+                self.local.insert(n);
+            }
+            for n in child.namespaces.drain() {
+                self.namespaces.insert(n);
+            }
+        }
+        self.shared = child.shared;
+    }
+
+    /*
+     * Text of the comments between token 'after' (start of input
+     * if None) and token 'before', markers and indentation removed
+     *
+     * Comments starting on the line of 'after' trail the previous rule
+     * or alternative and are not part of the doc, unless they also end
+     * on the line of 'before'.
+     */
+    fn doc_between(&self, after : Option<usize>, before : usize) -> Option<String> {
+        let from = after.map_or(self.base, |i| self.tokens[i].end.pos);
+        let after_line = after.map(|i| self.tokens[i].end.line);
+        let to = self.tokens.get(before).map_or(self.base + self.input.len(), |t| t.beg.pos);
+        let before_line = self.tokens.get(before).map(|t| t.beg.line);
+        let mut lines : Vec<&str> = Vec::new();
+        for c in &self.comments {
+            if c.beg.pos < from || c.end.pos > to
+                || (Some(c.beg.line) == after_line && Some(c.end.line) != before_line) {
+                continue;
+            }
+            let text = self.text(&c.beg, &c.end);
+            let text = if let Some(t) = text.strip_prefix("/*") {
+                t.trim_end_matches("*/")
+            } else if let Some(t) = text.strip_prefix("//") {
+                t
+            } else {
+                &text[1..]
+            };
+            for line in text.lines() {
+                let line = line.trim();
+                // ' * ' continuation lines of block comments
+                let line = line.strip_prefix('*').map_or(line, |l| l.trim());
+                if !line.is_empty() || !lines.is_empty() {
+                    lines.push(line);
                 }
-            },
-            State::EventNameEnd => {
-                if value == "`" {
-                    state = State::ComponentsEnd;
+            }
+        }
+        while lines.last() == Some(&"") {
+            lines.pop();
+        }
+        if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        }
+    }
+
+    // true if token i+1 follows token i without whitespace
+    fn adjacent(&self, i : usize) -> bool {
+        i + 1 < self.tokens.len() && self.tokens[i].end.pos == self.tokens[i + 1].beg.pos
+    }
+
+    // NAME or NAME::NAME... (the parts must not be separated by spaces)
+    // a name, digits are tokens of their own and are joined with the
+    // adjacent parts (INT32, A1)
+    fn parse_name(&mut self) -> String {
+        let word = |v : &str| v.starts_with(|c : char| c.is_alphanumeric() || c == '_');
+        let mut name = self.advance().to_string();
+        if !word(&name) {
+            return name;
+        }
+        while self.adjacent(self.next - 1) && self.peek().is_some_and(word) {
+            name.push_str(self.advance());
+        }
+        name
+    }
+
+    fn parse_qualified(&mut self) -> String {
+        let mut name = self.parse_name();
+        loop {
+            let n = self.next;
+            let qualified = self.peek() == Some(":") && self.peek_at(1) == Some(":")
+                && self.adjacent(n - 1) && self.adjacent(n) && self.adjacent(n + 1)
+                && !is_punct(self.peek_at(2).unwrap());
+            if !qualified {
+                break;
+            }
+            self.next += 2;
+            name.push_str("::");
+            let part = self.parse_name();
+            name.push_str(&part);
+        }
+        name
+    }
+
+    fn parse_rule(&mut self) -> ParseResult<()> {
+        let name_tok = self.next;
+        if self.peek_at(1) == Some("<") && self.adjacent(name_tok) {
+            return self.parse_template();
+        }
+        let nonterm = match self.peek() {
+            Some(v) if !is_punct(v) => {
+                let name = self.parse_qualified();
+                self.local.insert(name.clone());
+                format!("{}{}", self.prefix, name)
+            }
+            _ => return self.error("nonterminal name"),
+        };
+        if let Some(doc) = self.doc_between(name_tok.checked_sub(1), name_tok) {
+            self.shared.grammar.set_doc(&nonterm, &doc);
+        }
+        // Rule |= ... and Rule -= ...
+        let operator = match self.peek() {
+            Some(v) if (v == "|" || v == "-") && self.peek_at(1) == Some("=") && self.adjacent(self.next) => Some(v),
+            _ => None,
+        };
+        if let Some(operator) = operator {
+            if !self.shared.inherited.contains(&nonterm) {
+                self.next = name_tok;
+                return self.error("inherited nonterminal");
+            }
+            self.shared.redefined.insert(nonterm.clone());
+            self.next += 2;
+            let first = self.next;
+            let prods = self.parse_alternatives(&nonterm, true)?;
+            self.expect(";", "';'")?;
+            if operator == "|" {
+                for prod in prods {
+                    self.shared.grammar.add_rule(&nonterm, prod);
+                }
+            } else {
+                self.remove_productions(&nonterm, first, prods);
+            }
+            return Ok(());
+        }
+        // a definition replaces an inherited nonterminal
+        if (self.peek() == Some(";") || self.peek() == Some(":")) && self.shared.inherited.contains(&nonterm)
+            && self.shared.redefined.insert(nonterm.clone()) {
+            while !self.shared.grammar.iter_over_nonterm(&nonterm).is_empty() {
+                self.shared.grammar.remove_production(&nonterm, 0);
+            }
+        }
+        match self.peek() {
+            // nonterminal without productions
+            Some(";") => {
+                self.next += 1;
+                return Ok(());
+            }
+            Some(":") => {
+                self.next += 1;
+                // added before the helpers of its groups and operators
+                self.shared.grammar.prodlist(&nonterm);
+            }
+            _ => return self.error("':', ';', '|=' or '-='"),
+        }
+        let prods = self.parse_alternatives(&nonterm, true)?;
+        self.expect(";", "';'")?;
+        for prod in prods {
+            self.shared.grammar.add_rule(&nonterm, prod);
+        }
+        Ok(())
+    }
+
+    /*
+     * Remove the productions of nonterm written like prods
+     * (Rule -= prods ;), the alternatives start at token 'first'
+     */
+    fn remove_productions(&mut self, nonterm : &str, first : usize, prods : Vec<Production>) {
+        // token ranges of the alternatives, for errors
+        let mut ranges = Vec::new();
+        let mut start = first;
+        let mut depth = 0;
+        for i in first..self.next {
+            let t = &self.tokens[i];
+            match self.text(&t.beg, &t.end) {
+                "(" => depth += 1,
+                ")" => depth -= 1,
+                "|" | ";" if depth == 0 => {
+                    ranges.push((start, i));
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        for (prod, (start, end)) in prods.into_iter().zip(ranges) {
+            let source = self.shared.grammar.production_source(&prod);
+            let prods = self.shared.grammar.iter_over_nonterm(nonterm);
+            let index = prods.iter().position(|p| self.shared.grammar.production_source(p) == source);
+            // the last production stays, the rule would match nothing
+            let expected = match index {
+                Some(i) if prods.len() > 1 => {
+                    self.shared.grammar.remove_production(nonterm, i);
+                    continue;
+                }
+                Some(_) => format!("production of {} that is not its last", nonterm),
+                None => format!("production of {}", nonterm),
+            };
+            let text = self.text(&self.tokens[start].beg, &self.tokens[end].beg);
+            let e = GrammarError::new(self.tokens[start].beg.clone(), expected, format!("'{}'", text.trim()));
+            self.push_error(e);
+        }
+        // helpers of the removed alternatives as written after -=
+        self.shared.grammar.remove_unused_helpers();
+    }
+
+    // Name<A, B> : body ;
+    fn parse_template(&mut self) -> ParseResult<()> {
+        let name = match self.peek() {
+            Some(v) if !is_punct(v) => format!("{}{}", self.prefix, v),
+            _ => return self.error("template name"),
+        };
+        self.next += 2;
+        let mut params : Vec<String> = Vec::new();
+        loop {
+            match self.peek() {
+                Some(v) if !is_punct(v) && v != "," && v != ">" => {
+                    params.push(v.to_string());
+                    self.next += 1;
+                }
+                _ => return self.error("template parameter"),
+            }
+            match self.peek() {
+                Some(",") => self.next += 1,
+                Some(">") => {
+                    self.next += 1;
+                    break;
+                }
+                _ => return self.error("',' or '>'"),
+            }
+        }
+        self.expect(":", "':'")?;
+        let body = self.next;
+        loop {
+            match self.peek() {
+                Some(";") => {
+                    self.next += 1;
+                    break;
+                }
+                Some(_) => self.next += 1,
+                None => return self.error("';'"),
+            }
+        }
+        let end = self.next;
+        let valid = self.check_template_body(&name, &params, body);
+        self.next = end;
+
+        let text = self.text(&self.tokens[body].beg, &self.tokens[end - 1].beg);
+        let definition = format!("{}<{}> : {} ;", name, params.join(", "), text.trim());
+        self.shared.grammar.templates.insert(name.clone(), definition);
+        self.shared.templates.insert(name, Template {
+            params,
+            body : self.text(&self.tokens[body].beg, &self.tokens[end - 1].end).to_string(),
+            body_pos : self.tokens[body].beg.clone(),
+            valid,
+            file : self.file.clone(),
+            prefix : self.prefix.clone(),
+        });
+        Ok(())
+    }
+
+    /*
+     * Parse the body of a template starting at token 'body' into a
+     * scratch grammar, with the parameters standing for nonterminals,
+     * and report its errors. Returns true if there were none.
+     */
+    fn check_template_body(&mut self, name : &str, params : &[String], body : usize) -> bool {
+        let shared = mem::replace(&mut self.shared, Shared::new());
+        self.subst = params.iter()
+            .map(|p| (p.clone(), Component::new(RuleId::Nonterminal(p.clone()))))
+            .collect();
+        self.next = body;
+        self.shared.grammar.prodlist(name);
+        let r = self.parse_alternatives(name, true)
+            .and_then(|_| self.expect(";", "';'"));
+        self.subst.clear();
+        let scratch = mem::replace(&mut self.shared, shared);
+        let valid = r.is_ok() && scratch.errors.is_empty();
+        self.shared.errors.extend(scratch.errors);
+        valid
+    }
+
+    // move the positions of the tokens from the start of the input to 'origin'
+    fn shift_positions(&mut self, origin : &Position) {
+        let shift = |p : &mut Position| {
+            if p.line == 1 {
+                p.col += origin.col;
+                p.utf16_col += origin.utf16_col;
+            }
+            p.line += origin.line - 1;
+            p.pos += origin.pos;
+            p.char_pos += origin.char_pos;
+        };
+        self.base = origin.pos;
+        for t in self.tokens.iter_mut().chain(self.comments.iter_mut()) {
+            shift(&mut t.beg);
+            shift(&mut t.end);
+        }
+        shift(&mut self.eos_pos);
+    }
+
+    // after Name: '<' item (',' item)* '>'
+    fn parse_instance(&mut self, nonterm : &str, name : String, pos : Position) -> ParseResult<Component> {
+        self.next += 1;
+        let mut args : Vec<Component> = Vec::new();
+        loop {
+            match self.peek() {
+                Some(",") | Some(">") => return self.error("template argument"),
+                _ => args.push(self.parse_item(nonterm)?),
+            }
+            match self.peek() {
+                Some(",") => self.next += 1,
+                Some(">") => {
+                    self.next += 1;
+                    break;
+                }
+                _ => return self.error("',' or '>'"),
+            }
+        }
+        let placeholder = format!("<instance {}>", self.shared.instances.len());
+        self.shared.instances.push(Instance {
+            placeholder : placeholder.clone(),
+            template : name,
+            args,
+            prefix : self.prefix.clone(),
+            file : self.file.clone(),
+            pos,
+        });
+        Ok(Component::new(RuleId::Nonterminal(placeholder)))
+    }
+
+    /*
+     * Find the definition a name refers to from namespace prefix:
+     * prefix::name, then the enclosing namespaces, then name
+     *
+     * templates - look for a template instead of a nonterminal
+     */
+    fn lookup_name(&self, prefix : &str, name : &str, templates : bool) -> Option<String> {
+        let mut p = prefix;
+        loop {
+            let candidate = format!("{}{}", p, name);
+            let defined = if templates {
+                self.shared.templates.contains_key(&candidate)
+            } else {
+                self.shared.grammar.is_nonterminal(&candidate)
+            };
+            if defined {
+                return Some(candidate);
+            }
+            if p.is_empty() {
+                return None;
+            }
+            // "a::b::" -> "a::"
+            p = &p[..p.len() - 2];
+            p = match p.rfind("::") {
+                Some(i) => &p[..i + 2],
+                None => "",
+            };
+        }
+    }
+
+    // text of a template argument in the instance name
+    fn arg_name(com : &Component) -> String {
+        match com.rule {
+            RuleId::Terminal(ref s) => s.clone(),
+            RuleId::Nonterminal(ref s) => s.clone(),
+            RuleId::Regex(ref s) => format!("/{}/", s),
+            RuleId::Class(ref s) => format!("<{}>", s),
+        }
+    }
+
+    // expand the template references, including the ones
+    // found while expanding
+    fn expand_instances(&mut self) {
+        // placeholder -> instance name
+        let mut names : HashMap<String, String> = HashMap::new();
+        let mut expanded : HashSet<String> = HashSet::new();
+        let mut i = 0;
+        while i < self.shared.instances.len() {
+            let inst = self.shared.instances[i].clone();
+            i += 1;
+            let mut e = GrammarError::new(inst.pos.clone(), "", "");
+            e.file = inst.file.as_ref().map(|f| f.display().to_string());
+
+            if i > MAX_INSTANCES {
+                e.expected = format!("at most {} template instances", MAX_INSTANCES);
+                e.found = inst.template.clone();
+                self.shared.errors.push(e);
+                break;
+            }
+            let template_name = match self.lookup_name(&inst.prefix, &inst.template, true) {
+                Some(n) => n,
+                None => {
+                    e.expected = "template".to_string();
+                    e.found = found(&inst.template);
+                    self.shared.errors.push(e);
+                    continue;
+                }
+            };
+            let template = self.shared.templates[&template_name].clone();
+            if template.params.len() != inst.args.len() {
+                e.expected = format!("{} template arguments", template.params.len());
+                e.found = format!("{}", inst.args.len());
+                self.shared.errors.push(e);
+                continue;
+            }
+
+            // arguments refer to definitions seen from the reference
+            let mut args : Vec<Component> = Vec::new();
+            for arg in &inst.args {
+                let mut a = arg.clone();
+                a.rule = match arg.rule {
+                    RuleId::Terminal(ref s) => {
+                        match self.lookup_name(&inst.prefix, s, false) {
+                            Some(n) => RuleId::Nonterminal(n),
+                            None => RuleId::Terminal(s.clone()),
+                        }
+                    }
+                    RuleId::Nonterminal(ref s) => {
+                        RuleId::Nonterminal(names.get(s).cloned().unwrap_or(s.clone()))
+                    }
+                    ref r => r.clone(),
+                };
+                args.push(a);
+            }
+            let arg_names : Vec<String> = args.iter().map(Parser::arg_name).collect();
+            let name = format!("{}<{}>", template_name, arg_names.join(", "));
+            names.insert(inst.placeholder.clone(), name.clone());
+            if !expanded.insert(name.clone()) || !template.valid {
+                continue;
+            }
+            self.shared.grammar.instances.insert(name.clone());
+
+            // parse the body with the parameters replaced by the arguments
+            let shared = mem::replace(&mut self.shared, Shared::new());
+            let errors = shared.errors.len();
+            let mut child = Parser::new(&template.body, template.file.clone(), shared);
+            child.shift_positions(&template.body_pos);
+            child.prefix = template.prefix.clone();
+            child.subst = template.params.iter().cloned().zip(args).collect();
+            child.shared.grammar.prodlist(&name);
+            let first_id = child.shared.grammar.prod_seq_no;
+            if let Ok(prods) = child.parse_alternatives(&name, true) {
+                for prod in prods {
+                    child.shared.grammar.add_rule(&name, prod);
+                }
+            }
+            // errors in the body were reported at the definition
+            child.shared.errors.truncate(errors);
+            self.shared = child.shared;
+
+            // names in the body refer to definitions seen from the template
+            if !template.prefix.is_empty() {
+                let mut local : HashSet<String> = HashSet::new();
+                let mut namespaces : HashSet<String> = HashSet::new();
+                for nt in self.shared.grammar.nonterminals() {
+                    if let Some(n) = nt.strip_prefix(template.prefix.as_str()) {
+                        match n.find("::") {
+                            Some(i) => { namespaces.insert(n[..i].to_string()); }
+                            None => { local.insert(n.to_string()); }
+                        }
+                    }
+                }
+                self.shared.grammar.qualify(first_id, &local, &namespaces, &template.prefix);
+            }
+        }
+        self.shared.grammar.rename_references(&names);
+    }
+
+    // the whole input is one (qualified) NAME
+    fn parse_name_only(&mut self, expected : &str) -> ParseResult<String> {
+        let name = match self.peek() {
+            Some(v) if !is_punct(v) && !v.starts_with('\'') && !v.starts_with('"') => {
+                self.parse_qualified()
+            }
+            _ => return self.error(expected),
+        };
+        if self.peek().is_some() {
+            return self.error(expected);
+        }
+        Ok(name)
+    }
+
+    // the whole input is one item
+    fn parse_item_only(&mut self, nonterm : &str) -> ParseResult<Component> {
+        let com = self.parse_item(nonterm)?;
+        if self.peek().is_some() {
+            return self.error("end of item");
+        }
+        Ok(com)
+    }
+
+    // top - true for the alternatives of a rule (events allowed),
+    //       false inside a group
+    fn parse_alternatives(&mut self, nonterm : &str, top : bool) -> ParseResult<Vec<Production>> {
+        let mut prods : Vec<Production> = Vec::new();
+        loop {
+            let mut production = Production::new();
+            if top {
+                // comments between the previous alternative
+                // (or the rule name) and this one
+                let first = self.next;
+                production.doc = self.doc_between(first.checked_sub(2), first);
+            }
+            loop {
+                match self.peek() {
+                    None | Some(";") | Some("|") | Some(")") | Some("`") | Some("%prec") => break,
+                    _ => {
+                        let com = self.parse_item(nonterm)?;
+                        production.components.push(com);
+                    }
+                }
+            }
+            if top && self.peek() == Some("%prec") {
+                self.next += 1;
+                production.precedence = Some(self.parse_prec_terminal()?);
+            }
+            if top && self.peek() == Some("`") {
+                self.next += 1;
+                match self.peek() {
+                    Some(v) if !is_punct(v) => {
+                        production.name = Some(self.advance().to_string());
+                    }
+                    _ => return self.error("event name"),
+                }
+                self.expect("`", "'`' to end the event name")?;
+            }
+            prods.push(production);
+            match self.peek() {
+                Some("|") => { self.next += 1; }
+                Some(";") if top => break,
+                Some(")") if !top => break,
+                _ => {
+                    return self.error(if top { "component, '|' or ';'" }
+                                      else { "component, '|' or ')'" });
+                }
+            }
+        }
+        Ok(prods)
+    }
+
+    fn parse_item(&mut self, nonterm : &str) -> ParseResult<Component> {
+        let predicate = match self.peek() {
+            Some("&") => Some(Predicate::And),
+            Some("!") => Some(Predicate::Not),
+            _ => None,
+        };
+        if let Some(p) = predicate {
+            self.next += 1;
+            let com = self.parse_item(nonterm)?;
+            return Ok(self.shared.grammar.add_predicate(nonterm, p, com));
+        }
+        let mut com = match self.peek() {
+            Some("(") => {
+                self.next += 1;
+                let prods = self.parse_alternatives(nonterm, false)?;
+                self.expect(")", "')' to end the group")?;
+                self.shared.grammar.add_helper(nonterm, prods)
+            }
+            Some("<") => {
+                // <NAME>, the parts of the name are not separated by spaces
+                let pos = self.tokens[self.next].beg.clone();
+                self.next += 1;
+                let mut name = String::new();
+                while let Some(v) = self.peek() {
+                    if v == ">" || is_punct(v) || (!name.is_empty() && !self.adjacent(self.next - 1)) {
+                        break;
+                    }
+                    name.push_str(v);
+                    self.next += 1;
+                }
+                if name.is_empty() {
+                    return self.error("token class name");
+                }
+                self.expect(">", "'>' to end the token class")?;
+                let file = self.file.as_ref().map(|f| f.display().to_string());
+                self.shared.grammar.add_class_use(&name, nonterm, pos, file);
+                Component::new(RuleId::Class(name))
+            }
+            Some(v) if v.starts_with('/') || v.starts_with('[') => {
+                // '/regex/' or '[class]'
+                let pattern = match v.strip_prefix('/') {
+                    Some(p) => p.strip_suffix('/').unwrap_or(p),
+                    None => v,
+                };
+                if let Err(why) = Regex::new(pattern) {
+                    let found = format!("{} ({})", v, why);
+                    let e = GrammarError::new(self.tokens[self.next].beg.clone(),
+                                              "regular expression", found);
+                    self.push_error(e);
+                }
+                self.next += 1;
+                Component::new(RuleId::Regex(pattern.to_string()))
+            }
+            Some(v) if !is_punct(v) => {
+                let pos = self.tokens[self.next].beg.clone();
+                if v.starts_with('\'') || v.starts_with('"') {
+                    self.check_escapes();
+                }
+                let name = self.parse_qualified();
+                if let Some(arg) = self.subst.get(&name) {
+                    arg.clone()
+                } else if self.peek() == Some("<") && self.adjacent(self.next - 1) {
+                    self.parse_instance(nonterm, name, pos)?
+                } else if (name.starts_with('\'') || name.starts_with('"'))
+                    && self.peek() == Some("i") && self.adjacent(self.next - 1) {
+                    // 'select'i
+                    self.next += 1;
+                    let mut com = Component::new(RuleId::Terminal(name));
+                    com.ignore_case = true;
+                    com
                 } else {
-                    // error: exp ` to end the event name
+                    Component::new(RuleId::Terminal(name))
                 }
-            },
+            }
+            _ => return self.error("component"),
+        };
+        loop {
+            com = match self.peek() {
+                Some("?") => self.shared.grammar.add_optional(nonterm, com),
+                Some("*") => self.shared.grammar.add_star(nonterm, com),
+                Some("+") => self.shared.grammar.add_plus(nonterm, com),
+                _ => break,
+            };
+            self.next += 1;
+        }
+        // '(' NAME ')' names the component, unless it is a group
+        // followed by an operator: x (y)*
+        if self.peek() == Some("(") && self.peek_at(2) == Some(")") {
+            let postfix = matches!(self.peek_at(3), Some("?") | Some("*") | Some("+"));
+            let is_name = match self.peek_at(1) {
+                Some(v) => !is_punct(v) && !postfix,
+                None => false,
+            };
+            if is_name {
+                self.next += 1;
+                com.name = Some(self.advance().to_string());
+                self.next += 1;
+            }
+        }
+        Ok(com)
+    }
+}
+
+fn read_file(path : &Path) -> Result<String, ::std::io::Error> {
+    let mut file = File::open(path)?;
+    let mut s = String::new();
+    file.read_to_string(&mut s)?;
+    Ok(s)
+}
+
+/*
+ * Load a grammar from productions given in code (see GrammarBuilder)
+ *
+ * rules - nonterminal, items in the grammar language ("Term", "'+'",
+ *         "/[0-9]+/", "Arg*", ...) and the production holding the
+ *         event name and documentation
+ * starts - start nonterminals (%start)
+ *
+ * errors carry the nonterminal in GrammarError::nonterm and the
+ * position inside the item, name or event name
+ */
+pub fn load_grammar_parts<'a>(
+    rules : &'a [(String, Vec<String>, Production)],
+    starts : &[String],
+) -> Result<Grammar, Vec<GrammarError>> {
+    let mut shared = Shared::new();
+    for name in starts {
+        shared.starts.push((name.clone(), Position::new(), None));
+    }
+    for (nonterm, items, production) in rules {
+        let mut prod = production.clone();
+        prod.components.clear();
+
+        let parser = |input : &'a str, shared : Shared| {
+            let mut p = Parser::new(input, None, shared);
+            p.nonterm = Some(nonterm.clone());
+            p
+        };
+        let mut p = parser(nonterm, shared);
+        let valid = p.parse_name_only("nonterminal name").is_ok();
+        shared = p.shared;
+        if let Some(ref event) = production.name {
+            let mut p = parser(event, shared);
+            let _ = p.parse_name_only("event name");
+            shared = p.shared;
+        }
+        for item in items {
+            let mut p = parser(item, shared);
+            if let Ok(com) = p.parse_item_only(nonterm) {
+                prod.components.push(com);
+            }
+            shared = p.shared;
+        }
+        if valid {
+            shared.grammar.add_rule(nonterm, prod);
+        }
+    }
+    // expand template references and resolve the names
+    Parser::new("", None, shared).parse()
+}
+
+// load grammar from string and produce a grammar structure
+//
+// all syntax errors are collected; after an error the loader skips
+// to the next ';' and continues with the next nonterminal
+//
+// included files are relative to the current directory
+pub fn load_grammar_str(input_str : &str) -> Result<Grammar, Vec<GrammarError>> {
+    Parser::new(input_str, None, Shared::new()).parse()
+}
+
+// load grammar from a file, includes are relative to the file
+pub fn load_grammar_file<P : AsRef<Path>>(path : P) -> Result<Grammar, Vec<GrammarError>> {
+    let path = path.as_ref();
+    match read_file(path) {
+        Ok(s) => {
+            // the stack holds canonical paths for cycle detection
+            let key = path.canonicalize().unwrap_or(path.to_path_buf());
+            let mut shared = Shared::new();
+            shared.included.insert((key.clone(), String::new()));
+            shared.stack.push(key);
+            Parser::new(&s, Some(path.to_path_buf()), shared).parse()
+        }
+        Err(why) => {
+            let mut e = GrammarError::new(Position::new(), "readable grammar file",
+                                          format!("\"{}\" ({})", path.display(), why));
+            e.file = Some(path.display().to_string());
+            Err(vec![e])
         }
     }
-    grammar.resolve();
-    grammar
 }
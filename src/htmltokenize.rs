@@ -1,9 +1,9 @@
 // if the char is A-Z return lowercase a-z
 fn asciilowerchar(a : char) -> char {
-    if a >= 'A' && a <= 'Z' {
+    if a.is_ascii_uppercase() {
         let ai = a as u8;
-        let ax = 'A' as u8;
-        let ay = 'a' as u8;
+        let ax = b'A';
+        let ay = b'a';
         (ai - ax + ay) as char
     } else {
         a
@@ -34,13 +34,8 @@ pub struct HTMLToken {
 
 impl HTMLToken {
     pub fn get_attrib_value(&self, name: &str) -> Option<String> {
-        let index = self.attribs.iter().position(|ref kv| kv.key == name);
-        match index {
-            Some(idx) => {
-                Some(self.attribs[idx].value.clone())
-            }
-            None => None
-        }
+        let index = self.attribs.iter().position(|kv| kv.key == name);
+        index.map(|idx| self.attribs[idx].value.clone())
     }
 
     fn parse_attribs(v : &[char]) -> Vec<KeyValue> {
@@ -128,13 +123,11 @@ impl HTMLToken {
             attrib_end += 1;
         }
 
-        let attribs: Vec<KeyValue>;
-
-        if attrib_start != attrib_end {
-            attribs = HTMLToken::parse_attribs(&v[attrib_start..attrib_end]);
+        let attribs = if attrib_start != attrib_end {
+            HTMLToken::parse_attribs(&v[attrib_start..attrib_end])
         } else {
-            attribs = Vec::<KeyValue>::new();
-        }
+            Vec::<KeyValue>::new()
+        };
 
         // get the end of the token
         while j < v.len() {
@@ -143,8 +136,8 @@ impl HTMLToken {
         }
 
         HTMLToken {
-            attribs: attribs,
-            value: String::from(r),
+            attribs,
+            value: r,
         }
     }
 
@@ -169,8 +162,8 @@ impl Buf {
         for (_, c) in s.char_indices() {
             v.push(c);
         }
-        return Buf {
-            v : v,
+        Buf {
+            v,
             i : 0
         }
     }
@@ -287,7 +280,7 @@ pub fn tokenize_html(s : &str) -> Vec<HTMLToken> {
             let s = b.extract_without('<');
             // check if s is empty / whitespace
             let trimmed = s.trim();
-            if trimmed.len() > 0 {
+            if !trimmed.is_empty() {
                 v.push(HTMLToken::text(trimmed));
             }
         }
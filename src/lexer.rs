@@ -0,0 +1,143 @@
+/*!
+ * Longest-match lexer built from the %token and %skip declarations
+ *
+ * At each position the longest match of a token regex, a literal
+ * terminal of the grammar or a skip regex is taken; on a tie literals
+ * win over tokens (keywords over identifiers), earlier tokens over
+ * later ones and both over skips. Skipped text is dropped, text that
+ * nothing matches is an error. Each lexeme records the %token that
+ * matched it, <NAME> terminals compare that name.
+ */
+
+use std::fmt;
+use std::error::Error;
+
+use normalize::fold_case;
+use regex::Regex;
+use tokenize::Position;
+
+// token of the input text
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexeme {
+    pub text : String,
+    // name of the %token, None for a literal terminal
+    pub token : Option<String>,
+    pub beg : Position,
+    pub end : Position,
+}
+
+/**
+ * Text that no token, literal or skip matches
+ *
+ * pos   - position of the char
+ * found - the char
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexError {
+    pub pos : Position,
+    pub found : char,
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: expected token, found '{}'", self.pos.line, self.pos.col, self.found)
+    }
+}
+
+impl Error for LexError {}
+
+pub struct Lexer {
+    // %token name and regular expression
+    tokens : Vec<(String, Regex)>,
+    // literal terminals of the grammar, true if matched ignoring case
+    literals : Vec<(String, bool)>,
+    skips : Vec<Regex>,
+}
+
+impl Default for Lexer {
+    fn default() -> Lexer {
+        Lexer::new()
+    }
+}
+
+impl Lexer {
+
+    pub fn new() -> Lexer {
+        Lexer { tokens : Vec::new(), literals : Vec::new(), skips : Vec::new() }
+    }
+
+    pub fn add_token(&mut self, name : &str, re : Regex) {
+        self.tokens.push((name.to_string(), re));
+    }
+
+    pub fn add_literal(&mut self, value : &str, ignore_case : bool) {
+        if !self.literals.iter().any(|l| l.0 == value && l.1 == ignore_case) {
+            self.literals.push((value.to_string(), ignore_case));
+        }
+    }
+
+    pub fn add_skip(&mut self, re : Regex) {
+        self.skips.push(re);
+    }
+
+    // regular expression of the token 'name'
+    pub fn token(&self, name : &str) -> Option<&Regex> {
+        self.tokens.iter().find(|t| t.0 == name).map(|t| &t.1)
+    }
+
+    // length in bytes of the longest token or literal at the start of
+    // text and the name of the token (None for a literal)
+    fn longest_token(&self, text : &str) -> (usize, Option<&str>) {
+        let mut longest = 0;
+        for &(ref value, ignore_case) in &self.literals {
+            let len = if ignore_case {
+                // the same number of chars, compared case folded
+                let n = value.chars().count();
+                let end = text.char_indices().nth(n).map_or(text.len(), |(i, _)| i);
+                if text[..end].chars().count() == n && fold_case(&text[..end]) == fold_case(value) { end } else { 0 }
+            } else if text.starts_with(value.as_str()) {
+                value.len()
+            } else {
+                0
+            };
+            longest = longest.max(len);
+        }
+        let mut token = None;
+        for (name, re) in &self.tokens {
+            let len = re.match_prefix(text).unwrap_or(0);
+            if len > longest {
+                longest = len;
+                token = Some(name.as_str());
+            }
+        }
+        (longest, token)
+    }
+
+    /**
+     * Tokens of text, an error names the position of the first char
+     * that no token, literal or skip matches
+     */
+    pub fn lex(&self, text : &str) -> Result<Vec<Lexeme>, LexError> {
+        let mut lexemes = Vec::new();
+        let mut pos = Position::new();
+        while pos.pos < text.len() {
+            let rest = &text[pos.pos..];
+            let (token, name) = self.longest_token(rest);
+            let skip = self.skips.iter().map(|re| re.match_prefix(rest).unwrap_or(0)).max().unwrap_or(0);
+            let len = token.max(skip);
+            if len == 0 {
+                let found = rest.chars().next().unwrap();
+                return Err(LexError { pos, found });
+            }
+            let beg = pos.clone();
+            for ch in rest[..len].chars() {
+                pos.update(ch);
+            }
+            if token >= skip {
+                let token = name.map(|n| n.to_string());
+                lexemes.push(Lexeme { text : rest[..len].to_string(), token, beg, end : pos.clone() });
+            }
+        }
+        Ok(lexemes)
+    }
+}
@@ -1,13 +1,34 @@
+
 mod tokenize;
+mod regex;
+mod normalize;
 mod grammar;
+mod matchers;
+mod lexer;
+mod builder;
+mod abnf;
+mod yacc;
+mod ebnf;
+mod analysis;
+mod diff;
 mod compiler;
 mod vm;
 mod htmltokenize;
 mod tests;
 
-pub use tokenize::Tokenizer;
-pub use grammar::Grammar;
-pub use grammar::load_grammar_str;
-pub use compiler::{compile_grammar, compile_grammar_file, CompiledGrammar};
-pub use vm::{run, StreamingHandler};
+pub use tokenize::{Tokenizer, Token, TokenKind, TokenizeError, EscapeError, Position, LexerConfig, tokenize, unescape};
+pub use grammar::{Grammar, GrammarError, Assoc, Predicate, Normalization};
+pub use grammar::{load_grammar_str, load_grammar_file};
+pub use builder::{GrammarBuilder, rule};
+pub use abnf::load_abnf_str;
+pub use yacc::load_yacc_str;
+pub use ebnf::load_ebnf_str;
+pub use analysis::{Diagnostic, Lookahead};
+pub use diff::{GrammarDiff, ProductionChanges, SampleDifference, sample_differences};
+pub use regex::Regex;
+pub use normalize::{fold_case, nfc};
+pub use matchers::Matchers;
+pub use lexer::{Lexer, Lexeme, LexError};
+pub use compiler::{compile, compile_with_matchers, compile_grammar, compile_grammar_file, CompiledGrammar, EntryPoint};
+pub use vm::{run, run_input, parse_str, run_entry, Input, StreamingHandler, RunError};
 pub use htmltokenize::{tokenize_html, HTMLToken};
@@ -0,0 +1,50 @@
+/*!
+ * Registry of token class matchers
+ *
+ *   let matchers = Matchers::new()
+ *       .class("NUMBER", |tok| tok.chars().all(|c| c.is_ascii_digit()))
+ *       .class("IDENT", |tok| tok.chars().all(char::is_alphanumeric));
+ *
+ * A <NUMBER> terminal matches a token if the function registered for
+ * NUMBER returns true for the token text. The compiled grammar keeps
+ * the matchers:
+ *
+ *   let cg = compile_with_matchers(&grammar, matchers)?;
+ */
+
+// returns true if the token text belongs to the class
+pub type ClassFn = dyn Fn(&str) -> bool;
+
+pub struct Matchers {
+    classes : Vec<(String, Box<ClassFn>)>,
+}
+
+impl Default for Matchers {
+    fn default() -> Matchers {
+        Matchers::new()
+    }
+}
+
+impl Matchers {
+
+    pub fn new() -> Matchers {
+        Matchers { classes : Vec::new() }
+    }
+
+    // register (or replace) the function of a token class
+    pub fn class<F>(mut self, name : &str, f : F) -> Matchers
+        where F : Fn(&str) -> bool + 'static {
+        self.classes.retain(|c| c.0 != name);
+        self.classes.push((name.to_string(), Box::new(f)));
+        self
+    }
+
+    // function registered for the class 'name'
+    pub fn get(&self, name : &str) -> Option<&ClassFn> {
+        self.classes.iter().find(|c| c.0 == name).map(|c| &*c.1)
+    }
+
+    pub fn contains(&self, name : &str) -> bool {
+        self.get(name).is_some()
+    }
+}
@@ -0,0 +1,594 @@
+/*!
+ * Small regular expression engine used for terminals
+ *
+ * Supported syntax:
+ *   literals, '.', classes [a-z_] and [^...],
+ *   escapes \d \w \s \D \W \S \n \r \t \xHH \u{H..} and escaped punctuation,
+ *   groups ( ) and (?: ), alternation |,
+ *   quantifiers * + ? {n} {n,} {n,m}
+ *
+ * A pattern is compiled to a small instruction list that is executed
+ * as a NFA simulation (all threads advance one char at a time), so
+ * matching time is linear in the length of the text.
+ */
+
+use std::fmt;
+use std::collections::VecDeque;
+
+// the largest count accepted in {n,m}
+const MAX_REPEAT : u32 = 1000;
+
+// highest unicode scalar value, used for negated classes
+const MAX_CHAR : u32 = 0x10FFFF;
+
+#[derive(Debug, Clone)]
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    // sorted, non overlapping ranges
+    Class(Vec<(u32, u32)>),
+    Seq(Vec<Node>),
+    Alt(Vec<Node>),
+    // node, min, max (None = unbounded)
+    Repeat(Box<Node>, u32, Option<u32>),
+}
+
+#[derive(Debug, Clone)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Vec<(u32, u32)>),
+    Split(usize, usize),
+    Jmp(usize),
+    Match,
+}
+
+#[derive(Clone)]
+pub struct Regex {
+    source : String,
+    prog : Vec<Inst>,
+}
+
+impl fmt::Debug for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Regex({})", self.source)
+    }
+}
+
+/**
+ * Sort ranges and merge the overlapping or adjacent ones
+ */
+fn normalize(mut ranges : Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    ranges.sort();
+    let mut out : Vec<(u32, u32)> = Vec::new();
+    for (lo, hi) in ranges {
+        if let Some(last) = out.last_mut() {
+            if lo <= last.1 + 1 {
+                if hi > last.1 {
+                    last.1 = hi;
+                }
+                continue;
+            }
+        }
+        out.push((lo, hi));
+    }
+    out
+}
+
+/**
+ * All chars not in ranges (ranges must be normalized)
+ */
+fn complement(ranges : &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut out = Vec::new();
+    let mut next = 0;
+    for &(lo, hi) in ranges {
+        if lo > next {
+            out.push((next, lo - 1));
+        }
+        next = hi + 1;
+    }
+    if next <= MAX_CHAR {
+        out.push((next, MAX_CHAR));
+    }
+    out
+}
+
+/**
+ * A char of the ranges, the first one above ' ' if there is one
+ */
+fn example_char(ranges : &[(u32, u32)]) -> Option<char> {
+    let printable = ranges.iter()
+        .filter_map(|&(lo, hi)| (lo.max(0x21)..hi + 1).find_map(::std::char::from_u32))
+        .next();
+    printable.or_else(|| ranges.iter().filter_map(|&(lo, hi)| (lo..hi + 1).find_map(::std::char::from_u32)).next())
+}
+
+struct Parser {
+    chars : Vec<char>,
+    i : usize,
+}
+
+impl Parser {
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.i).cloned()
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        match self.peek() {
+            Some(c) => {
+                self.i += 1;
+                Ok(c)
+            }
+            None => Err("unexpected end of pattern".to_string()),
+        }
+    }
+
+    // alt : seq ('|' seq)*
+    fn parse_alt(&mut self) -> Result<Node, String> {
+        let mut alts = vec![self.parse_seq()?];
+        while self.peek() == Some('|') {
+            self.i += 1;
+            alts.push(self.parse_seq()?);
+        }
+        if alts.len() == 1 {
+            Ok(alts.pop().unwrap())
+        } else {
+            Ok(Node::Alt(alts))
+        }
+    }
+
+    // seq : repeat*
+    fn parse_seq(&mut self) -> Result<Node, String> {
+        let mut items = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some('|') | Some(')') => break,
+                _ => items.push(self.parse_repeat()?),
+            }
+        }
+        match items.len() {
+            0 => Ok(Node::Empty),
+            1 => Ok(items.pop().unwrap()),
+            _ => Ok(Node::Seq(items)),
+        }
+    }
+
+    // repeat : atom ('*' | '+' | '?' | '{' n (',' m?)? '}')*
+    fn parse_repeat(&mut self) -> Result<Node, String> {
+        let mut node = self.parse_atom()?;
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.i += 1;
+                    let count = self.parse_count()?;
+                    node = Node::Repeat(Box::new(node), count.0, count.1);
+                    continue;
+                }
+                _ => break,
+            };
+            self.i += 1;
+            node = Node::Repeat(Box::new(node), min, max);
+        }
+        Ok(node)
+    }
+
+    // parse 'n}', 'n,}' or 'n,m}' after '{'
+    fn parse_count(&mut self) -> Result<(u32, Option<u32>), String> {
+        let min = self.parse_number()?;
+        let max = if self.peek() == Some(',') {
+            self.i += 1;
+            if self.peek() == Some('}') {
+                None
+            } else {
+                Some(self.parse_number()?)
+            }
+        } else {
+            Some(min)
+        };
+        if self.next()? != '}' {
+            return Err("expected '}' to end the repetition count".to_string());
+        }
+        if let Some(m) = max {
+            if m < min {
+                return Err(format!("invalid repetition count {{{},{}}}", min, m));
+            }
+        }
+        Ok((min, max))
+    }
+
+    fn parse_number(&mut self) -> Result<u32, String> {
+        let mut n : u32 = 0;
+        let start = self.i;
+        while let Some(c) = self.peek() {
+            match c.to_digit(10) {
+                Some(d) => {
+                    n = n * 10 + d;
+                    if n > MAX_REPEAT {
+                        return Err(format!("repetition count larger than {}", MAX_REPEAT));
+                    }
+                    self.i += 1;
+                }
+                None => break,
+            }
+        }
+        if self.i == start {
+            return Err("expected a repetition count".to_string());
+        }
+        Ok(n)
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, String> {
+        match self.next()? {
+            '(' => {
+                // non capturing group, groups do not capture anyway
+                if self.peek() == Some('?') {
+                    self.i += 1;
+                    if self.next()? != ':' {
+                        return Err("expected ':' after '(?'".to_string());
+                    }
+                }
+                let node = self.parse_alt()?;
+                if self.peek() != Some(')') {
+                    return Err("missing ')'".to_string());
+                }
+                self.i += 1;
+                Ok(node)
+            }
+            '[' => self.parse_class(),
+            '.' => Ok(Node::Any),
+            '\\' => {
+                match self.parse_escape()? {
+                    Ok(c) => Ok(Node::Char(c)),
+                    Err(ranges) => Ok(Node::Class(ranges)),
+                }
+            }
+            c @ '*' | c @ '+' | c @ '?' | c @ '{' => {
+                Err(format!("nothing to repeat before '{}'", c))
+            }
+            ')' => Err("unmatched ')'".to_string()),
+            c => Ok(Node::Char(c)),
+        }
+    }
+
+    // after '\': Ok(char) for a single char, Err(ranges) for a class escape
+    fn parse_escape(&mut self) -> Result<Result<char, Vec<(u32, u32)>>, String> {
+        let digit = vec![('0' as u32, '9' as u32)];
+        let word = normalize(vec![('0' as u32, '9' as u32), ('A' as u32, 'Z' as u32),
+                                  ('a' as u32, 'z' as u32), ('_' as u32, '_' as u32)]);
+        let space = normalize(vec![(' ' as u32, ' ' as u32), (0x09, 0x0D)]);
+        let c = self.next()?;
+        let r = match c {
+            'd' => Err(digit),
+            'w' => Err(word),
+            's' => Err(space),
+            'D' => Err(complement(&digit)),
+            'W' => Err(complement(&word)),
+            'S' => Err(complement(&space)),
+            'n' => Ok('\n'),
+            'r' => Ok('\r'),
+            't' => Ok('\t'),
+            'x' => {
+                let mut v = 0;
+                for _ in 0..2 {
+                    match self.next()?.to_digit(16) {
+                        Some(d) => v = v * 16 + d,
+                        None => return Err("expected two hex digits after \\x".to_string()),
+                    }
+                }
+                Ok(::std::char::from_u32(v).unwrap())
+            }
+            'u' => {
+                if self.next()? != '{' {
+                    return Err("expected '{' after \\u".to_string());
+                }
+                let mut v : u32 = 0;
+                let mut digits = 0;
+                loop {
+                    let h = self.next()?;
+                    if h == '}' {
+                        break;
+                    }
+                    match h.to_digit(16) {
+                        Some(d) if digits < 6 => {
+                            v = v * 16 + d;
+                            digits += 1;
+                        }
+                        _ => return Err("invalid \\u{...} escape".to_string()),
+                    }
+                }
+                match ::std::char::from_u32(v) {
+                    Some(ch) if digits > 0 => Ok(ch),
+                    _ => return Err("invalid \\u{...} escape".to_string()),
+                }
+            }
+            c if c.is_alphanumeric() => {
+                return Err(format!("unknown escape \\{}", c));
+            }
+            c => Ok(c),
+        };
+        Ok(r)
+    }
+
+    // after '[': items up to ']'
+    fn parse_class(&mut self) -> Result<Node, String> {
+        let mut negated = false;
+        if self.peek() == Some('^') {
+            self.i += 1;
+            negated = true;
+        }
+        let mut ranges : Vec<(u32, u32)> = Vec::new();
+        let mut first = true;
+        loop {
+            let c = match self.next() {
+                Ok(c) => c,
+                Err(_) => return Err("missing ']'".to_string()),
+            };
+            // ']' as the first char is a literal
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = if c == '\\' {
+                match self.parse_escape()? {
+                    Ok(ch) => ch,
+                    Err(rs) => {
+                        ranges.extend(rs);
+                        continue;
+                    }
+                }
+            } else {
+                c
+            };
+            // range a-z, a '-' before ']' is a literal
+            if self.peek() == Some('-') && self.chars.get(self.i + 1) != Some(&']')
+                && self.chars.get(self.i + 1).is_some() {
+                self.i += 1;
+                let hc = self.next()?;
+                let hi = if hc == '\\' {
+                    match self.parse_escape()? {
+                        Ok(ch) => ch,
+                        Err(_) => return Err("class escape can not end a range".to_string()),
+                    }
+                } else {
+                    hc
+                };
+                if hi < lo {
+                    return Err(format!("invalid range {}-{}", lo, hi));
+                }
+                ranges.push((lo as u32, hi as u32));
+            } else {
+                ranges.push((lo as u32, lo as u32));
+            }
+        }
+        let ranges = normalize(ranges);
+        if negated {
+            Ok(Node::Class(complement(&ranges)))
+        } else {
+            Ok(Node::Class(ranges))
+        }
+    }
+}
+
+struct Compiler {
+    prog : Vec<Inst>,
+}
+
+impl Compiler {
+
+    fn emit(&mut self, inst : Inst) -> usize {
+        self.prog.push(inst);
+        self.prog.len() - 1
+    }
+
+    fn compile(&mut self, node : &Node) {
+        match *node {
+            Node::Empty => {}
+            Node::Char(c) => { self.emit(Inst::Char(c)); }
+            Node::Any => { self.emit(Inst::Any); }
+            Node::Class(ref ranges) => { self.emit(Inst::Class(ranges.clone())); }
+            Node::Seq(ref items) => {
+                for item in items {
+                    self.compile(item);
+                }
+            }
+            Node::Alt(ref alts) => {
+                // split L1, next; L1: alt0; jmp end; next: split L2, ...
+                let mut jumps = Vec::new();
+                for (i, alt) in alts.iter().enumerate() {
+                    if i + 1 < alts.len() {
+                        let split = self.emit(Inst::Split(0, 0));
+                        self.compile(alt);
+                        jumps.push(self.emit(Inst::Jmp(0)));
+                        let next = self.prog.len();
+                        self.prog[split] = Inst::Split(split + 1, next);
+                    } else {
+                        self.compile(alt);
+                    }
+                }
+                let end = self.prog.len();
+                for j in jumps {
+                    self.prog[j] = Inst::Jmp(end);
+                }
+            }
+            Node::Repeat(ref n, min, max) => {
+                for _ in 0..min {
+                    self.compile(n);
+                }
+                match max {
+                    None => {
+                        // L: split body, end; body; jmp L
+                        let split = self.emit(Inst::Split(0, 0));
+                        self.compile(n);
+                        self.emit(Inst::Jmp(split));
+                        let end = self.prog.len();
+                        self.prog[split] = Inst::Split(split + 1, end);
+                    }
+                    Some(max) => {
+                        // nested optionals: (n(n(n)?)?)?
+                        let mut splits = Vec::new();
+                        for _ in min..max {
+                            splits.push(self.emit(Inst::Split(0, 0)));
+                            self.compile(n);
+                        }
+                        let end = self.prog.len();
+                        for s in splits {
+                            self.prog[s] = Inst::Split(s + 1, end);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Regex {
+
+    /**
+     * Compile a pattern, returns a description of the problem on error
+     */
+    pub fn new(pattern : &str) -> Result<Regex, String> {
+        let mut p = Parser { chars : pattern.chars().collect(), i : 0 };
+        let node = p.parse_alt()?;
+        if p.i < p.chars.len() {
+            // parse_alt only stops early at an unmatched ')'
+            return Err("unmatched ')'".to_string());
+        }
+        let mut c = Compiler { prog : Vec::new() };
+        c.compile(&node);
+        c.emit(Inst::Match);
+        Ok(Regex { source : pattern.to_string(), prog : c.prog })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    // add pc and everything reachable through jumps to the list
+    fn add_thread(&self, list : &mut Vec<usize>, seen : &mut [usize], gen : usize, pc : usize) {
+        let mut stack = vec![pc];
+        while let Some(pc) = stack.pop() {
+            if seen[pc] == gen {
+                continue;
+            }
+            seen[pc] = gen;
+            match self.prog[pc] {
+                Inst::Jmp(a) => stack.push(a),
+                Inst::Split(a, b) => {
+                    // push b first to visit a first
+                    stack.push(b);
+                    stack.push(a);
+                }
+                _ => list.push(pc),
+            }
+        }
+    }
+
+    /**
+     * Length in bytes of the longest prefix of text matched by the pattern
+     */
+    pub fn match_prefix(&self, text : &str) -> Option<usize> {
+        let mut seen = vec![0; self.prog.len()];
+        let mut gen = 1;
+        let mut clist : Vec<usize> = Vec::new();
+        let mut nlist : Vec<usize> = Vec::new();
+        self.add_thread(&mut clist, &mut seen, gen, 0);
+
+        let mut longest = None;
+        let mut chars = text.char_indices();
+        loop {
+            if clist.iter().any(|&pc| matches!(self.prog[pc], Inst::Match)) {
+                longest = Some(match chars.clone().next() {
+                    Some((i, _)) => i,
+                    None => text.len(),
+                });
+            }
+            let ch = match chars.next() {
+                Some((_, ch)) => ch,
+                None => break,
+            };
+            if clist.is_empty() {
+                break;
+            }
+            gen += 1;
+            nlist.clear();
+            for &pc in &clist {
+                let ok = match self.prog[pc] {
+                    Inst::Char(c) => c == ch,
+                    Inst::Any => ch != '\n',
+                    Inst::Class(ref ranges) => {
+                        let v = ch as u32;
+                        ranges.iter().any(|&(lo, hi)| lo <= v && v <= hi)
+                    }
+                    _ => false,
+                };
+                if ok {
+                    self.add_thread(&mut nlist, &mut seen, gen, pc + 1);
+                }
+            }
+            ::std::mem::swap(&mut clist, &mut nlist);
+        }
+        longest
+    }
+
+    /**
+     * A shortest text the pattern matches, None if it matches nothing
+     * ('.' and classes give their first printable char)
+     */
+    pub fn example(&self) -> Option<String> {
+        // breadth first search over the instructions, jumps cost
+        // nothing and chars one (0-1 BFS)
+        let mut dist = vec![usize::MAX; self.prog.len()];
+        let mut done = vec![false; self.prog.len()];
+        let mut from : Vec<Option<(usize, Option<char>)>> = vec![None; self.prog.len()];
+        let mut queue = VecDeque::new();
+        dist[0] = 0;
+        queue.push_back(0);
+        while let Some(pc) = queue.pop_front() {
+            if done[pc] {
+                continue;
+            }
+            done[pc] = true;
+            let edges = match self.prog[pc] {
+                Inst::Char(c) => vec![(pc + 1, Some(c))],
+                Inst::Any => vec![(pc + 1, Some('a'))],
+                Inst::Class(ref ranges) => example_char(ranges).map(|c| (pc + 1, Some(c))).into_iter().collect(),
+                Inst::Split(a, b) => vec![(a, None), (b, None)],
+                Inst::Jmp(a) => vec![(a, None)],
+                Inst::Match => {
+                    let mut text = Vec::new();
+                    let mut at = pc;
+                    while let Some((prev, ch)) = from[at] {
+                        text.extend(ch);
+                        at = prev;
+                    }
+                    return Some(text.into_iter().rev().collect());
+                }
+            };
+            for (next, ch) in edges {
+                let d = dist[pc] + if ch.is_some() { 1 } else { 0 };
+                if d < dist[next] {
+                    dist[next] = d;
+                    from[next] = Some((pc, ch));
+                    if ch.is_some() {
+                        queue.push_back(next);
+                    } else {
+                        queue.push_front(next);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /**
+     * True if the pattern matches the whole text
+     */
+    pub fn is_match(&self, text : &str) -> bool {
+        self.match_prefix(text) == Some(text.len())
+    }
+}
@@ -1,13 +1,31 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
 
     extern crate core;
 
-    use std::collections::VecDeque;
-    use tokenize::{Tokenizer,Token};
-    use compiler::{compile_grammar};
+    use std::cell::Cell;
+    use std::collections::{HashSet, VecDeque};
+    use std::rc::Rc;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+    use tokenize::{Tokenizer, Token, TokenKind, TokenizeError, LexerConfig, tokenize, unescape};
+    use compiler::{compile, compile_grammar, compile_grammar_file, compile_with_matchers};
+    use builder::rule;
+    use abnf::load_abnf_str;
+    use yacc::load_yacc_str;
+    use ebnf::load_ebnf_str;
+    use grammar::{load_grammar_str, load_grammar_file, Assoc, Production, Component, RuleId};
+    use analysis::{Diagnostic, Lookahead};
+    use diff::{sample_differences, SampleDifference};
+    use regex::Regex;
+    use normalize::{fold_case, nfc};
     use htmltokenize::{tokenize_html,HTMLToken};
-    use vm::{run, StreamingHandler};
+    use matchers::Matchers;
+    use vm::{run, run_input, run_entry, parse_str, StreamingHandler, RunError};
 
     struct ParsedData {
         counter: usize,
@@ -32,8 +50,8 @@ mod tests {
         fn inc_term(&mut self) {
             self.term_counter += 1;
         }
-        fn push(&mut self, name: &String) {
-            self.nt_stack.push(name.clone());
+        fn push(&mut self, name: &str) {
+            self.nt_stack.push(name.to_string());
         }
         fn pop(&mut self) -> Option<String> {
             self.nt_stack.pop()
@@ -48,29 +66,58 @@ mod tests {
     }
 
     impl StreamingHandler for ParsedData {
-        fn start(&mut self, ntname: &String, name: &Option<&String>) {
+        fn start(&mut self, ntname: &String, _name: &Option<&String>) {
             self.inc();
             self.push(ntname);
 //            println!("--- start {} {:?} [{}]", ntname, name, self.count());
         }
-        fn end(&mut self, ntname: &String, xname: &Option<&String>) {
+        fn end(&mut self, ntname: &String, _xname: &Option<&String>) {
             match self.pop() {
                 Some(ref s) => {
                     assert_eq!(s, ntname);
                 },
                 None => {
-                    assert!(false);
+                    panic!("end of {} without start", ntname);
                 }
             };
             self.dec();
 //            println!("--- end {} {:?} [{}]", ntname, xname, self.count());
         }
-        fn term(&mut self, tokidx: usize, name: &Option<&String>) {
+        fn term(&mut self, _tokidx: usize, _name: &Option<&String>) {
             self.inc_term();
 //            println!("--- term = {} {:?}", tokidx, name);
         }
     }
 
+    // records start/end events as "+name" and "-name"
+    struct EventLog {
+        events: Vec<String>,
+    }
+
+    impl StreamingHandler for EventLog {
+        fn start(&mut self, ntname: &String, _name: &Option<&String>) {
+            self.events.push(format!("+{}", ntname));
+        }
+        fn end(&mut self, ntname: &String, _xname: &Option<&String>) {
+            self.events.push(format!("-{}", ntname));
+        }
+        fn term(&mut self, tokidx: usize, _name: &Option<&String>) {
+            self.events.push(format!("{}", tokidx));
+        }
+    }
+
+    // write files into a fresh directory under the temp dir
+    fn write_files(dir: &str, files: &[(&str, &str)]) -> PathBuf {
+        let base = env::temp_dir().join(format!("rparse-{}-{}", dir, ::std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        for &(name, content) in files {
+            let path = base.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(&path).unwrap().write_all(content.as_bytes()).unwrap();
+        }
+        base
+    }
+
     #[test]
     fn tokenizer_works() {
         let mut tokens : VecDeque<Token> = VecDeque::new();
@@ -83,7 +130,7 @@ mod tests {
             for ch in input_str.chars() {
                 t.push(ch);
             }
-            t.finish();
+            t.finish().unwrap();
         }
 
         // check first token
@@ -145,21 +192,22 @@ mod tests {
                               'sunny'(sunnyname) 'world'(worldname) `wtyperule`;
                   OTHERTYPE : 'other'(othername) 'another'(anothername) `otherrule`;
                   START : 'begin'(beginname) WORLDTYPE(wtypent) OTHERTYPE 'end'(endname) `startrule`;";
-        let c = compile_grammar(gs);
+        let c = compile_grammar(gs).unwrap();
 //        c.display();
 
-        let mut tokens = Vec::<String>::new();
-        tokens.push("begin".to_string());
-        tokens.push("sunny".to_string());
-        tokens.push("world".to_string());
-        tokens.push("other".to_string());
-        tokens.push("another".to_string());
-        tokens.push("end".to_string());
+        let tokens = [
+            "begin".to_string(),
+            "sunny".to_string(),
+            "world".to_string(),
+            "other".to_string(),
+            "another".to_string(),
+            "end".to_string(),
+        ];
 
         // "Y" - START grammar rule
         // &c - grammar to use
         // 3rd arg: match function
-        let parsed_trees = run("START", &c, |s, i| { tokens[i] == s }, tokens.len());
+        let parsed_trees = run("START", &c, |s, i| { tokens[i] == s }, tokens.len()).unwrap();
 
         assert_eq!(parsed_trees.count(), 1);
 
@@ -177,16 +225,17 @@ mod tests {
           E : ;
           Z : A E A A A;
         "#;
-        let c = compile_grammar(gs);
+        let c = compile_grammar(gs).unwrap();
         // c.display();
 
-        let mut tokens = Vec::<String>::new();
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
+        let tokens = [
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+        ];
 
-        let pt = run("Z", &c, |s, i| { i < tokens.len() && tokens[i] == s }, 0);
+        let pt = run("Z", &c, |s, i| { i < tokens.len() && tokens[i] == s }, 0).unwrap();
 
         assert_eq!(pt.count(), 1);
     }
@@ -198,16 +247,17 @@ mod tests {
           E : 'e' | ;
           X : A E A A A;
         "#;
-        let c = compile_grammar(gs);
+        let c = compile_grammar(gs).unwrap();
         // c.display();
 
-        let mut tokens = Vec::<String>::new();
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
+        let tokens = [
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+        ];
 
-        let pt = run("X", &c, |s, i| { i < tokens.len() && tokens[i] == s }, 0);
+        let pt = run("X", &c, |s, i| { i < tokens.len() && tokens[i] == s }, 0).unwrap();
 
         assert_eq!(pt.count(), 1);
     }
@@ -220,17 +270,18 @@ mod tests {
             | 'b'
             ;
         "#;
-        let c = compile_grammar(gs);
+        let c = compile_grammar(gs).unwrap();
         // c.display();
 
-        let mut tokens = Vec::<String>::new();
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("b".to_string());
+        let tokens = [
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+            "b".to_string(),
+        ];
 
-        let pt = run("R", &c, |s, i| { tokens[i] == s }, 0);
+        let pt = run("R", &c, |s, i| { tokens[i] == s }, 0).unwrap();
 
         assert_eq!(pt.count(), 1);
     }
@@ -243,28 +294,64 @@ mod tests {
             |
             ;
         "#;
-        let c = compile_grammar(gs);
+        let c = compile_grammar(gs).unwrap();
         c.display();
 
-        let mut tokens = Vec::<String>::new();
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("a".to_string());
-        tokens.push("w".to_string());
-
-        let pt = run("Q", &c, |s, i| { i < tokens.len() && tokens[i] == s }, 0);
+        let tokens = [
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+            "a".to_string(),
+            "w".to_string(),
+        ];
+
+        let pt = run("Q", &c, |s, i| { i < tokens.len() && tokens[i] == s }, 0).unwrap();
 
         assert_eq!(pt.count_at_n(tokens.len() - 1), 1);
     }
 
+    #[test]
+    fn left_rec_closure_test() {
+        let c = compile_grammar("E : E '+' E | 'n' ;").unwrap();
+        let tokens = ["n", "+", "n", "+", "n"];
+
+        let pt = run("E", &c, |s, i| { i < tokens.len() && tokens[i] == s }, tokens.len()).unwrap();
+        assert_eq!(pt.count_at_n(tokens.len() - 1), 2);
+
+        // min_match only filters the results
+        let pt = run("E", &c, |s, i| { i < tokens.len() && tokens[i] == s }, 0).unwrap();
+        assert_eq!(pt.count_at_n(tokens.len() - 1), 2);
+        assert_eq!(pt.count(), 4);
+
+        let c = compile_grammar("E : E '+' 'n' | 'n' ;").unwrap();
+        let pt = run("E", &c, |s, i| { i < tokens.len() && tokens[i] == s }, 0).unwrap();
+        assert_eq!(pt.count_at_n(4), 1);
+    }
+
+    #[test]
+    fn nullable_cycle_test() {
+        let gs = r#"
+          S : ( 'a' 'b' | T )+ '%' ;
+          T : T (t) T* | T* '%' "'" | ;
+        "#;
+        let c = compile_grammar(gs).unwrap();
+
+        let tokens = ["a", "b", "1"];
+        let pt = run_input("S", &c, &tokens[..], tokens.len()).unwrap();
+        assert_eq!(pt.count(), 0);
+
+        let tokens = ["a", "b", "%"];
+        let pt = run_input("S", &c, &tokens[..], tokens.len()).unwrap();
+        assert!(pt.count_at_n(2) > 0);
+    }
+
     #[test]
     fn html_token_test() {
         let t0 = HTMLToken::parse("<body class=\"no-js\">");
@@ -318,9 +405,1190 @@ mod tests {
     fn html_parse_test() {
         let html_tokens = tokenize_html("<html lang=\"en\"><head><TITLE>hello</TITLE></head><body></body></html>");
         let gs = "S : X; X : '<html>' '<head>' '<title>' 'hello' '</title>' '</head>' '<body>' '</body>' '</html>';";
-        let cg = compile_grammar(gs);
-        let pt = run("S", &cg, |s, i| { html_tokens[i].value == s }, 0);
+        let cg = compile_grammar(gs).unwrap();
+        let pt = run("S", &cg, |s, i| { html_tokens[i].value == s }, 0).unwrap();
+        assert_eq!(pt.count(), 1);
+    }
+
+    #[test]
+    fn grammar_error_test() {
+        let gs = "A : 'a' `ev ;\nB 'b';\nC : 'c';\nD : 'd' `x";
+        let errors = load_grammar_str(gs).unwrap_err();
+        assert_eq!(errors.len(), 3);
+
+        // missing closing backtick
+        assert_eq!(errors[0].pos.line, 1);
+        assert_eq!(errors[0].pos.col, 12);
+        assert_eq!(errors[0].expected, "'`' to end the event name");
+        assert_eq!(errors[0].found, "';'");
+
+        // missing ':' after the nonterminal
+        assert_eq!(errors[1].pos.line, 2);
+        assert_eq!(errors[1].pos.col, 2);
+        assert_eq!(errors[1].found, "'b'");
+
+        // unterminated event name at the end of input
+        assert_eq!(errors[2].pos.line, 4);
+        assert_eq!(errors[2].expected, "'`' to end the event name");
+        assert_eq!(errors[2].found, "end of input");
+
+        assert!(compile_grammar("A : 'a' ; ( ;").is_err());
+    }
+
+    #[test]
+    fn ebnf_grammar_test() {
+        let gs = r#"
+          S : 'a'? ( 'b' | 'c' D )* 'd'+ `s` ;
+          D : 'x' ;
+        "#;
+        let c = compile_grammar(gs).unwrap();
+
+        let tokens = ["b", "c", "x", "d", "d"];
+        let pt = run("S", &c, |s, i| { i < tokens.len() && tokens[i] == s }, tokens.len()).unwrap();
+        assert_eq!(pt.count(), 1);
+
+        // generated nonterminals do not show up in the events
+        let mut log = EventLog { events: Vec::new() };
+        pt.execute(0, &mut log);
+        assert_eq!(log.events, vec!["+S", "0", "1", "+D", "2", "-D", "3", "4", "-S"]);
+
+        let tokens = ["a", "d"];
+        let pt = run("S", &c, |s, i| { i < tokens.len() && tokens[i] == s }, tokens.len()).unwrap();
+        assert_eq!(pt.count(), 1);
+
+        let tokens = ["a", "b"];
+        let pt = run("S", &c, |s, i| { i < tokens.len() && tokens[i] == s }, tokens.len()).unwrap();
+        assert_eq!(pt.count(), 0);
+
+        // component names after operators, named groups
+        let g = load_grammar_str("S : 'a'*(as) ('b' 'c')(bc) X (y)+ ; X : 'x' ; y : 'y' ;").unwrap();
+        let prods = g.iter_over_nonterm("S");
+        assert_eq!(prods[0].components.len(), 4);
+        assert_eq!(prods[0].components[0].name, Some("as".to_string()));
+        assert_eq!(prods[0].components[1].name, Some("bc".to_string()));
+        assert_eq!(prods[0].components[2].name, None);
+
+        assert!(load_grammar_str("S : ( 'a' | 'b' ;").is_err());
+    }
+
+    #[test]
+    fn regex_test() {
+        let r = Regex::new("[a-zA-Z_][a-zA-Z_0-9]*").unwrap();
+        assert!(r.is_match("_foo1"));
+        assert!(!r.is_match("1foo"));
+        assert!(!r.is_match(""));
+
+        let r = Regex::new("-?\\d+(\\.\\d+)?|0x[0-9a-f]{1,4}").unwrap();
+        assert!(r.is_match("-12.5"));
+        assert!(r.is_match("0xbeef"));
+        assert!(!r.is_match("0xbeef0"));
+        assert!(!r.is_match("12."));
+        assert_eq!(r.match_prefix("42.0.1"), Some(4));
+        assert_eq!(r.match_prefix("x"), None);
+
+        let r = Regex::new("[^\\s,]+").unwrap();
+        assert!(r.is_match("caf\u{e9}"));
+        assert!(!r.is_match("a b"));
+
+        // shortest texts for sampling
+        for pattern in &["[a-zA-Z_][a-zA-Z_0-9]*", "-?\\d+(\\.\\d+)?|0x[0-9a-f]{1,4}", "[^\\s,]+", "(ab|c)+d?", "x*"] {
+            let r = Regex::new(pattern).unwrap();
+            let example = r.example().unwrap();
+            assert!(r.is_match(&example), "{} {}", pattern, example);
+        }
+        assert_eq!(Regex::new("-?\\d+(\\.\\d+)?").unwrap().example(), Some("0".to_string()));
+        assert_eq!(Regex::new("(ab|c)+d?").unwrap().example(), Some("c".to_string()));
+        assert_eq!(Regex::new("[^\\x00-\\u{10FFFF}]").unwrap().example(), None);
+
+        assert!(Regex::new("(ab").is_err());
+        assert!(Regex::new("*a").is_err());
+        assert!(Regex::new("[a-").is_err());
+        assert!(Regex::new("a{3,1}").is_err());
+    }
+
+    #[test]
+    fn regex_terminal_test() {
+        let gs = r#"
+          Sum : Num ( '+' Num )* ;
+          Num : /[0-9]+/ | /0x[0-9a-f]+/ | [a-z] ;
+        "#;
+        let c = compile_grammar(gs).unwrap();
+
+        let tokens = ["12", "+", "0x1f", "+", "x"];
+        let pt = run_input("Sum", &c, &tokens[..], tokens.len()).unwrap();
+        assert_eq!(pt.count(), 1);
+
+        let tokens = ["12", "+", "xy"];
+        let pt = run_input("Sum", &c, &tokens[..], tokens.len()).unwrap();
+        assert_eq!(pt.count(), 0);
+
+        // a closure has no token text for the regular expressions
+        assert!(c.needs_token_text());
+        let err = run("Sum", &c, |v, i| i < tokens.len() && tokens[i] == v, tokens.len()).err();
+        assert_eq!(err, Some(RunError::NeedsTokenText));
+        assert!(!compile_grammar("S : 'a' ;").unwrap().needs_token_text());
+
+        // a slash inside a regex terminal must be escaped
+        let g = load_grammar_str("S : /a\\/b/ ;").unwrap();
+        assert_eq!(format!("{:?}", g.iter_over_nonterm("S")[0].components[0].rule),
+                   "Regex(\"a\\\\/b\")");
+
+        let errors = load_grammar_str("S : 'a' /[0-9/ ;").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].expected, "regular expression");
+    }
+
+    #[test]
+    fn include_test() {
+        let base = write_files("include", &[
+            ("main.g", "%include \"lib/common.g\"\n%include \"lib/html.g\" as html\nS : Item html::Tag ;\n"),
+            ("lib/common.g", "Item : 'i' ;\n"),
+            ("lib/html.g", "%include \"common.g\"\nTag : '<' Name '>' Item ;\nName : 'b' ;\n"),
+            ("a.g", "%include \"b.g\"\nA : 'a' ;\n"),
+            ("b.g", "B : 'b' ;\n%include \"a.g\"\n"),
+            ("c.g", "%include \"d.g\"\nC : D ;\n"),
+            ("d.g", "D : 'd'\n  | <WORD> ;\n"),
+        ]);
+
+        let g = load_grammar_file(base.join("main.g")).unwrap();
+        let mut nts = g.nonterminals();
+        nts.sort();
+        assert_eq!(nts, vec!["Item", "S", "html::Item", "html::Name", "html::Tag"]);
+
+        let c = compile_grammar_file(base.join("main.g")).unwrap();
+        let tokens = ["i", "<", "b", ">", "i"];
+        let pt = run_input("S", &c, &tokens[..], tokens.len()).unwrap();
+        assert_eq!(pt.count(), 1);
+
+        // included rules print as qualified definitions that load back
+        let printed = g.to_string();
+        assert!(printed.contains("html::Tag\n    : '<' html::Name '>' html::Item\n"));
+        let g2 = load_grammar_str(&printed).unwrap();
+        assert_eq!(g2.to_string(), printed);
+        let c = compile(&g2).unwrap();
+        assert_eq!(run_input("S", &c, &tokens[..], tokens.len()).unwrap().count(), 1);
+
+        // unknown token classes are reported where they are used
+        let errors = compile_grammar_file(base.join("c.g")).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].file.as_ref().unwrap().ends_with("d.g"));
+        assert_eq!((errors[0].pos.line, errors[0].pos.col), (2, 4));
+        assert_eq!(errors[0].nonterm, Some("D".to_string()));
+
+        // a.g -> b.g -> a.g
+        let errors = load_grammar_file(base.join("a.g")).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].file.as_ref().unwrap().ends_with("b.g"));
+        assert_eq!(errors[0].pos.line, 2);
+        assert_eq!(errors[0].expected, "non-cyclic include");
+
+        let errors = load_grammar_file(base.join("missing.g")).unwrap_err();
+        assert_eq!(errors[0].expected, "readable grammar file");
+
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn comments_test() {
+        let gs = r#"
+          # the start rule
+          # spans two lines
+          S : A /* inline */ A   // trailing
+            ;
+          /**
+           * A is 'a' or 'b'
+           */
+          A
+            // first
+            : 'a'
+            # second
+            | 'b' '#' '//'
+            ;
+        "#;
+        let g = load_grammar_str(gs).unwrap();
+        assert_eq!(g.doc("S"), Some("the start rule\nspans two lines"));
+        assert_eq!(g.doc("A"), Some("A is 'a' or 'b'"));
+
+        let prods = g.iter_over_nonterm("A");
+        assert_eq!(prods[0].doc, Some("first".to_string()));
+        assert_eq!(prods[1].doc, Some("second".to_string()));
+        assert_eq!(prods[1].components.len(), 3);
+        assert_eq!(g.iter_over_nonterm("S")[0].components.len(), 2);
+
+        // comments trailing a rule or alternative are not docs
+        let g = load_grammar_str("T : 'x' ; // end of T\nU : 'y' ;\nV : 'a' // after a\n  | 'b' ;").unwrap();
+        assert_eq!(g.doc("U"), None);
+        assert_eq!(g.iter_over_nonterm("V")[1].doc, None);
+        assert!(!g.to_string().contains("after a"));
+
+        let mut tokens : Vec<Token> = Vec::new();
+        let input = "a#x\nb/*/ */c//";
+        let comments;
+        {
+            let mut t = Tokenizer::with_config(LexerConfig::grammar(), |t| { tokens.push(t); });
+            for ch in input.chars() {
+                t.push(ch);
+            }
+            t.finish().unwrap();
+            comments = t.comments().clone();
+        }
+        let values : Vec<&str> = tokens.iter().map(|t| &input[t.beg.pos..t.end.pos]).collect();
+        assert_eq!(values, vec!["a", "b", "c"]);
+        let values : Vec<&str> = comments.iter().map(|t| &input[t.beg.pos..t.end.pos]).collect();
+        assert_eq!(values, vec!["#x", "/*/ */", "//"]);
+    }
+
+    #[test]
+    fn template_test() {
+        let gs = r#"
+          List<X, Sep> : X | X Sep List<X, Sep> ;
+          Pair<A> : '(' A ',' A ')' ;
+          S : List<Item, ','> ';' List<Pair<Item>, '|'> ;
+          Item : /[a-z]+/ ;
+        "#;
+        let g = load_grammar_str(gs).unwrap();
+        let mut nts = g.nonterminals();
+        nts.sort();
+        assert_eq!(nts, vec!["Item", "List<Item, ','>", "List<Pair<Item>, '|'>", "Pair<Item>", "S"]);
+
+        let c = compile_grammar(gs).unwrap();
+        let tokens = ["a", ",", "b", ";", "(", "a", ",", "b", ")", "|", "(", "c", ",", "d", ")"];
+        let pt = run_input("S", &c, &tokens[..], tokens.len()).unwrap();
+        assert_eq!(pt.count(), 1);
+
+        let mut log = EventLog { events: Vec::new() };
+        pt.execute(0, &mut log);
+        assert_eq!(&log.events[..4], &["+S", "+List<Item, ','>", "+Item", "0"]);
+        assert!(log.events.contains(&"+Pair<Item>".to_string()));
+
+        let errors = load_grammar_str("L<X> : X ; S : L<'a', 'b'> M<'c'> ;").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].expected, "1 template arguments");
+        assert_eq!(errors[0].pos.col, 15);
+        assert_eq!(errors[1].expected, "template");
+        assert_eq!(errors[1].found, "'M'");
+
+        // endless expansion is reported
+        let errors = load_grammar_str("T<X> : X | T<(X X)> ; S : T<'a'> ;").unwrap_err();
+        assert_eq!(errors[0].expected, "at most 1000 template instances");
+
+        // errors in a body are reported once, even if the template is unused
+        let errors = load_grammar_str("L<X> : X ) ) ; S : 'a' ;").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].pos.col, 9);
+        let errors = load_grammar_str("L<X> : X ) ; S : L<'a'> L<'b'> L<'c'> ;").unwrap_err();
+        assert_eq!(errors.len(), 1);
+
+        // errors found while expanding point into the body
+        let errors = load_grammar_str("L<X> : X\n  M<X> ;\nS : L<'a'> ;").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].found, "'M'");
+        assert_eq!((errors[0].pos.line, errors[0].pos.col), (2, 2));
+        let errors = load_grammar_str("S : L<'é'> ;\nL<X> : X | X L<L<X>> ;").unwrap_err();
+        let pos = &errors[0].pos;
+        assert_eq!((pos.line, pos.col, pos.char_pos, pos.pos), (2, 13, 26, 27));
+    }
+
+    #[test]
+    fn analyze_test() {
+        let g = load_grammar_str(r#"
+          S : A B N | A B N | A B N `other` ;
+          A : 'a' | Exprr ;
+          B : C 'x' ;
+          C : B 'y' | 'c' | D ;
+          D : D 'z' ;
+          N : Opt N 'n' | 'n' ;
+          Opt : 'o' | ;
+          U : 'u' ;
+        "#).unwrap();
+        let diags = g.analyze("S");
+        assert_eq!(diags, vec![
+            Diagnostic::UndefinedSymbol { name: "Exprr".to_string(), used_in: Some("A".to_string()) },
+            Diagnostic::Unreachable { name: "U".to_string() },
+            Diagnostic::Unproductive { name: "D".to_string() },
+            Diagnostic::DuplicateProduction { nonterm: "S".to_string(), index: 1 },
+            Diagnostic::LeftRecursion { cycle: vec!["B".to_string(), "C".to_string()] },
+            Diagnostic::LeftRecursion { cycle: vec!["D".to_string()] },
+            Diagnostic::LeftRecursion { cycle: vec!["N".to_string()] },
+        ]);
+        assert_eq!(diags[4].to_string(), "left recursion: B -> C -> B");
+
+        let diags = g.analyze("Start");
+        assert_eq!(diags[0].to_string(), "undefined start symbol Start");
+    }
+
+    #[test]
+    fn first_follow_test() {
+        let gs = r#"
+          E : T Ep ;
+          Ep : '+' T Ep | ;
+          T : F Tp ;
+          Tp : '*' F Tp | ;
+          F : '(' E ')' | /[0-9]+/ ;
+        "#;
+        fn lit(s : &str) -> Lookahead<String> {
+            Lookahead::Terminal(s.to_string())
+        }
+        fn sorted<T : Ord + Clone>(set : &HashSet<T>) -> Vec<T> {
+            let mut v : Vec<T> = set.iter().cloned().collect();
+            v.sort();
+            v
+        }
+        let g = load_grammar_str(gs).unwrap();
+        assert_eq!(sorted(&g.nullable()), vec!["Ep", "Tp"]);
+        let first = g.first_sets();
+        assert_eq!(sorted(&first["E"]), vec![lit("("), Lookahead::Regex("[0-9]+".to_string())]);
+        assert_eq!(sorted(&first["Tp"]), vec![lit("*")]);
+        let follow = g.follow_sets("E");
+        assert_eq!(sorted(&follow["E"]), vec![lit(")"), Lookahead::End]);
+        assert_eq!(sorted(&follow["F"]), vec![lit(")"), lit("*"), lit("+"), Lookahead::End]);
+
+        // the same sets by string index
+        let c = compile_grammar(gs).unwrap();
+        let idx = |s : &str| c.lookup_string(s).unwrap();
+        assert_eq!(sorted(&c.nullable()), sorted(&[idx("Ep"), idx("Tp")].iter().cloned().collect()));
+        let first = c.first_sets();
+        assert!(first[&idx("E")].contains(&Lookahead::Terminal(idx("("))));
+        assert!(first[&idx("E")].contains(&Lookahead::Regex(0)));
+        assert_eq!(first[&idx("E")].len(), 2);
+        let follow = c.follow_sets(idx("E"));
+        assert_eq!(follow[&idx("F")].len(), 4);
+        assert!(follow[&idx("T")].contains(&Lookahead::End));
+    }
+
+    #[test]
+    fn display_test() {
+        let gs = r#"
+          # a list of items
+          S : Item (first) (',' Item (rest))* ';'? `items`
+            // nothing
+            | ;
+          Item : /[0-9]+/ | [a-z] | 'it\'s' | "dq" | ( 'a' 'b' | Other )+ `word` ;
+          List<X, Sep> : X | X Sep List<X, Sep> ;
+          L : List<Item, ','> | x::Y ;
+          x::Y : y ;
+        "#;
+        let g = load_grammar_str(gs).unwrap();
+        let printed = g.to_string();
+        assert_eq!(printed, r#"List<X, Sep> : X | X Sep List<X, Sep> ;
+
+Item
+    : /[0-9]+/
+    | [a-z]
+    | 'it\'s'
+    | 'dq'
+    | ( 'a' 'b' | Other )+ `word`
+    ;
+
+L
+    : List<Item, ','>
+    | x::Y
+    ;
+
+// a list of items
+S
+    : Item (first) ( ',' Item (rest) )* ';'? `items`
+    // nothing
+    |
+    ;
+
+x::Y
+    : y
+    ;
+
+"#);
+        // printing the loaded output gives the same text
+        let g2 = load_grammar_str(&printed).unwrap();
+        assert_eq!(g2.to_string(), printed);
+        assert_eq!(g2.bare_terminals().len(), g.bare_terminals().len());
+    }
+
+    #[test]
+    fn builder_test() {
+        let g = rule("Expr").seq(["Term", "'+'", "Expr"]).event("add")
+            .rule("Expr").seq(["Term"])
+            .rule("Term").seq(["/[0-9]+/"]).event("num").doc("a number")
+            .build().unwrap();
+        assert!(g.is_nonterminal("Term"));
+        assert_eq!(g.iter_over_nonterm("Term")[0].doc.as_ref().unwrap(), "a number");
+        assert_eq!(g.to_string(), load_grammar_str(r#"
+          Expr : Term '+' Expr `add` | Term ;
+          Term : /* a number */ /[0-9]+/ `num` ;
+        "#).unwrap().to_string());
+
+        let c = compile(&g).unwrap();
+        let tokens = ["1", "+", "2", "+", "3"];
+        let pt = run_input("Expr", &c, &tokens[..], tokens.len()).unwrap();
         assert_eq!(pt.count(), 1);
+
+        let errors = rule("S").seq(["A*", "/[/", "'a' 'b'"])
+            .rule("T x").seq(["'t'"]).event("bad event")
+            .build().unwrap_err();
+        let messages : Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(messages, vec![
+            "1:0: expected regular expression, found /[/ (missing ']') in rule S",
+            "1:4: expected end of item, found 'b' in rule S",
+            "1:2: expected nonterminal name, found 'x' in rule T x",
+            "1:4: expected event name, found 'event' in rule T x",
+        ]);
+        assert_eq!(errors[0].file, None);
+        assert_eq!(errors[0].nonterm, Some("S".to_string()));
+
+        // grammars changed in code are checked by compile
+        let mut g = load_grammar_str("S : 'a' ;").unwrap();
+        let mut prod = Production::new();
+        prod.components.push(Component::new(RuleId::Regex("(a".to_string())));
+        g.add_rule(&"S".to_string(), prod);
+        assert_eq!(g.add_token("N", "["), Err("missing ']'".to_string()));
+        assert!(g.add_skip("*").is_err());
+        assert!(g.token_rules().is_empty() && g.skips().is_empty());
+        let errors = compile(&g).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "1:0: expected regular expression, found /(a/ (missing ')') in rule S");
+    }
+
+    #[test]
+    fn deterministic_compile_test() {
+        let gs = r#"
+          S : A B* C `s` | C A ;
+          A : 'a' | ( 'x' | 'y' ) ;
+          B : 'b' ;
+          C : 'c' | A? ;
+        "#;
+        let g = load_grammar_str(gs).unwrap();
+        let nts = g.nonterminals();
+        assert_eq!(&nts[..3], &["S", "S~1", "A"]);
+
+        let c = compile_grammar(gs).unwrap();
+        let ops : Vec<String> = (0..20).map(|ip| format!("{:?}", c.at(ip))).collect();
+        for _ in 0..10 {
+            let c2 = compile_grammar(gs).unwrap();
+            assert_eq!(c2.strings, c.strings);
+            let ops2 : Vec<String> = (0..20).map(|ip| format!("{:?}", c2.at(ip))).collect();
+            assert_eq!(ops2, ops);
+        }
+    }
+
+    #[test]
+    fn start_test() {
+        let gs = r#"
+          %start Document, Fragment
+          Document : Fragment+ ;
+          Fragment : 'a' Inner ;
+          Inner : 'b' ;
+        "#;
+        let g = load_grammar_str(gs).unwrap();
+        assert_eq!(g.start_symbols(), vec!["Document", "Fragment"]);
+        assert!(g.to_string().starts_with("%start Document, Fragment\n"));
+
+        let c = compile_grammar(gs).unwrap();
+        assert_eq!(c.entry_points().len(), 2);
+        assert!(c.entry_point("Inner").is_none());
+        assert!(c.entry_point("Documnet").is_none());
+        let doc = c.entry_point("Document").unwrap();
+        assert_eq!(c.entry_points()[0], doc);
+        let tokens = ["a", "b", "a", "b"];
+        assert_eq!(run_entry(doc, &c, &tokens[..], tokens.len()).count(), 1);
+        let frag = c.entry_point("Fragment").unwrap();
+        assert_eq!(run_entry(frag, &c, &tokens[..2], 2).count(), 1);
+
+        // without %start every nonterminal is an entry point
+        let c = compile_grammar("S : 'a' ;").unwrap();
+        assert!(c.entry_points().is_empty());
+        assert!(c.entry_point("S").is_some());
+        assert!(c.entry_point("a").is_none());
+
+        // the run functions check the start nonterminal the same way
+        let tokens = ["a"];
+        assert_eq!(run_input("S", &c, &tokens[..], 1).unwrap().count(), 1);
+        assert_eq!(run_input("a", &c, &tokens[..], 1).err(), Some(RunError::UnknownStart("a".to_string())));
+        assert!(run("T", &c, |v, i| i < 1 && tokens[i] == v, 1).is_err());
+        let c = compile_grammar(gs).unwrap();
+        let err = run_input("Inner", &c, &tokens[..], 1).err().unwrap();
+        assert_eq!(err.to_string(), "unknown start nonterminal Inner");
+
+        // start nonterminals added in code are checked by compile
+        let mut g = load_grammar_str("S : 'a' ;").unwrap();
+        g.add_start("Nope");
+        let errors = compile(&g).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "1:0: expected defined start nonterminal, found 'Nope' in rule Nope");
+
+        let errors = compile_grammar("%start Documnet, S\nS : 'a' ;").err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "1:7: expected defined start nonterminal, found 'Documnet'");
+        let errors = rule("S").seq(["'a'"]).start("T").build().unwrap_err();
+        assert_eq!(errors[0].found, "'T'");
+    }
+
+    #[test]
+    fn abnf_test() {
+        let abnf = "; a small part of RFC 3986
+URI-reference = scheme \":\" [ \"//\" host ] *( \"/\" segment )
+scheme        = ALPHA *( ALPHA / DIGIT / \"+\" / \"-\" / \".\" )
+host          = 1*3dec-octet / %s\"LOCAL\"
+host          =/ %x5B.5D ; []
+dec-octet     = digit
+segment       = 0*2( %x61-7A / \"%\" 2HEXDIG )
+";
+        let g = load_abnf_str(abnf).unwrap();
+        // core rules are added when referenced
+        assert!(g.is_nonterminal("HEXDIG"));
+        assert!(g.is_nonterminal("DIGIT"));
+        assert!(!g.is_nonterminal("WSP"));
+        assert_eq!(g.iter_over_nonterm("host").len(), 3);
+        // names with '-' are written with '_' and load as a grammar file
+        assert!(g.is_nonterminal("URI_reference") && g.is_nonterminal("dec_octet"));
+        let printed = format!("{}", load_abnf_str("foo-bar = \"x\" Baz-1\nbaz-1 = \"y\"\n").unwrap());
+        assert!(printed.contains("foo_bar\n    : [xX] baz_1\n"));
+        assert_eq!(load_grammar_str(&printed).unwrap().to_string(), printed);
+        assert_eq!(load_grammar_str(&g.to_string()).unwrap().to_string(), g.to_string());
+
+        let c = compile(&g).unwrap();
+        let accepts = |text : &str| {
+            let tokens : Vec<String> = text.chars().map(|c| c.to_string()).collect();
+            run_input("URI_reference", &c, &tokens[..], tokens.len()).unwrap().count()
+        };
+        assert_eq!(accepts("http:"), 1);
+        assert_eq!(accepts("HTTP://127/a/%2f"), 1);
+        assert_eq!(accepts("x-1://local"), 0);
+        assert_eq!(accepts("x-1://LOCAL/ab/"), 1);
+        assert_eq!(accepts("x://[]"), 1);
+        assert_eq!(accepts("a://1234"), 0);
+        assert_eq!(accepts("a:/abc"), 0);
+
+        // ABNF chars are regexes, the sampler generates them
+        let other = load_abnf_str("URI-reference = \"#\"\n").unwrap();
+        let differences = sample_differences(&g, &other, "URI_reference", 10, 5).unwrap();
+        assert!(differences.iter().any(|d| d.first_only && d.tokens.contains(&":".to_string())));
+
+        let errors = load_abnf_str("a = b / \"x\nc = 2*1\"y\"\nd =/ \"z\"\n").unwrap_err();
+        let messages : Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(messages, vec![
+            "1:8: expected '\"' to end the string, found end of line",
+            "2:0: expected element, found 'c'",
+            "2:7: expected repetition with min <= max, found string",
+            "3:0: expected rule defined before '=/', found 'd'",
+        ]);
+        let errors = load_abnf_str("a = b\n").unwrap_err();
+        assert_eq!(errors[0].to_string(), "1:4: expected defined rule, found 'b'");
+    }
+
+    #[test]
+    fn yacc_ebnf_test() {
+        let yacc = "%{
+#include <stdio.h>
+%}
+%token NUMBER
+%left '+' '-'
+%start expr
+%%
+expr : term '+' expr   { $$ = $1 + $3; }
+     | term '-' expr   { $$ = $1 - $3; }
+     | term
+     ;
+term : NUMBER
+     | '(' expr ')' %prec '+'
+     ;
+%%
+int main() { return yyparse(); }
+";
+        let g = load_yacc_str(yacc).unwrap();
+        assert_eq!(g.start_symbols(), vec!["expr"]);
+        assert_eq!(g.iter_over_nonterm("expr").len(), 3);
+        let c = compile(&g).unwrap();
+        let accepts = |tokens : &[&str]| run_input("expr", &c, tokens, tokens.len()).unwrap().count();
+        assert_eq!(accepts(&["NUMBER", "+", "(", "NUMBER", "-", "NUMBER", ")"]), 1);
+        assert_eq!(accepts(&["NUMBER", "+"]), 0);
+
+        let g = load_yacc_str("%token NUMBER\n%%\nlist.item : list.item ',' NUMBER | NUMBER ;\n").unwrap();
+        assert_eq!(g.start_symbols(), vec!["list_item"]);
+        assert_eq!(load_grammar_str(&g.to_string()).unwrap().to_string(), g.to_string());
+        let g = load_yacc_str("%token NUMBER\n%%\na.b : a_b ;\na_b : NUMBER ;\n").unwrap();
+        assert_eq!(g.start_symbols(), vec!["a_b_"]);
+
+        let errors = load_yacc_str("%%\nexpr : expr '+' NUM ;\n").unwrap_err();
+        assert_eq!(errors[0].to_string(), "2:16: expected declared token or rule, found 'NUM'");
+
+        let ebnf = "/* the same expressions */
+[1] expr   ::= term (('+' | '-') term)*
+[2] term   ::= number | '(' expr ')'
+[3] number ::= [0-9]+ | #x221E
+";
+        let g = load_ebnf_str(ebnf).unwrap();
+        let c = compile(&g).unwrap();
+        let accepts = |text : &str| {
+            let tokens : Vec<String> = text.chars().map(|c| c.to_string()).collect();
+            run_input("expr", &c, &tokens[..], tokens.len()).unwrap().count()
+        };
+        assert_eq!(accepts("12+(3-\u{221E})"), 1);
+        assert_eq!(accepts("1+"), 0);
+        assert_eq!(accepts("a"), 0);
+
+        // productions of the XML specification, strings match char by char
+        let xml = r#"
+[3]  S ::= (#x20 | #x9 | #xD | #xA)+
+[4]  NameStartChar ::= ":" | [A-Z] | "_" | [a-z] | [#xC0-#xD6] | [#xD8-#xF6] | [#xF8-#x2FF]
+[4a] NameChar ::= NameStartChar | "-" | "." | [0-9] | #xB7 | [#x0300-#x036F] | [#x203F-#x2040]
+[5]  Name ::= NameStartChar (NameChar)*
+[24] VersionInfo ::= S 'version' Eq ("'" VersionNum "'" | '"' VersionNum '"')
+[25] Eq ::= S? '=' S?
+[26] VersionNum ::= '1.' [0-9]+
+"#;
+        let c = compile(&load_ebnf_str(xml).unwrap()).unwrap();
+        let accepts = |start : &str, text : &str| {
+            let tokens : Vec<String> = text.chars().map(|c| c.to_string()).collect();
+            run_input(start, &c, &tokens[..], tokens.len()).unwrap().count()
+        };
+        assert_eq!(accepts("Name", "xml:lang-1.b\u{e9}"), 1);
+        assert_eq!(accepts("Name", "1abc"), 0);
+        assert_eq!(accepts("VersionInfo", " version = '1.0'"), 1);
+        assert_eq!(accepts("VersionInfo", " version=\"1.10\""), 1);
+        assert_eq!(accepts("VersionInfo", " version='2.0'"), 0);
+        // an operator after a string repeats the whole string
+        let c = compile(&load_ebnf_str("X ::= 'ab'+ 'c'").unwrap()).unwrap();
+        let tokens = ["a", "b", "a", "b", "c"];
+        assert_eq!(run_input("X", &c, &tokens[..], tokens.len()).unwrap().count(), 1);
+        assert_eq!(run_input("X", &c, &tokens[1..], tokens.len() - 1).unwrap().count(), 0);
+
+        let g = load_ebnf_str("doc.body ::= item-list\nitem-list ::= 'x'+").unwrap();
+        assert!(g.is_nonterminal("doc_body") && g.is_nonterminal("item_list"));
+        assert_eq!(load_grammar_str(&g.to_string()).unwrap().to_string(), g.to_string());
+
+        let errors = load_ebnf_str("a ::= b - c\nb ::= 'x' d\n").unwrap_err();
+        let messages : Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(messages, vec![
+            "1:8: expected item (A - B is not supported), found '-'",
+            "2:10: expected defined symbol, found 'd'",
+        ]);
+    }
+
+    #[test]
+    fn precedence_test() {
+        let gs = "
+          %left '+' '-'
+          %left '*'
+          %right '^'
+          %nonassoc '<'
+          E : E '+' E | E '-' E | E '*' E | E '^' E | E '<' E | '(' E ')' | n ;
+        ";
+        let g = load_grammar_str(gs).unwrap();
+        assert_eq!(g.terminal_precedence("*"), Some((1, Assoc::Left)));
+        assert!(g.to_string().starts_with("%left '+' '-'\n%left '*'\n%right '^'\n%nonassoc '<'\n\n"));
+        let c = compile(&g).unwrap();
+        // the tree as nested parentheses around each E
+        let grouped = |tokens : &[&str]| {
+            let trees = run_input("E", &c, tokens, tokens.len()).unwrap();
+            (0..trees.count()).map(|i| {
+                let mut log = EventLog { events: Vec::new() };
+                trees.execute(i, &mut log);
+                log.events.iter().map(|e| match e.as_str() {
+                    "+E" => "[".to_string(),
+                    "-E" => "]".to_string(),
+                    idx => tokens[idx.parse::<usize>().unwrap()].to_string(),
+                }).collect::<String>()
+            }).collect::<Vec<String>>()
+        };
+        assert_eq!(grouped(&["n", "+", "n", "*", "n"]), vec!["[[n]+[[n]*[n]]]"]);
+        assert_eq!(grouped(&["n", "*", "n", "+", "n"]), vec!["[[[n]*[n]]+[n]]"]);
+        assert_eq!(grouped(&["n", "-", "n", "-", "n"]), vec!["[[[n]-[n]]-[n]]"]);
+        assert_eq!(grouped(&["n", "^", "n", "^", "n"]), vec!["[[n]^[[n]^[n]]]"]);
+        assert_eq!(grouped(&["(", "n", "+", "n", ")", "*", "n"]), vec!["[[([[n]+[n]])]*[n]]"]);
+        assert!(grouped(&["n", "<", "n", "<", "n"]).is_empty());
+        assert_eq!(grouped(&["n", "<", "n", "+", "n"]), vec!["[[[n]<[n]]+[n]]"]);
+
+        // the checks prune while parsing, long expressions stay fast
+        let c = compile_grammar("%left '+'\n%left '*'\nE : E '+' E | E '*' E | 'n' ;").unwrap();
+        let mut tokens = vec!["n"];
+        for i in 1..40 {
+            tokens.push(if i % 3 == 0 { "*" } else { "+" });
+            tokens.push("n");
+        }
+        let started = Instant::now();
+        assert_eq!(run_input("E", &c, &tokens[..], tokens.len()).unwrap().count(), 1);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let errors = load_grammar_str("%left '+'\n%right '^' '+'\nE : n ;").unwrap_err();
+        assert_eq!(errors[0].to_string(), "2:11: expected terminal without a precedence level, found '+'");
+
+        // yacc precedence declarations
+        let yacc = "%token NUM\n%left '+'\n%left '*'\n%%\ne : e '+' e | e '*' e | NUM ;\n";
+        let c = compile(&load_yacc_str(yacc).unwrap()).unwrap();
+        let tokens = ["NUM", "*", "NUM", "+", "NUM", "*", "NUM"];
+        assert_eq!(run_input("e", &c, &tokens[..], tokens.len()).unwrap().count(), 1);
+
+        // %prec gives a production the level of another terminal
+        let yacc = "%token n\n%left '-'\n%left '*'\n%right UMINUS\n%%\n\
+                    e : e '-' e | e '*' e | '-' e %prec UMINUS | n ;\n";
+        let g = load_yacc_str(yacc).unwrap();
+        assert!(g.to_string().contains("| '-' e %prec 'UMINUS'\n"));
+        let c = compile(&g).unwrap();
+        let tokens = ["-", "n", "*", "n"];
+        assert_eq!(run_input("e", &c, &tokens[..], tokens.len()).unwrap().count(), 1);
+        let errors = load_yacc_str("%token n\n%%\ne : '-' e %prec NEG | n ;\n").unwrap_err();
+        assert_eq!(errors[0].to_string(), "3:16: expected token with a precedence level, found 'NEG'");
+
+        // ... and in the grammar language
+        let gs = "%left '-'\n%left '*'\n%right 'NEG'\n\
+                  E : E '-' E | E '*' E | '-' E %prec 'NEG' `neg` | n ;";
+        let g = load_grammar_str(gs).unwrap();
+        assert_eq!(g.iter_over_nonterm("E")[2].precedence, Some("NEG".to_string()));
+        let printed = g.to_string();
+        assert!(printed.contains("    | '-' E %prec 'NEG' `neg`\n"));
+        assert_eq!(load_grammar_str(&printed).unwrap().to_string(), printed);
+        let c = compile(&g).unwrap();
+        assert_eq!(run_input("E", &c, &tokens[..], tokens.len()).unwrap().count(), 1);
+        let tokens = ["-", "n", "-", "n"];
+        let trees = run_input("E", &c, &tokens[..], tokens.len()).unwrap();
+        assert_eq!(trees.count(), 1);
+        let errors = load_grammar_str("%left '-'\nE : '-' E %prec 'NEG' | n ;").unwrap_err();
+        assert_eq!(errors[0].to_string(), "2:16: expected terminal with a precedence level, found 'NEG'");
+    }
+
+    #[test]
+    fn predicate_test() {
+        let gs = r#"
+          S : Word* ;
+          Word : Keyword `keyword` | !Keyword /[a-z]+/ `ident` ;
+          Keyword : 'if' | 'end' ;
+          Pair : &'a' Letter Letter | Letter ;
+          Letter : [a-z] ;
+        "#;
+        let g = load_grammar_str(gs).unwrap();
+        assert!(g.to_string().contains("    | !Keyword /[a-z]+/ `ident`\n"));
+        assert!(g.to_string().contains("    : &'a' Letter Letter\n"));
+        assert!(g.nullable().contains("S"));
+        assert!(!g.nullable().contains("Word"));
+
+        let c = compile(&g).unwrap();
+        let tokens = ["x", "if", "end", "y"];
+        let trees = run_input("S", &c, &tokens[..], tokens.len()).unwrap();
+        // without the predicate the keywords would also be identifiers
+        assert_eq!(trees.count(), 1);
+        // predicates add nothing to the tree
+        let mut log = EventLog { events: Vec::new() };
+        trees.execute(0, &mut log);
+        assert_eq!(log.events.join(" "), "+S +Word 0 -Word +Word +Keyword 1 -Keyword -Word \
+                                         +Word +Keyword 2 -Keyword -Word +Word 3 -Word -S");
+
+        let pair = |tokens : &[&str]| run_input("Pair", &c, tokens, tokens.len()).unwrap().count();
+        assert_eq!(pair(&["a", "b"]), 1);
+        assert_eq!(pair(&["b", "a"]), 0);
+        assert_eq!(pair(&["b"]), 1);
+        assert_eq!(pair(&["a"]), 1);
+
+        // predicates that depend on themselves do not match
+        let c = compile_grammar("S : !S 'a' | 'b' ;").unwrap();
+        assert_eq!(run_input("S", &c, &["a"][..], 1).unwrap().count(), 0);
+        let c = compile_grammar("S : A ; A : &B 'a' ; B : A | 'a' ;").unwrap();
+        assert_eq!(run_input("S", &c, &["a"][..], 1).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn normalize_test() {
+        assert_eq!(nfc("cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(nfc("\u{212B}"), "\u{c5}");
+        assert_eq!(nfc("a\u{323}\u{302}"), "\u{1ead}");
+        assert_eq!(nfc("a\u{302}\u{323}"), "\u{1ead}");
+        assert_eq!(nfc("\u{1100}\u{1161}\u{11a8}"), "\u{ac01}");
+        assert_eq!(fold_case("Stra\u{df}e"), fold_case("STRASSE"));
+
+        let gs = r"
+          %normalize nfc
+          S : 'select'i Col 'from'i /[a-z\u{e0}-\u{ff}]+/ ;
+          Col : '*' | 'café' ;
+        ";
+        let g = load_grammar_str(gs).unwrap();
+        let printed = g.to_string();
+        assert!(printed.starts_with("%normalize nfc\n"));
+        assert!(printed.contains(": 'select'i Col 'from'i"));
+        let c = compile(&g).unwrap();
+        let accepts = |tokens : &[&str]| run_input("S", &c, tokens, tokens.len()).unwrap().count();
+        assert_eq!(accepts(&["SELECT", "cafe\u{301}", "From", "t"]), 1);
+        assert_eq!(accepts(&["Select", "*", "from", "cre\u{300}me"]), 1);
+        assert_eq!(accepts(&["select", "CAF\u{c9}", "from", "t"]), 0);
+
+        // grammar-wide case folding, a closure gets the folded terminals
+        let c = compile_grammar("%normalize casefold\nS : 'Abc' 'x'i ;").unwrap();
+        let tokens = ["ABC", "x"];
+        assert_eq!(run_input("S", &c, &tokens[..], 2).unwrap().count(), 1);
+        assert_eq!(run("S", &c, |v, i| i < 2 && fold_case(tokens[i]) == v, 2).unwrap().count(), 1);
+        assert_eq!(run("S", &c, |v, i| i < 2 && tokens[i] == v, 2).unwrap().count(), 0);
+        let c = compile_grammar("S : 'select'i ;").unwrap();
+        assert!(!c.needs_token_text());
+        assert_eq!(run_input("S", &c, &["SELECT"][..], 1).unwrap().count(), 1);
+        assert_eq!(run("S", &c, |v, i| i < 1 && fold_case("SeLect") == v, 1).unwrap().count(), 1);
+        let c = compile_grammar("%normalize nfc\nS : 'cafe\u{301}' ;").unwrap();
+        assert_eq!(run("S", &c, |v, i| i < 1 && nfc("caf\u{e9}") == v, 1).unwrap().count(), 1);
+
+        let errors = load_grammar_str("%normalize nfd\nS : 'a' ;").unwrap_err();
+        assert_eq!(errors[0].to_string(), "1:11: expected normalization (casefold or nfc), found 'nfd'");
+    }
+
+    #[test]
+    fn class_test() {
+        let gs = r"
+          S : Value | Value '+' S ;
+          Value : <NUMBER> | <IDENT> | '(' S ')' ;
+        ";
+        let g = load_grammar_str(gs).unwrap();
+        assert!(g.to_string().contains(": <NUMBER>\n"));
+
+        let errors = compile_with_matchers(&g, Matchers::new().class("NUMBER", |_| true)).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].nonterm, Some("Value".to_string()));
+        assert_eq!(errors[0].to_string(),
+                   "3:29: expected token class declared with %token or registered, found <IDENT> in rule Value");
+        // without matchers every class must be a %token
+        assert_eq!(compile(&g).err().unwrap().len(), 2);
+        assert!(compile_grammar("%token NUMBER /[0-9]+/\nS : <NUMBER> ;").is_ok());
+        // grammars built in code have no positions
+        let mut built = load_grammar_str("S : 'a' ;").unwrap();
+        let mut prod = Production::new();
+        prod.components.push(Component::new(RuleId::Class("N".to_string())));
+        built.add_rule(&"S".to_string(), prod);
+        let errors = compile(&built).err().unwrap();
+        assert_eq!(errors[0].to_string(),
+                   "1:0: expected token class declared with %token or registered, found <N> in rule S");
+
+        // count the calls of the NUMBER matcher
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let matchers = Matchers::new()
+            .class("NUMBER", move |tok| {
+                counter.set(counter.get() + 1);
+                tok.chars().all(|c| c.is_ascii_digit())
+            })
+            .class("IDENT", |tok| tok.chars().all(char::is_alphabetic));
+        let c = compile_with_matchers(&g, matchers).unwrap();
+        let tokens = ["1", "+", "(", "x", "+", "22", ")"];
+        let trees = run_input("S", &c, &tokens[..], tokens.len()).unwrap();
+        assert_eq!(trees.count(), 1);
+        let mut log = EventLog { events: Vec::new() };
+        trees.execute(0, &mut log);
+        assert!(log.events.join(" ").starts_with("+S +Value 0 -Value 1 +S +Value 2 +S +Value 3 -Value"));
+        // at most one call per token
+        assert!(calls.get() <= tokens.len());
+
+        assert_eq!(run_input("S", &c, &["+"][..], 1).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn diff_test() {
+        let old = load_grammar_str(r"
+          Expr : Expr '+' Term | Expr '-' Term | Term ;
+          Term : 'n' | '(' Expr ')' ;
+          Unused : 'u' ;
+        ").unwrap();
+        // reformatted, Term unchanged
+        let new = load_grammar_str(r"
+          Term : 'n'
+               | '(' Expr ')' ;
+          Expr : Expr '+' Term | Expr '*' Term | Term ;
+          List : Term (',' Term)* ;
+        ").unwrap();
+        let diff = old.diff(&new);
+        assert_eq!(diff.added, ["List"]);
+        assert_eq!(diff.removed, ["Unused"]);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].nonterm, "Expr");
+        assert_eq!(diff.changed[0].added, ["Expr '*' Term"]);
+        assert_eq!(diff.changed[0].removed, ["Expr '-' Term"]);
+        assert_eq!(diff.to_string(), "+ List\n- Unused\n~ Expr\n    + : Expr '*' Term\n    - : Expr '-' Term\n");
+        assert!(old.diff(&old).is_empty());
+
+        // directives
+        let ops = load_grammar_str("%left '+'\n%left '*'\nE : E '+' E | E '*' E | 'n' ;").unwrap();
+        let swapped = load_grammar_str("%start E\n%left '*'\n%left '+'\n%token N /[0-9]+/\nE : E '+' E | E '*' E | 'n' ;").unwrap();
+        let diff = ops.diff(&swapped);
+        assert!(diff.changed.is_empty());
+        assert_eq!(diff.added_directives, ["%start E", "%left '*'", "%left '+'", "%token N /[0-9]+/"]);
+        assert_eq!(diff.removed_directives, ["%left '+'", "%left '*'"]);
+        let cased = load_grammar_str("%normalize casefold\n%skip /\\s+/\nE : 'n' ;").unwrap();
+        let plain = load_grammar_str("E : 'n' ;").unwrap();
+        assert_eq!(plain.diff(&cased).to_string(), "+ %normalize casefold\n+ %skip /\\s+/\n");
+
+        let differences = sample_differences(&old, &new, "Expr", 50, 7).unwrap();
+        assert!(differences.iter().any(|d| d.first_only && d.tokens.contains(&"-".to_string())));
+        assert!(differences.iter().any(|d| !d.first_only && d.tokens.contains(&"*".to_string())));
+        assert!(differences.iter().all(|d| d.tokens.contains(&"-".to_string()) == d.first_only));
+        assert_eq!(differences, sample_differences(&old, &new, "Expr", 50, 7).unwrap());
+        assert!(sample_differences(&old, &old, "Expr", 50, 7).unwrap().is_empty());
+
+        // regexes and %token classes are sampled with a text they match
+        let digits = load_grammar_str("%token ID /[a-z]+/\nS : /[0-9]+/ | ID '=' /[0-9]+/ ;").unwrap();
+        let letters = load_grammar_str("%token ID /[a-z]+/\nS : /[0-9]+/ ;").unwrap();
+        let differences = sample_differences(&digits, &letters, "S", 20, 3).unwrap();
+        assert_eq!(differences, [SampleDifference { tokens : vec!["a".to_string(), "=".to_string(), "0".to_string()],
+                                                    first_only : true }]);
+    }
+
+    #[test]
+    fn extends_test() {
+        let base = write_files("extends", &[
+            ("strict.g", "%start Doc\nDoc : Value (',' Value)* ;\nValue : Number | 'true' | 'false' | '[' Doc? ']' ;\nNumber : 'n' | '-' 'n' ;\n"),
+            ("lenient.g", "%extends \"strict.g\"\n\
+                           Value |= 'null' | 'undefined' ;\n\
+                           Number -= '-' 'n' ;\n\
+                           Doc : Value (';' Value)* ;\n\
+                           Doc |= ;\n"),
+            ("bad.g", "%extends \"strict.g\"\nNumber -= '+' 'n' ;\nMissing |= 'x' ;\n"),
+            ("late.g", "A : 'a' ;\n%extends \"strict.g\"\n"),
+            ("last.g", "%extends \"strict.g\"\nNumber -= 'n' ;\nNumber -= '-' 'n' ;\n"),
+            ("local.g", "%extends \"strict.g\"\nNumber |= 'x' ;\nNumber |= 'y' ;\nLocal : 'l' ;\nLocal |= 'm' ;\n"),
+        ]);
+
+        let g = load_grammar_file(base.join("lenient.g")).unwrap();
+        assert_eq!(g.start_symbols(), ["Doc"]);
+        let printed = g.to_string();
+        assert!(printed.contains("Doc\n    : Value ( ';' Value )*\n    |\n"));
+        assert!(printed.contains("Number\n    : 'n'\n    ;\n"));
+        assert!(printed.contains("    | 'null'\n    | 'undefined'\n"));
+        // the helper of the replaced Doc production is gone
+        assert!(!g.nonterminals().iter().any(|nt| nt.starts_with("Doc~") && g.iter_over_nonterm(nt)
+            .iter().any(|p| g.production_source(p).contains("','"))));
+
+        let c = compile_grammar_file(base.join("lenient.g")).unwrap();
+        let accepts = |tokens : &[&str]| run_input("Doc", &c, tokens, tokens.len()).unwrap().count();
+        assert_eq!(accepts(&["n", ";", "null", ";", "[", "true", "]"]), 1);
+        assert_eq!(accepts(&["n", ",", "n"]), 0);
+        assert_eq!(accepts(&["-", "n"]), 0);
+        assert_eq!(accepts(&[]), 1);
+
+        let strict = load_grammar_file(base.join("strict.g")).unwrap();
+        let diff = strict.diff(&g);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(diff.changed.len(), 3);
+
+        let errors = load_grammar_file(base.join("bad.g")).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].to_string().split_once(':').unwrap().1, "2:10: expected production of Number, found ''+' 'n''");
+        assert_eq!(errors[1].expected, "inherited nonterminal");
+        let errors = load_grammar_file(base.join("late.g")).unwrap_err();
+        assert_eq!(errors[0].expected, "%extends before the rules");
+        // -= keeps a production
+        let errors = load_grammar_file(base.join("last.g")).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string().split_once(':').unwrap().1,
+                   "3:10: expected production of Number that is not its last, found ''-' 'n''");
+        // |= and -= only for inherited nonterminals, as often as needed
+        let errors = load_grammar_file(base.join("local.g")).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string().split_once(':').unwrap().1, "5:0: expected inherited nonterminal, found 'Local'");
+
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn unicode_test() {
+        let input_str = "caf\u{e9} \u{1f600}x\n\u{6f22}\u{5b57}";
+        let mut tokens : Vec<Token> = Vec::new();
+        {
+            let mut t = Tokenizer::with_config(LexerConfig::grammar(), |t| tokens.push(t));
+            for ch in input_str.chars() {
+                t.push(ch);
+            }
+            t.finish().unwrap();
+        }
+        let texts : Vec<&str> = tokens.iter().map(|t| &input_str[t.beg.pos..t.end.pos]).collect();
+        assert_eq!(texts, ["caf\u{e9}", "\u{1f600}x", "\u{6f22}\u{5b57}"]);
+        let end = &tokens[1].end;
+        assert_eq!((end.line, end.col, end.utf16_col, end.char_pos, end.pos), (1, 7, 8, 7, 11));
+        let beg = &tokens[2].beg;
+        assert_eq!((beg.line, beg.col, beg.utf16_col, beg.char_pos, beg.pos), (2, 0, 0, 8, 12));
+
+        let gs = "
+          // \u{6587}\u{6cd5}: a sentence of words
+          S : '\u{4f60}\u{597d}' Word+ '\u{3002}' ;
+          Word : 'caf\u{e9}' | /[\u{3040}-\u{30ff}]+/ ;
+          List<X> : X | X '\u{3001}' List<X> ;
+          T : List<Word> ;
+        ";
+        let g = load_grammar_str(gs).unwrap();
+        assert_eq!(g.doc("S"), Some("\u{6587}\u{6cd5}: a sentence of words"));
+        let printed = g.to_string();
+        assert!(printed.contains("    : '\u{4f60}\u{597d}' Word+ '\u{3002}'\n"));
+        assert!(printed.contains("List<X> : X | X '\u{3001}' List<X> ;\n"));
+        let c = compile(&g).unwrap();
+        let tokens = ["\u{4f60}\u{597d}", "caf\u{e9}", "\u{3072}\u{3089}\u{304c}\u{306a}", "\u{3002}"];
+        assert_eq!(run_input("S", &c, &tokens[..], tokens.len()).unwrap().count(), 1);
+        let tokens = ["caf\u{e9}", "\u{3001}", "\u{30ab}\u{30bf}"];
+        assert_eq!(run_input("T", &c, &tokens[..], tokens.len()).unwrap().count(), 1);
+
+        // error columns count chars
+        let errors = load_grammar_str("S : '\u{1f600}' \u{e9} | ;\nT : '\u{e9}' ) ;").unwrap_err();
+        assert_eq!(errors[0].to_string(), "2:8: expected component, '|' or ';', found ')'");
+        assert_eq!(errors[0].pos.utf16_col, 8);
+        let errors = load_grammar_str("S : '\u{1f600}' ) ;").unwrap_err();
+        assert_eq!((errors[0].pos.col, errors[0].pos.utf16_col, errors[0].pos.pos), (8, 9, 11));
+    }
+
+    #[test]
+    fn lexer_test() {
+        let mut config = LexerConfig::new();
+        config.operators = vec!["->".to_string(), "::=".to_string(), "<=".to_string(), "==".to_string()];
+        config.line_comments = vec!["--".to_string()];
+        let input = "rule ::= x1 -> \"a \\\" b\" <= 2.5e-3 + 7.x -- note\n:: = a==b \\; e";
+        let tokens = tokenize(input, &config).unwrap();
+        let texts : Vec<&str> = tokens.iter().map(|t| t.text(input)).collect();
+        assert_eq!(texts, ["rule", "::=", "x1", "->", "\"a \\\" b\"", "<=", "2.5e-3", "+", "7", ".", "x",
+                           ":", ":", "=", "a", "==", "b", "\\;", "e"]);
+        let kinds : Vec<TokenKind> = tokens.iter().map(|t| t.kind).collect();
+        assert_eq!(&kinds[..8], [TokenKind::Word, TokenKind::Delimiter, TokenKind::Word, TokenKind::Delimiter,
+                                 TokenKind::Quoted, TokenKind::Delimiter, TokenKind::Number, TokenKind::Delimiter]);
+        assert_eq!(tokens[17].kind, TokenKind::Escape);
+
+        // the comment is skipped, digits end words without word_digits
+        let mut numbers = Vec::new();
+        {
+            config.word_digits = false;
+            config.exponents = false;
+            let mut t = Tokenizer::with_config(config.clone(), |t| if t.kind == TokenKind::Number { numbers.push(t) });
+            for ch in "x1 1e5 -- 2".chars() {
+                t.push(ch);
+            }
+            t.finish().unwrap();
+            assert_eq!(t.comments().len(), 1);
+            assert_eq!(t.comments()[0].text("x1 1e5 -- 2"), "-- 2");
+        }
+        // x 1, 1 e 5
+        assert_eq!(numbers.len(), 3);
+
+        // tokens of the input straight into the parser
+        let g = load_grammar_str("S : <WORD> '->' <WORD> | S ';' S ;").unwrap();
+        let matchers = Matchers::new().class("WORD", |t| t.chars().all(char::is_alphabetic));
+        let c = compile_with_matchers(&g, matchers).unwrap();
+        let mut config = LexerConfig::new();
+        config.operators = vec!["->".to_string()];
+        let input = "a -> b; c->d";
+        let words : Vec<&str> = tokenize(input, &config).unwrap().iter().map(|t| t.text(input)).collect();
+        assert_eq!(words.len(), 7);
+        assert_eq!(run_input("S", &c, &words[..], words.len()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn token_test() {
+        let src = "%token NUMBER /[0-9]+(\\.[0-9]+)?/\n%token NAME /[a-z]+/\n%skip /\\s+/\n\
+                   Expr : Expr '+' Term | Term ;\nTerm : NUMBER | NAME | '(' Expr ')' | 'pi' ;";
+        let g = load_grammar_str(src).unwrap();
+        assert_eq!(g.token_rules()[0], ("NUMBER".to_string(), "[0-9]+(\\.[0-9]+)?".to_string()));
+        assert!(g.is_token("NAME") && !g.is_token("Term"));
+        let printed = g.to_string();
+        assert!(printed.contains("%token NUMBER /[0-9]+(\\.[0-9]+)?/\n"));
+        assert!(printed.contains("%skip /\\s+/\n"));
+        assert_eq!(load_grammar_str(&printed).unwrap().to_string(), printed);
+
+        let c = compile(&g).unwrap();
+        let lexemes = c.lex("1.5 + (x+pi)").unwrap();
+        let texts : Vec<&str> = lexemes.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["1.5", "+", "(", "x", "+", "pi", ")"]);
+        assert_eq!(lexemes[3].beg.col, 7);
+        let (trees, lexemes) = parse_str(&c, "Expr", "1.5 + (x+y)").unwrap();
+        assert_eq!(trees.count(), 1);
+        assert_eq!((lexemes.len(), lexemes[6].beg.col), (7, 10));
+        // pi is as long as the NAME match, the literal wins
+        assert_eq!(parse_str(&c, "Expr", "pi").unwrap().0.count(), 1);
+        assert_eq!(parse_str(&c, "Expr", "x").unwrap().1[0].token, Some("NAME".to_string()));
+        assert_eq!(parse_str(&c, "Expr", "1 +").unwrap().0.count(), 0);
+        match parse_str(&c, "Expr", "1 +\n 2 $") {
+            Err(RunError::Lex(e)) => {
+                assert_eq!(e.to_string(), "2:3: expected token, found '$'");
+                assert_eq!((e.pos.line, e.found), (2, '$'));
+            }
+            _ => panic!("lexed '$'"),
+        }
+        assert_eq!(parse_str(&c, "Stmt", "1").err(), Some(RunError::UnknownStart("Stmt".to_string())));
+
+        let errors = load_grammar_str("%token N /[0-9]+/\n%token N /x/\nS : N ;").unwrap_err();
+        assert_eq!(errors[0].to_string(), "2:7: expected token name not declared before, found 'N'");
+        let errors = load_grammar_str("%token S /x/\nS : 'a' ;").unwrap_err();
+        assert_eq!(errors[0].expected, "token name that is not a nonterminal");
+        assert!(load_grammar_str("%token N /[0-9/\nS : N ;").is_err());
+
+        // names with digits in declarations, rules and references
+        let g = load_grammar_str("%token INT32 /[0-9]+/\nS : INT32 A1 ;\nA1 : 'a' ;").unwrap();
+        assert!(g.to_string().contains("    : <INT32> A1\n"));
+        assert_eq!(load_grammar_str(&g.to_string()).unwrap().to_string(), g.to_string());
+        let c = compile(&g).unwrap();
+        assert_eq!(parse_str(&c, "S", "42a").unwrap().0.count(), 1);
+
+        // keywords are not identifiers and identifiers not keywords
+        let c = compile(&load_grammar_str("%token ID /[a-z]+/\n%skip / /\nS : 'if' ID | ID ID ;").unwrap()).unwrap();
+        assert_eq!(parse_str(&c, "S", "if x").unwrap().0.count(), 1);
+        assert_eq!(parse_str(&c, "S", "x if").unwrap().0.count(), 0);
+        assert_eq!(parse_str(&c, "S", "x y").unwrap().0.count(), 1);
+    }
+
+    #[test]
+    fn unterminated_test() {
+        let config = LexerConfig::new();
+        match tokenize("x = 'it\\'s", &config) {
+            Err(TokenizeError::UnterminatedQuote(pos, '\'')) => assert_eq!((pos.line, pos.col), (1, 4)),
+            other => panic!("{:?}", other),
+        }
+        let e = tokenize("a\nb \\", &config).unwrap_err();
+        assert_eq!(e, TokenizeError::DanglingEscape(e.pos().clone()));
+        assert_eq!(e.to_string(), "2:2: expected char after the escape, found end of input");
+        // a quote ending in an escaped closing char is still open
+        assert!(tokenize("\"a\\\"", &config).is_err());
+        assert_eq!(tokenize("'a\\\\' \"b\"", &config).unwrap().len(), 2);
+
+        // the tokens before the open construct are still passed on
+        let mut tokens = Vec::new();
+        let result = {
+            let mut t = Tokenizer::with_config(LexerConfig::grammar(), |t| tokens.push(t));
+            for ch in "S : 'a' /* b".chars() {
+                t.push(ch);
+            }
+            t.finish()
+        };
+        assert_eq!(result.unwrap_err().to_string(), "1:8: expected */ ending the comment, found end of input");
+        assert_eq!(tokens.len(), 3);
+
+        // caught before the rules are parsed
+        let errors = load_grammar_str("S : 'a' | 'b ;\nT : x ;").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "1:10: expected closing ' of the quote, found end of input");
+        let errors = load_grammar_str("S : [a-z ;").unwrap_err();
+        assert_eq!(errors[0].expected, "closing ] of the quote");
+        let errors = load_grammar_str("S : 'a' \\").unwrap_err();
+        assert_eq!(errors[0].to_string(), "1:8: expected char after the escape, found end of input");
+    }
+
+    #[test]
+    fn escape_test() {
+        assert_eq!(unescape(r#"a\n\t\\\'\"\u{e9}\u{1F600}\;"#, '\\').unwrap(), "a\n\t\\'\"\u{e9}\u{1f600};");
+        let e = unescape(r"ab\u{zz}c", '\\').unwrap_err();
+        assert_eq!((e.offset, e.sequence.as_str()), (2, r"\u{zz}"));
+        assert_eq!(e.to_string(), r"invalid escape sequence \u{zz}");
+        assert!(unescape(r"\u{110000}", '\\').is_err());
+        assert!(unescape(r"\u41", '\\').is_err());
+
+        // token values
+        let config = LexerConfig::new();
+        let input = r#"'it\'s' "a\"b\n" \; x"#;
+        let values : Vec<String> = tokenize(input, &config).unwrap().iter()
+            .map(|t| t.value(input, &config).unwrap()).collect();
+        assert_eq!(values, ["it's", "a\"b\n", ";", "x"]);
+        let input = r#"'\u{}'"#;
+        let tokens = tokenize(input, &config).unwrap();
+        assert_eq!(tokens[0].value(input, &config).unwrap_err().offset, 1);
+
+        // grammar literals in both quote styles are the same terminal
+        let g = load_grammar_str(r#"S : 'it\'s' "\u{e9}" '\n' | "it's" 'é' "\n" ;"#).unwrap();
+        let printed = g.to_string();
+        assert!(printed.contains(r"'it\'s' 'é' '\n'"));
+        assert_eq!(load_grammar_str(&printed).unwrap().to_string(), printed);
+        let c = compile(&g).unwrap();
+        let tokens = ["it's", "\u{e9}", "\n"];
+        assert_eq!(run_input("S", &c, &tokens[..], tokens.len()).unwrap().count(), 2);
+        let g = load_grammar_str("%left '\\\\' \"\\t\"\nS : S '\\\\' S | 'a' ;").unwrap();
+        assert!(g.terminal_precedence("\\").is_some() && g.terminal_precedence("\t").is_some());
+
+        let errors = load_grammar_str("S : 'a' 'b\\u{d800}' ;").unwrap_err();
+        assert_eq!(errors[0].to_string(), "1:10: expected escape sequence, found '\\u{d800}'");
+        let g = load_yacc_str("%%\ns : 'a' '\\n' ;\n").unwrap();
+        assert!(g.to_string().contains("'a' '\\n'"));
     }
 
 }
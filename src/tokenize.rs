@@ -1,206 +1,657 @@
-#[derive(PartialEq)]
-enum Category {
-    Whitespace,
-    Delimiter,
-    Character,
-    Numeric,
-}
+use std::collections::VecDeque;
+use std::fmt;
 
+/**
+ * Position in the input
+ *
+ * pos is a byte offset, so &input[beg.pos..end.pos] is the text of a
+ * token. col counts chars; editors and LSP clients that count UTF-16
+ * code units use utf16_col.
+ */
 #[derive(Clone, PartialEq, Debug)]
 pub struct Position {
     pub line : usize, // line number
-    pub col : usize,  // column number
-    pub pos : usize,  // position in input string for indexing
+    pub col : usize,  // column number in chars
+    pub pos : usize,  // byte offset in the input string for indexing
+    pub char_pos : usize,  // offset in chars
+    pub utf16_col : usize, // column number in UTF-16 code units
 }
 
 impl Position {
 
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Position {
-        Position { line: 1, col: 0, pos: 0 }
+        Position { line: 1, col: 0, pos: 0, char_pos: 0, utf16_col: 0 }
     }
 
     pub fn update(&mut self, ch : char) {
         if ch == '\n' {
             self.line += 1;
             self.col = 0;
+            self.utf16_col = 0;
         } else {
             self.col += 1;
+            self.utf16_col += ch.len_utf16();
         }
-        // increment position in input string
-        self.pos += 1;
+        // advance the offsets in the input string
+        self.pos += ch.len_utf8();
+        self.char_pos += 1;
     }
 }
 
-#[derive(Clone,PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TokenKind {
+    // run of word chars
+    Word,
+    // run of digits, with a fraction and exponent if configured
+    Number,
+    // quoted text, including the quotes
+    Quoted,
+    // single delimiter char or an operator
+    Delimiter,
+    // escape char and the escaped char, outside quotes
+    Escape,
+    // skipped comment, see Tokenizer::comments
+    Comment,
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct Token {
     pub beg : Position,
     pub end : Position,
+    pub kind : TokenKind,
+}
+
+impl Token {
+    // text of the token in the input it was read from
+    pub fn text<'a>(&self, input : &'a str) -> &'a str {
+        &input[self.beg.pos..self.end.pos]
+    }
+
+    /**
+     * Text of the token with the escapes decoded (see unescape),
+     * quoted tokens without their quotes
+     */
+    pub fn value(&self, input : &str, config : &LexerConfig) -> Result<String, EscapeError> {
+        let text = self.text(input);
+        let escape = match config.escape {
+            Some(c) => c,
+            None => return Ok(text.to_string()),
+        };
+        match self.kind {
+            TokenKind::Quoted => {
+                let mut chars = text.chars();
+                let opening = chars.next().unwrap();
+                let closing = config.quotes.iter().find(|q| q.0 == opening).map(|q| q.1);
+                let inner = match (closing, text.chars().last()) {
+                    (Some(c), Some(last)) if last == c && text.len() > opening.len_utf8() => {
+                        &text[opening.len_utf8()..text.len() - c.len_utf8()]
+                    }
+                    _ => &text[opening.len_utf8()..],
+                };
+                unescape(inner, escape).map_err(|e| EscapeError { offset : e.offset + opening.len_utf8(), ..e })
+            }
+            TokenKind::Escape => unescape(text, escape),
+            _ => Ok(text.to_string()),
+        }
+    }
+}
+
+// malformed escape sequence, offset in bytes of the escape char
+#[derive(Clone, PartialEq, Debug)]
+pub struct EscapeError {
+    pub offset : usize,
+    pub sequence : String,
 }
 
+impl fmt::Display for EscapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid escape sequence {}", self.sequence)
+    }
+}
+
+/**
+ * Decode the escape sequences of text
+ *
+ *   \n \t \r \0     newline, tab, carriage return, NUL
+ *   \u{1F600}       the char with the hex code (1 to 6 digits)
+ *
+ * Any other escaped char stands for itself (\\ \' \"), an escape
+ * char at the end stands for itself too.
+ */
+pub fn unescape(text : &str, escape : char) -> Result<String, EscapeError> {
+    let mut value = String::with_capacity(text.len());
+    let mut chars = text.char_indices().peekable();
+    while let Some((offset, ch)) = chars.next() {
+        if ch != escape {
+            value.push(ch);
+            continue;
+        }
+        let next = match chars.next() {
+            Some((_, c)) => c,
+            None => {
+                value.push(ch);
+                break;
+            }
+        };
+        match next {
+            'n' => value.push('\n'),
+            't' => value.push('\t'),
+            'r' => value.push('\r'),
+            '0' => value.push('\0'),
+            'u' => {
+                // \u{...}, the error shows the sequence up to '}'
+                let mut sequence = format!("{}u", escape);
+                let mut code = None;
+                if chars.peek().is_some_and(|&(_, c)| c == '{') {
+                    let mut digits = String::new();
+                    for (_, c) in chars.by_ref() {
+                        sequence.push(c);
+                        if c == '}' {
+                            break;
+                        }
+                        if c != '{' {
+                            digits.push(c);
+                        }
+                    }
+                    if sequence.ends_with('}') && !digits.is_empty() && digits.len() <= 6 {
+                        code = u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32);
+                    }
+                }
+                match code {
+                    Some(c) => value.push(c),
+                    None => return Err(EscapeError { offset, sequence }),
+                }
+            }
+            c => value.push(c),
+        }
+    }
+    Ok(value)
+}
+
+/**
+ * Constructs left open at the end of the input, with the position
+ * where they start
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub enum TokenizeError {
+    // quoted token without its closing char
+    UnterminatedQuote(Position, char),
+    // escape char as the last char of the input
+    DanglingEscape(Position),
+    // block comment without its end marker
+    UnterminatedComment(Position, String),
+}
+
+impl TokenizeError {
+    pub fn pos(&self) -> &Position {
+        match *self {
+            TokenizeError::UnterminatedQuote(ref pos, _) => pos,
+            TokenizeError::DanglingEscape(ref pos) => pos,
+            TokenizeError::UnterminatedComment(ref pos, _) => pos,
+        }
+    }
+
+    // what the input lacks before its end
+    pub fn expected(&self) -> String {
+        match *self {
+            TokenizeError::UnterminatedQuote(_, closing) => format!("closing {} of the quote", closing),
+            TokenizeError::DanglingEscape(_) => "char after the escape".to_string(),
+            TokenizeError::UnterminatedComment(_, ref end) => format!("{} ending the comment", end),
+        }
+    }
+}
+
+// line:col: expected X, found end of input
+impl fmt::Display for TokenizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pos = self.pos();
+        write!(f, "{}:{}: expected {}, found end of input", pos.line, pos.col, self.expected())
+    }
+}
+
+/**
+ * Which chars form which tokens
+ *
+ * Whitespace separates tokens. Comments and quotes are recognized
+ * first, then delimiters and operators (the longest operator wins),
+ * numbers and words. Chars that are none of these are delimiters.
+ */
+#[derive(Clone)]
+pub struct LexerConfig {
+    // chars that are tokens of their own
+    pub delimiters : Vec<char>,
+    // sequences of chars that are one delimiter token ("->", "::=", "<=")
+    pub operators : Vec<String>,
+    // chars of words
+    pub word_char : fn(char) -> bool,
+    // digits continue a word (x1 is one token, not x and 1)
+    pub word_digits : bool,
+    // 1.5 is one number
+    pub fractions : bool,
+    // 1e5 and 2.5E-3 are one number
+    pub exponents : bool,
+    // opening and closing char of quoted tokens
+    pub quotes : Vec<(char, char)>,
+    // char that escapes the next char, inside and outside quotes
+    pub escape : Option<char>,
+    // markers that start comments to the end of the line
+    pub line_comments : Vec<String>,
+    // start and end markers of block comments
+    pub block_comments : Vec<(String, String)>,
+}
+
+impl Default for LexerConfig {
+    fn default() -> LexerConfig {
+        LexerConfig::new()
+    }
+}
+
+impl LexerConfig {
+
+    /**
+     * Words of letters, digits and '_', numbers with fractions and
+     * exponents, '"' and '\'' quotes and '\\' escapes, no comments;
+     * every other char is a delimiter
+     */
+    pub fn new() -> LexerConfig {
+        LexerConfig {
+            delimiters : Vec::new(),
+            operators : Vec::new(),
+            word_char : |c| c.is_alphanumeric() || c == '_',
+            word_digits : true,
+            fractions : true,
+            exponents : true,
+            quotes : vec![('"', '"'), ('\'', '\'')],
+            escape : Some('\\'),
+            line_comments : Vec::new(),
+            block_comments : Vec::new(),
+        }
+    }
+
+    /**
+     * The grammar language: '...', "...", /regex/ and [class] are
+     * quoted, #, // and /* */ are comments and digits are numbers
+     * of their own
+     */
+    pub fn grammar() -> LexerConfig {
+        LexerConfig {
+            delimiters : "()|:?*+,->;<`&!".chars().collect(),
+            operators : Vec::new(),
+            word_char : |c| !c.is_whitespace(),
+            word_digits : false,
+            fractions : false,
+            exponents : false,
+            quotes : vec![('\'', '\''), ('"', '"'), ('/', '/'), ('[', ']')],
+            escape : Some('\\'),
+            line_comments : vec!["#".to_string(), "//".to_string()],
+            block_comments : vec![("/*".to_string(), "*/".to_string())],
+        }
+    }
+
+    // chars needed to decide on the first one
+    fn lookahead(&self) -> usize {
+        let markers = self.operators.iter()
+            .chain(self.line_comments.iter())
+            .chain(self.block_comments.iter().map(|b| &b.1))
+            .chain(self.block_comments.iter().map(|b| &b.0));
+        // 1e-5 needs three chars to tell a number from 1 e - 5
+        markers.map(|m| m.chars().count()).fold(3, usize::max)
+    }
+}
+
+enum State {
+    Start,
+    Word,
+    Number { fraction : bool, exponent : bool },
+    // inside a quoted token, the char that ends it
+    Quoted(char),
+    LineComment,
+    // inside a block comment, the end marker
+    BlockComment(String),
+}
+
+#[allow(clippy::unused_unit)]
 pub struct Tokenizer<F> where F : FnMut(Token) -> () {
     callback : F,
 
-    // non zero if inside a quoted string
-    // quoting contains the char that started the quote
-    quoting : char,
+    config : LexerConfig,
+
+    // chars pushed but not read yet, the lexer looks at up to
+    // 'lookahead' of them before deciding on the first one
+    pending : VecDeque<char>,
+    lookahead : usize,
 
-    // true if escaping the next char
-    escaping: bool,
+    state : State,
 
-    // previous char
-    prev : char,
+    // ranges of the skipped comments
+    comments : Vec<Token>,
 
-    // current position in the string
+    // position of the first pending char
     pos : Position,
 
     // current token begin position
     beg : Position,
+
+    // first construct found open at the end of the input
+    error : Option<TokenizeError>,
 }
 
+#[allow(clippy::unused_unit)]
 impl<F> Tokenizer<F> where F : FnMut(Token) -> () {
 
+    // tokenizer with the default configuration (see LexerConfig::new,
+    // LexerConfig::grammar is the grammar language)
     pub fn new(callback : F) -> Tokenizer<F> {
+        Tokenizer::with_config(LexerConfig::new(), callback)
+    }
+
+    pub fn with_config(config : LexerConfig, callback : F) -> Tokenizer<F> {
         Tokenizer {
-            quoting: '\0',
-            escaping: false,
-            prev: '\0',
-            callback: callback,
+            callback,
+            lookahead : config.lookahead(),
+            config,
+            pending : VecDeque::new(),
+            state : State::Start,
+            comments : Vec::new(),
             beg : Position::new(),
             pos : Position::new(),
+            error : None,
         }
     }
 
-    fn char_category(ch : char) -> Category {
-        match ch {
-            '0' | '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9' => Category::Numeric,
-            '(' | ')' | '|' | ':' | '-' | '>' | '<' | ';' | '`' | '"' | '\'' | '\\' => Category::Delimiter,
-            ' ' | '\t' | '\n' | '\r' => Category::Whitespace,
-            _ => Category::Character,
+    /**
+     * Comments skipped so far, the ranges include the comment markers
+     */
+    pub fn comments(&self) -> &Vec<Token> {
+        &self.comments
+    }
+
+    // true if the pending chars start with s
+    fn lookahead_is(&self, s : &str) -> bool {
+        let mut n = 0;
+        for ch in s.chars() {
+            if self.pending.get(n) != Some(&ch) {
+                return false;
+            }
+            n += 1;
         }
+        n > 0
     }
 
-    /**
-     * Add character to current token
-     *
-     * Increments the current position
-     * Saves the char as the previous char
-     */
-    fn add_char(&mut self, ch : char) {
-        self.pos.update(ch);
-        self.prev = ch;
+    fn is_digit(&self, n : usize) -> bool {
+        self.pending.get(n).is_some_and(|c| c.is_ascii_digit())
     }
 
-    fn flush(&mut self) {
-        // do not flush if prev category was a whitespace
-        // or if token is empty (zero sized)
-        if Tokenizer::<F>::char_category(self.prev) != Category::Whitespace
-            && self.beg.pos != self.pos.pos {
+    // move n pending chars into the current token
+    fn consume(&mut self, n : usize) {
+        for _ in 0..n {
+            let ch = self.pending.pop_front().unwrap();
+            self.pos.update(ch);
+        }
+    }
+
+    // pass the current token (if not empty) to the callback
+    fn emit(&mut self, kind : TokenKind) {
+        if self.beg.pos != self.pos.pos {
             let t = Token {
                 beg : self.beg.clone(),
                 end : self.pos.clone(),
+                kind,
             };
-            let ref mut x = self.callback;
-            x(t);
+            (self.callback)(t);
         }
+        self.beg = self.pos.clone();
+        self.state = State::Start;
     }
 
-    /**
-     * Push char, do not start quoting
-     * Flush if necessary
-     */
-    fn maybe_start_token(&mut self, ch : char) {
-        let char_category = Tokenizer::<F>::char_category(ch);
-        let prev_category = Tokenizer::<F>::char_category(self.prev);
-
-        match char_category {
-            Category::Whitespace => {
-                if prev_category != Category::Whitespace {
-                    // whitespace, flush token if prev char was not a whitespace
-                    self.flush();
-                    self.beg = self.pos.clone();
-                }
+    // end a word or number
+    fn flush(&mut self) {
+        match self.state {
+            State::Word => self.emit(TokenKind::Word),
+            State::Number { .. } => self.emit(TokenKind::Number),
+            _ => self.beg = self.pos.clone(),
+        }
+    }
+
+    fn end_comment(&mut self) {
+        self.comments.push(Token {
+            beg : self.beg.clone(),
+            end : self.pos.clone(),
+            kind : TokenKind::Comment,
+        });
+        self.beg = self.pos.clone();
+        self.state = State::Start;
+    }
+
+    // start a comment if one starts at the first pending char
+    fn start_comment(&mut self) -> bool {
+        let line = self.config.line_comments.iter().find(|m| self.lookahead_is(m)).cloned();
+        if let Some(marker) = line {
+            self.flush();
+            self.consume(marker.chars().count());
+            self.state = State::LineComment;
+            return true;
+        }
+        let block = self.config.block_comments.iter().find(|m| self.lookahead_is(&m.0)).cloned();
+        if let Some((start, end)) = block {
+            self.flush();
+            self.consume(start.chars().count());
+            self.state = State::BlockComment(end);
+            return true;
+        }
+        false
+    }
+
+    // length of the longest operator at the first pending char
+    fn operator_len(&self) -> usize {
+        self.config.operators.iter()
+            .filter(|op| self.lookahead_is(op))
+            .map(|op| op.chars().count())
+            .max()
+            .unwrap_or(1)
+    }
+
+    // chars a number continues with at the first pending char, 0 if it ends
+    fn number_continues(&self, fraction : bool, exponent : bool) -> usize {
+        let ch = self.pending[0];
+        let point = ch == '.' && self.config.fractions && !fraction && !exponent && self.is_digit(1);
+        if ch.is_ascii_digit() || point {
+            1
+        } else if (ch == 'e' || ch == 'E') && self.config.exponents && !exponent {
+            match self.pending.get(1) {
+                Some(&'+') | Some(&'-') if self.is_digit(2) => 2,
+                _ if self.is_digit(1) => 1,
+                _ => 0,
             }
-            Category::Delimiter => {
-                // delimiter never continues, even if prev char was a delimiter
-                // skip flush if prev category was Whitespace
-                self.flush();
-                self.beg = self.pos.clone();
+        } else {
+            0
+        }
+    }
+
+    // read the first pending char (and the ones that go with it)
+    fn step(&mut self) {
+        let ch = self.pending[0];
+        match self.state {
+            State::LineComment => {
+                if ch == '\n' {
+                    // the newline is not part of the comment
+                    self.end_comment();
+                } else {
+                    self.consume(1);
+                }
+                return;
             }
-            Category::Character => {
-                if prev_category != Category::Character {
-                    self.flush();
-                    self.beg = self.pos.clone();
+            State::BlockComment(ref end) => {
+                if self.lookahead_is(end) {
+                    let n = end.chars().count();
+                    self.consume(n);
+                    self.end_comment();
+                } else {
+                    self.consume(1);
                 }
+                return;
             }
-            Category::Numeric => {
-                if prev_category != Category::Numeric {
-                    self.flush();
-                    self.beg = self.pos.clone();
+            State::Quoted(closing) => {
+                if Some(ch) == self.config.escape && self.pending.len() > 1 {
+                    // the escaped char does not end the quote
+                    self.consume(2);
+                } else {
+                    self.consume(1);
+                    if ch == closing {
+                        self.emit(TokenKind::Quoted);
+                    }
                 }
+                return;
             }
+            _ => {}
+        }
+
+        if self.start_comment() {
+            return;
         }
-    }
 
-    fn push_normal(&mut self, ch : char) {
-        assert!(self.quoting == '\0');
-        if ch == '\'' || ch == '"' {
-            // flush any previous token since quoting is starting
-            // a"b" -> two tokens: a and "b"
+        // escape changes only the interpretation of the next char
+        // (quotes do not start quoting), the token includes the
+        // escape char (see Token::value)
+        if Some(ch) == self.config.escape {
             self.flush();
-            // token should include the starting quote
+            if self.pending.len() == 1 {
+                // only at the end, push keeps the lookahead pending
+                self.error.get_or_insert(TokenizeError::DanglingEscape(self.pos.clone()));
+                self.consume(1);
+                self.beg = self.pos.clone();
+                return;
+            }
+            self.consume(2);
+            self.emit(TokenKind::Escape);
+            return;
+        }
+
+        let quote = self.config.quotes.iter().find(|q| q.0 == ch).map(|q| q.1);
+        if let Some(closing) = quote {
+            // the token includes the quotes
+            self.flush();
+            self.consume(1);
+            self.state = State::Quoted(closing);
+            return;
+        }
+
+        if ch.is_whitespace() {
+            self.flush();
+            self.consume(1);
             self.beg = self.pos.clone();
-            self.add_char(ch);
-            // remember the char type that started the quoting
-            self.quoting = ch;
+            return;
+        }
+
+        let delimiter = self.config.delimiters.contains(&ch);
+        match self.state {
+            State::Word => {
+                let word = (self.config.word_char)(ch) && !ch.is_ascii_digit()
+                    || ch.is_ascii_digit() && self.config.word_digits;
+                if word && !delimiter {
+                    self.consume(1);
+                    return;
+                }
+                self.flush();
+            }
+            State::Number { fraction, exponent } => {
+                let n = self.number_continues(fraction, exponent);
+                if n > 0 {
+                    self.state = State::Number {
+                        fraction : fraction || ch == '.',
+                        exponent : exponent || ch == 'e' || ch == 'E',
+                    };
+                    self.consume(n);
+                    return;
+                }
+                self.flush();
+            }
+            _ => {}
+        }
+
+        if delimiter || self.config.operators.iter().any(|op| self.lookahead_is(op)) {
+            let n = self.operator_len();
+            self.consume(n);
+            self.emit(TokenKind::Delimiter);
+        } else if ch.is_ascii_digit() {
+            self.state = State::Number { fraction : false, exponent : false };
+            self.consume(1);
+        } else if (self.config.word_char)(ch) {
+            self.state = State::Word;
+            self.consume(1);
         } else {
-            self.maybe_start_token(ch);
-            self.add_char(ch);
+            self.consume(1);
+            self.emit(TokenKind::Delimiter);
         }
     }
 
     /**
      * Push a character into the tokenizer
+     *
+     * Tokens are passed to the callback once the chars after them
+     * are known. Comments are skipped, see comments()
      */
     pub fn push(&mut self, ch : char) {
+        self.pending.push_back(ch);
+        while self.pending.len() >= self.lookahead {
+            self.step();
+        }
+    }
 
-        // escape changes only the interpretation
-        // of the next char (quotes do not start quoting)
-        //
-        // output token positions will still include ranges with the escape
-        // chars, to be consistent with escaping inside quotes
-        //
-        // to remove escape chars, the output needs to be post-processed
-        // above the tokenizer level
-        if self.escaping {
-            // current char is escaped
-            // (quoting is ignored)
-            self.maybe_start_token(ch);
-            self.add_char('\\');
-            self.add_char(ch);
-            self.escaping = false;
-        } else if ch == '\\' {
-            // do not update position yet
-            // do not update self.prev to not break the next push_noquote() call
-            // next char will be escaped
-            self.escaping = true;
-        } else {
-
-            if self.quoting != '\0' {
-                // the token range will include the ending quote
-                self.add_char(ch);
-
-                if ch == self.quoting {
-                    self.flush();
-                    self.beg = self.pos.clone();
-                    self.quoting = '\0';
-                }
-            } else {
-                self.push_normal(ch);
+    /**
+     * Signal the end of the input, the last token is passed to the
+     * callback
+     *
+     * An unterminated quote or block comment or a trailing escape
+     * char is an error at the position where it starts; the text
+     * from there on is not a token.
+     */
+    pub fn finish(&mut self) -> Result<(), TokenizeError> {
+        while !self.pending.is_empty() {
+            self.step();
+        }
+        let open = match self.state {
+            State::Quoted(closing) => Some(TokenizeError::UnterminatedQuote(self.beg.clone(), closing)),
+            State::BlockComment(ref end) => Some(TokenizeError::UnterminatedComment(self.beg.clone(), end.clone())),
+            State::LineComment => {
+                self.end_comment();
+                None
+            }
+            _ => {
+                self.flush();
+                None
             }
+        };
+        if let Some(e) = open {
+            self.error.get_or_insert(e);
+            self.beg = self.pos.clone();
+            self.state = State::Start;
+        }
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
         }
     }
+}
 
-    pub fn finish(&mut self) {
-        self.flush();
+/**
+ * Tokens of a string, or the error of Tokenizer::finish
+ *
+ *   let input = "x -> y1 <= 2.5";
+ *   let mut config = LexerConfig::new();
+ *   config.operators = vec!["->".to_string(), "<=".to_string()];
+ *   let texts : Vec<&str> = tokenize(input, &config).unwrap().iter().map(|t| t.text(input)).collect();
+ *   // ["x", "->", "y1", "<=", "2.5"]
+ */
+pub fn tokenize(input : &str, config : &LexerConfig) -> Result<Vec<Token>, TokenizeError> {
+    let mut tokens = Vec::new();
+    {
+        let mut t = Tokenizer::with_config(config.clone(), |t| tokens.push(t));
+        for ch in input.chars() {
+            t.push(ch);
+        }
+        t.finish()?;
     }
+    Ok(tokens)
 }
@@ -1,6 +1,12 @@
-use std::usize;
 use std::env;
-use compiler::{CompiledGrammar, Opcode};
+use std::fmt;
+use std::error::Error;
+use std::collections::{HashMap, HashSet};
+use compiler::{CompiledGrammar, EntryPoint, Opcode};
+use grammar::Assoc;
+use normalize::{fold_case, nfc};
+use matchers::ClassFn;
+use lexer::{Lexeme, LexError};
 
 struct SharedStackItem<U> {
     u: U,
@@ -19,6 +25,7 @@ impl<U> SharedStack<U> {
     }
 
     // returns new sp
+    #[allow(clippy::redundant_field_names)]
     fn push(&mut self, sp: usize, u: U) -> usize {
         let si = SharedStackItem {
             u: u,
@@ -48,6 +55,7 @@ impl<U> SharedStack<U> {
 //  Q. ...
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum FragmentType {
     // RuleStart without parent is the top node
     RuleStart {
@@ -64,6 +72,9 @@ pub enum FragmentType {
         child : usize,
         ntnameidx: usize,
         ev_name : Option<usize>,
+        retaddr : usize, // address of the production's Return
+        start : usize, // RuleStart of the child
+        span : (usize, usize), // tokens of the child, first and end
     },
 }
 
@@ -74,7 +85,8 @@ pub struct ParseFragment {
 }
 
 #[inline]
-fn prev_fragment(fragments: &Vec<ParseFragment>, fragidx: usize, default: usize) -> usize {
+#[allow(clippy::match_ref_pats)]
+fn prev_fragment(fragments: &[ParseFragment], fragidx: usize, default: usize) -> usize {
     match &fragments[fragidx].value {
         &FragmentType::RuleStart { parent, .. } => {
             match parent {
@@ -87,6 +99,80 @@ fn prev_fragment(fragments: &Vec<ParseFragment>, fragidx: usize, default: usize)
     }
 }
 
+/**
+ * Token input for the VM
+ *
+ * matches - true if the literal terminal 'value' matches token tokidx
+ * text    - text of token tokidx, used by regex terminals and by
+ *           literals compared case folded or normalized (regex
+ *           terminals never match if it returns None, the literals
+ *           are then passed to matches folded and normalized)
+ * lexeme  - the token as the lexer of the grammar found it (input of
+ *           parse_str): literals only match tokens lexed as literals,
+ *           <NAME> terminals of %token declarations compare the name
+ * token_count - number of tokens if known, productions that need
+ *           more tokens than are left are not tried (without it left
+ *           recursion takes the tokens up to the first one no literal
+ *           terminal matches, see run)
+ *
+ * Implemented for closures Fn(&str, usize) -> bool (see run) and
+ * for slices of strings (see run_input)
+ */
+pub trait Input {
+    fn matches(&self, value: &str, tokidx: usize) -> bool;
+
+    fn text(&self, _tokidx: usize) -> Option<&str> {
+        None
+    }
+
+    fn lexeme(&self, _tokidx: usize) -> Option<&Lexeme> {
+        None
+    }
+
+    fn token_count(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl<F> Input for F where F : Fn(&str, usize) -> bool {
+    fn matches(&self, value: &str, tokidx: usize) -> bool {
+        self(value, tokidx)
+    }
+}
+
+impl<S> Input for [S] where S : AsRef<str> {
+    fn matches(&self, value: &str, tokidx: usize) -> bool {
+        tokidx < self.len() && self[tokidx].as_ref() == value
+    }
+
+    fn text(&self, tokidx: usize) -> Option<&str> {
+        self.get(tokidx).map(|t| t.as_ref())
+    }
+
+    fn token_count(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+impl Input for [Lexeme] {
+    fn matches(&self, value: &str, tokidx: usize) -> bool {
+        self.get(tokidx).is_some_and(|l| l.token.is_none() && l.text == value)
+    }
+
+    fn text(&self, tokidx: usize) -> Option<&str> {
+        self.get(tokidx).map(|l| l.text.as_str())
+    }
+
+    fn lexeme(&self, tokidx: usize) -> Option<&Lexeme> {
+        self.get(tokidx)
+    }
+
+    fn token_count(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+#[allow(clippy::ptr_arg)]
 pub trait StreamingHandler {
     fn start(&mut self, ntname: &String, name: &Option<&String>);
     fn end(&mut self, ntname: &String, xname: &Option<&String>);
@@ -104,20 +190,25 @@ pub struct ParsedTrees {
     tails : Vec<(usize, usize)>,
     // string table
     strings: Vec<String>,
+    // string indexes of nonterminals not reported to the handler
+    hidden: HashSet<usize>,
 }
 
 impl ParsedTrees {
 
+    #[allow(clippy::redundant_field_names)]
     pub fn new(
         fragments : Vec<ParseFragment>,
         tails : Vec<(usize, usize)>,
-        strings: Vec<String>
+        strings: Vec<String>,
+        hidden: HashSet<usize>
     ) -> ParsedTrees {
 
         ParsedTrees {
             fragments : fragments,
             tails: tails,
             strings: strings,
+            hidden,
         }
     }
 
@@ -147,6 +238,7 @@ impl ParsedTrees {
      * fidx: fragment index
      * returns: previous index into fragments
      */
+    #[allow(clippy::ptr_arg, clippy::match_ref_pats)]
     fn stream<U: StreamingHandler>(
         &self,
         indexes: &Vec<usize>,
@@ -169,18 +261,22 @@ impl ParsedTrees {
                         // RuleStart
                         // current node is the child of parent
                         &FragmentType::RuleStart { ntname, name, .. } => {
-                            let name_string = name.map(|x| &self.strings[x]);
-                            let ntname_string = &self.strings[ntname];
-                            handler.start(ntname_string, &name_string);
+                            if !self.hidden.contains(&ntname) {
+                                let name_string = name.map(|x| &self.strings[x]);
+                                let ntname_string = &self.strings[ntname];
+                                handler.start(ntname_string, &name_string);
+                            }
                         },
                         &FragmentType::RuleTermValue { tokidx, name, .. } => {
                             let name_string = name.map(|x| &self.strings[x]);
                             handler.term(tokidx, &name_string);
                         },
                         &FragmentType::RuleNonTerm { ev_name, ntnameidx, .. } => {
-                            let ntname_string = &self.strings[ntnameidx];
-                            let evname = ev_name.map(|x| &self.strings[x]);
-                            handler.end(ntname_string, &evname);
+                            if !self.hidden.contains(&ntnameidx) {
+                                let ntname_string = &self.strings[ntnameidx];
+                                let evname = ev_name.map(|x| &self.strings[x]);
+                                handler.end(ntname_string, &evname);
+                            }
                         },
                     }
                 }
@@ -223,13 +319,288 @@ struct VMThread {
     fragidx : usize,
 }
 
+// release the fragments of a terminated thread
+fn release(fragments: &mut [ParseFragment], freelist: &mut Vec<usize>, fragidx: usize) {
+    let mut fragidx = fragidx;
+    while fragidx != usize::MAX {
+        assert!(fragments[fragidx].refcount > 0);
+        fragments[fragidx].refcount -= 1;
+        if fragments[fragidx].refcount == 0 {
+            // maintain a sorted freelist
+            match freelist.binary_search(&fragidx) {
+                Ok(pos) => freelist.insert(pos, fragidx),
+                Err(pos) => freelist.insert(pos, fragidx),
+            }
+            // continue to the prev element
+            fragidx = prev_fragment(fragments, fragidx, usize::MAX);
+        } else {
+            // element (and all his prev elements) not garbage
+            // collectable due to rc > 0
+            break;
+        }
+    }
+}
+
+// fragment before the child node starting with RuleStart 'start'
+fn previous_sibling(fragments: &[ParseFragment], start: usize) -> usize {
+    match fragments[start].value {
+        FragmentType::RuleStart { parent, .. } => parent.unwrap(),
+        _ => unreachable!(),
+    }
+}
+
+//
+// true if the node ending with fragment 'fragidx' has a descendant
+// nonterminal ntidx over the same tokens 'span', through children
+// that all span them: a cycle like E -> E, repeatable forever
+//
+fn same_span_cycle(fragments: &[ParseFragment], fragidx: usize, ntidx: usize, span: (usize, usize)) -> bool {
+    let mut curr = fragidx;
+    loop {
+        match fragments[curr].value {
+            FragmentType::RuleStart { .. } => return false,
+            FragmentType::RuleTermValue { prev, .. } => curr = prev,
+            FragmentType::RuleNonTerm { child, ntnameidx, start, span: s, .. } => {
+                if s == span && (ntnameidx == ntidx || same_span_cycle(fragments, child, ntidx, span)) {
+                    return true;
+                }
+                curr = previous_sibling(fragments, start);
+            }
+        }
+    }
+}
+
+// precedence of a child node, None for terminals
+fn child_precedence(cg: &CompiledGrammar, fragments: &[ParseFragment], fragidx: usize) -> Option<(usize, Assoc)> {
+    match fragments[fragidx].value {
+        FragmentType::RuleNonTerm { retaddr, .. } => cg.precedence(retaddr),
+        _ => None,
+    }
+}
+
+//
+// true if the node returning at 'retaddr' (last fragment 'fragidx')
+// has a first or last child that the precedence declarations
+// group the other way
+//
+fn precedence_conflict(cg: &CompiledGrammar, fragments: &[ParseFragment], fragidx: usize, retaddr: usize) -> bool {
+    let (level, assoc) = match cg.precedence(retaddr) {
+        Some(p) => p,
+        None => return false,
+    };
+    // the children from the last to the first
+    let mut children = Vec::new();
+    let mut curr = fragidx;
+    loop {
+        match fragments[curr].value {
+            FragmentType::RuleStart { .. } => break,
+            FragmentType::RuleTermValue { prev, .. } => {
+                children.push(curr);
+                curr = prev;
+            }
+            FragmentType::RuleNonTerm { start, .. } => {
+                children.push(curr);
+                curr = previous_sibling(fragments, start);
+            }
+        }
+    }
+    if children.len() < 2 {
+        return false;
+    }
+    if let Some((l, _)) = child_precedence(cg, fragments, children[children.len() - 1]) {
+        if l < level || (l == level && assoc != Assoc::Left) {
+            return true;
+        }
+    }
+    if let Some((l, _)) = child_precedence(cg, fragments, children[0]) {
+        if l < level || (l == level && assoc != Assoc::Right) {
+            return true;
+        }
+    }
+    false
+}
+
+// true if an opcode adds a child to the parse tree
+fn adds_child(op : Opcode) -> bool {
+    matches!(op, Opcode::Fork { .. } | Opcode::Match { .. } | Opcode::MatchRegex { .. } | Opcode::MatchClass { .. })
+}
+
+// whether the child added at 'ip' is the first and the last child
+// of its production (predicates add no children)
+fn child_position(cg : &CompiledGrammar, ip : usize) -> (bool, bool) {
+    let mut first = true;
+    let mut addr = ip;
+    while addr > 0 && !matches!(cg.at(addr - 1), Opcode::Return { .. }) {
+        addr -= 1;
+        if adds_child(cg.at(addr)) {
+            first = false;
+            break;
+        }
+    }
+    let mut last = true;
+    let mut addr = ip + 1;
+    while !matches!(cg.at(addr), Opcode::Return { .. }) {
+        if adds_child(cg.at(addr)) {
+            last = false;
+            break;
+        }
+        addr += 1;
+    }
+    (first, last)
+}
+
+// index into the match result cache for a Match, MatchRegex or
+// MatchClass opcode: literals use the string index, regexes follow the
+// string table, case folded literals follow the regexes and token
+// classes come last
+fn match_slot(cg : &CompiledGrammar, op : &Opcode) -> usize {
+    match *op {
+        Opcode::Match { validx, fold: false, .. } => validx,
+        Opcode::Match { validx, fold: true, .. } => cg.strings.len() + cg.regex_count() + validx,
+        Opcode::MatchRegex { reidx, .. } => cg.strings.len() + reidx,
+        Opcode::MatchClass { classidx, .. } => 2 * cg.strings.len() + cg.regex_count() + classidx,
+        _ => panic!("matchable not at Match instruction"),
+    }
+}
+
+//
+// true if the literal 'value' matches token tokidx
+//
+// Without normalization the input decides (match_fn). Otherwise the
+// normalized token text is compared with the normalized value, inputs
+// without token text get the normalized value.
+//
+fn match_literal<I>(cg : &CompiledGrammar, input : &I, value : &str, fold : bool, tokidx : usize) -> bool
+    where I : Input + ?Sized {
+    if input.lexeme(tokidx).is_some_and(|l| l.token.is_some()) {
+        return false;
+    }
+    let normalization = cg.normalization();
+    if !fold && !normalization.nfc {
+        return input.matches(value, tokidx);
+    }
+    let normalize = |s : &str| {
+        let s = if normalization.nfc { nfc(s) } else { s.to_string() };
+        if fold { fold_case(&s) } else { s }
+    };
+    match input.text(tokidx) {
+        Some(text) => normalize(text) == normalize(value),
+        None => input.matches(&normalize(value), tokidx),
+    }
+}
+
+//
+// number of tokens of an input without token_count: the index of the
+// first token from 'from' that no literal terminal matches
+//
+fn token_count<I>(cg : &CompiledGrammar, input : &I, from : usize) -> usize
+    where I : Input + ?Sized {
+    let literals = cg.literals();
+    let mut n = from;
+    while literals.iter().any(|&(validx, fold)| match_literal(cg, input, &cg.strings[validx], fold, n)) {
+        n += 1;
+    }
+    n
+}
+
+/**
+ * Reasons for not parsing at all
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub enum RunError {
+    // not a nonterminal parsing can start with (see
+    // CompiledGrammar::entry_point)
+    UnknownStart(String),
+    // the grammar has terminals that match the token text, which the
+    // input does not provide (see CompiledGrammar::needs_token_text)
+    NeedsTokenText,
+    // parse_str: text the lexer of the grammar does not match
+    Lex(LexError),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RunError::UnknownStart(ref name) => write!(f, "unknown start nonterminal {}", name),
+            RunError::NeedsTokenText => write!(f, "the grammar matches token text, the input has none"),
+            RunError::Lex(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for RunError {}
+
+fn start_entry(cg : &CompiledGrammar, nt_start : &str) -> Result<EntryPoint, RunError> {
+    cg.entry_point(nt_start).ok_or_else(|| RunError::UnknownStart(nt_start.to_string()))
+}
+
 //
-// tokens: tokenized input string
 // nt_start: nonterminal
 // cg: grammar to use
+// match_fn: returns true if the terminal (1st arg) matches the token at
+//           the index (2nd arg)
+//
+// the closure does not tell how many tokens there are: for left
+// recursion the tokens are counted up to the first index where
+// match_fn is false for every literal terminal of the grammar
+//
+// literals compared case folded or normalized ('select'i, %normalize)
+// are passed to match_fn as fold_case / nfc return them, match_fn
+// must fold or normalize its token the same way
 //
-pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: usize) -> ParsedTrees
+// fails if nt_start is not a start nonterminal of the grammar, or if
+// the grammar has /regex/ or <NAME> terminals: they match the token
+// text, use run_input with an Input that has it
+//
+pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: usize) -> Result<ParsedTrees, RunError>
     where F : Fn(&str, usize) -> bool {
+    if cg.needs_token_text() {
+        return Err(RunError::NeedsTokenText);
+    }
+    run_input(nt_start, cg, &match_fn, min_match)
+}
+
+//
+// Same as run, the tokens are provided by an Input implementation
+// (e.g. a slice of strings: run_input("S", &cg, &tokens[..], 0))
+//
+pub fn run_input<I>(nt_start : &str, cg : &CompiledGrammar, input: &I, min_match: usize) -> Result<ParsedTrees, RunError>
+    where I : Input + ?Sized {
+    let entry = start_entry(cg, nt_start)?;
+    Ok(run_nonterm(entry.index(), cg, input, min_match, 0, &[]))
+}
+
+//
+// Lex text with the %token and %skip declarations of the grammar
+// (see Lexer) and parse all of the tokens starting with nt_start
+//
+// returns the trees and the tokens they refer to by index; fails for
+// start nonterminals the grammar does not have (see
+// CompiledGrammar::entry_point) and with RunError::Lex for text the
+// lexer does not match
+//
+pub fn parse_str(cg : &CompiledGrammar, nt_start : &str, text : &str) -> Result<(ParsedTrees, Vec<Lexeme>), RunError> {
+    let entry = start_entry(cg, nt_start)?;
+    let lexemes = cg.lex(text).map_err(RunError::Lex)?;
+    let trees = run_entry(entry, cg, &lexemes[..], lexemes.len());
+    Ok((trees, lexemes))
+}
+
+//
+// Same as run_input, starting with an entry point of the grammar
+// (cg.entry_point("Document") checks the name once)
+//
+pub fn run_entry<I>(entry : EntryPoint, cg : &CompiledGrammar, input: &I, min_match: usize) -> ParsedTrees
+    where I : Input + ?Sized {
+    run_nonterm(entry.index(), cg, input, min_match, 0, &[])
+}
+
+// parse starting at token 'first' (predicates parse from the token
+// they are evaluated at); 'active' are the predicates being evaluated
+// by the callers as (nonterminal, token index)
+fn run_nonterm<I>(nt_start_idx : usize, cg : &CompiledGrammar, input: &I, min_match: usize, first: usize,
+                  active : &[(usize, usize)]) -> ParsedTrees
+    where I : Input + ?Sized {
 
     let debug_level = match env::var("PARSERDEBUG") {
         Ok(s) => {
@@ -246,10 +617,14 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
 
     // allocate enough space to store all possible
     // matches within one token
-    let mut matched = Vec::<isize>::with_capacity(cg.strings.len());
-    for _ in 0..cg.strings.len() {
-        matched.push(0);
-    }
+    let classes = cg.classes();
+    let slots = 2 * cg.strings.len() + cg.regex_count() + classes.len();
+    let mut matched = vec![0isize; slots];
+
+    // matcher functions by class index
+    let class_fns : Vec<Option<&ClassFn>> = classes.iter()
+        .map(|c| cg.matchers().and_then(|m| m.get(c)))
+        .collect();
 
     let mut fragments = Vec::<ParseFragment>::new();
 
@@ -266,14 +641,12 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
     // sorted by first
     let mut matchable : Vec<(usize, VMThread)> = Vec::new();
 
-    let nt_start_idx: Option<usize> = cg.lookup_string(nt_start);
-
-    for initial_thread_addr in cg.lookup_nonterm_idx(nt_start_idx.unwrap()) {
+    for initial_thread_addr in cg.lookup_nonterm_idx(nt_start_idx) {
         let frag = ParseFragment {
             refcount: 1,
             value: FragmentType::RuleStart {
                 parent: None,
-                ntname: nt_start_idx.unwrap(),
+                ntname: nt_start_idx,
                 name: None,
             }
         };
@@ -285,16 +658,24 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
         });
     }
 
-    let mut shared_stack = SharedStack::<usize>::new();
-    let mut tokidx = 0;
+    // return address, token index of each Fork and the least number
+    // of tokens the callers still have to match after the return
+    // and the RuleStart fragment of the called nonterminal
+    let mut shared_stack = SharedStack::<(usize, usize, usize, usize)>::new();
+    let mut tokidx = first;
+
+    // number of tokens, counted when left recursion needs it
+    let mut count = input.token_count();
 
-    while runnable.len() > 0  {
+    // results of the And/Not predicates at tokidx by nonterminal
+    let mut predicates : HashMap<usize, bool> = HashMap::new();
+
+    while !runnable.is_empty() {
         if debug_level > 2 {
             println!("at tokidx {} running {} threads",
                      tokidx, runnable.len());
         }
-        while runnable.len() > 0 {
-            let mut thread = runnable.pop().unwrap();
+        while let Some(mut thread) = runnable.pop() {
             if debug_level > 3 {
                 match cg.at(thread.ip) {
                     Opcode::Match { validx, .. } => {
@@ -304,6 +685,20 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
                                  runnable.len(),
                                  matchable.len());
                     }
+                    Opcode::MatchRegex { reidx, .. } => {
+                        println!("** {} MatchRegex /{}/ (runnable {} matchable {})",
+                                 thread.ip,
+                                 cg.regex(reidx).as_str(),
+                                 runnable.len(),
+                                 matchable.len());
+                    }
+                    Opcode::MatchClass { classidx, .. } => {
+                        println!("** {} MatchClass <{}> (runnable {} matchable {})",
+                                 thread.ip,
+                                 classes[classidx],
+                                 runnable.len(),
+                                 matchable.len());
+                    }
                     Opcode::Fork { ntidx, nameidx } => {
                         println!("** {} Fork '{}/{}' (runnable {} matchable {})",
                                  thread.ip,
@@ -322,19 +717,89 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
                                  runnable.len(),
                                  matchable.len());
                     }
+                    Opcode::And { ntidx } | Opcode::Not { ntidx } => {
+                        println!("** {} {:?} '{}' (runnable {} matchable {})",
+                                 thread.ip,
+                                 cg.at(thread.ip),
+                                 cg.debug_lookup(ntidx),
+                                 runnable.len(),
+                                 matchable.len());
+                    }
                 }
             }
             // fetch instruction at 'ip'
             match cg.at(thread.ip) {
-                Opcode::Match { validx, .. } => {
+                op @ Opcode::Match { .. } | op @ Opcode::MatchRegex { .. } | op @ Opcode::MatchClass { .. } => {
                     // maintain a sorted order in matchable
-                    // on the first item of the tuple (validx)
-                    match matchable.binary_search_by_key(&validx, |&(a, _)| a) {
-                        Ok(pos) => matchable.insert(pos, (validx, thread)),
-                        Err(pos) => matchable.insert(pos, (validx, thread))
+                    // on the first item of the tuple (cache slot)
+                    let slot = match_slot(cg, &op);
+                    match matchable.binary_search_by_key(&slot, |&(a, _)| a) {
+                        Ok(pos) => matchable.insert(pos, (slot, thread)),
+                        Err(pos) => matchable.insert(pos, (slot, thread))
                     }
                 }
                 Opcode::Fork { ntidx, nameidx } => {
+                    let need = match thread.sp {
+                        usize::MAX => 0,
+                        sp => shared_stack.top(sp).2,
+                    }.saturating_add(cg.min_tokens(thread.ip + 1));
+                    // left recursion: each pending call of the nonterminal
+                    // at this token needs at least one more token (see
+                    // token_count for inputs that do not know the count)
+                    //
+                    // a pending call the new one is the last child of, through
+                    // callers that also got no tokens and have nothing after
+                    // it, spans the same tokens: a cycle that never progresses
+                    let mut pending = 0;
+                    let mut tail = child_position(cg, thread.ip).1;
+                    let mut cycle = false;
+                    let mut sp = thread.sp;
+                    while sp != usize::MAX {
+                        let (ip, at, _, _) = *shared_stack.top(sp);
+                        if at != tokidx {
+                            break;
+                        }
+                        if let Opcode::Fork { ntidx: n, .. } = cg.at(ip) {
+                            if n == ntidx {
+                                pending += 1;
+                                cycle = cycle || tail;
+                            }
+                        }
+                        tail = tail && child_position(cg, ip).1;
+                        sp = shared_stack.pop(sp);
+                    }
+                    if pending > 0 && count.is_none() {
+                        count = Some(token_count(cg, input, tokidx));
+                    }
+                    if cycle || pending > count.unwrap_or(0).saturating_sub(tokidx) {
+                        release(&mut fragments, &mut freelist, thread.fragidx);
+                        continue;
+                    }
+                    // at the first or last component of a production with
+                    // a precedence level, skip the productions that the
+                    // check at Return would drop
+                    let (first, last) = child_position(cg, thread.ip);
+                    // (productions of one child are not checked)
+                    let edge = if first != last { cg.precedence(cg.return_addr(thread.ip)) } else { None };
+                    let remaining = input.token_count().map(|count| count.saturating_sub(tokidx));
+                    let addrs : Vec<usize> = cg.lookup_nonterm_idx(ntidx).into_iter().filter(|&addr| {
+                        // too few tokens left for the production and the callers
+                        if remaining.is_some_and(|r| need.saturating_add(cg.min_tokens(addr)) > r) {
+                            return false;
+                        }
+                        match (edge, cg.precedence(cg.return_addr(addr))) {
+                            (Some((level, assoc)), Some((l, _))) => {
+                                let dropped_first = l < level || (l == level && assoc != Assoc::Left);
+                                let dropped_last = l < level || (l == level && assoc != Assoc::Right);
+                                !((first && dropped_first) || (last && dropped_last))
+                            }
+                            _ => true,
+                        }
+                    }).collect();
+                    if addrs.is_empty() {
+                        release(&mut fragments, &mut freelist, thread.fragidx);
+                        continue;
+                    }
                     // ordering: [1] depends on [2]
                     let frag = ParseFragment {
                         refcount: 0,
@@ -358,7 +823,7 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
                         }
                     }
 
-                    for initial_thread_addr in cg.lookup_nonterm_idx(ntidx) {
+                    for initial_thread_addr in addrs {
                         if debug_level > 4 {
                             println!("forking '{}' -> addr {} fragidx {}",
                                      cg.debug_lookup(ntidx),
@@ -368,7 +833,7 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
                         fragments[fragment_idx].refcount += 1;
                         let vmt = VMThread {
                             // continue stack from parent thread
-                            sp: shared_stack.push(thread.sp, thread.ip),
+                            sp: shared_stack.push(thread.sp, (thread.ip, tokidx, need, fragment_idx)),
                             ip: initial_thread_addr,
                             fragidx: fragment_idx, // [1]
                         };
@@ -376,7 +841,41 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
                         runnable.push(vmt);
                     }
                 }
+                op @ Opcode::And { .. } | op @ Opcode::Not { .. } => {
+                    let (ntidx, wanted) = match op {
+                        Opcode::And { ntidx } => (ntidx, true),
+                        Opcode::Not { ntidx } => (ntidx, false),
+                        _ => unreachable!(),
+                    };
+                    // parse the nonterminal on its own from this token,
+                    // the thread keeps its fragments and token index
+                    // a predicate that depends on itself at the same
+                    // token does not match
+                    let matched = *predicates.entry(ntidx).or_insert_with(|| {
+                        if active.contains(&(ntidx, tokidx)) {
+                            return false;
+                        }
+                        let mut nested = active.to_vec();
+                        nested.push((ntidx, tokidx));
+                        run_nonterm(ntidx, cg, input, 0, tokidx, &nested).count() > 0
+                    });
+                    if matched == wanted {
+                        thread.ip += 1;
+                        runnable.push(thread);
+                    } else {
+                        release(&mut fragments, &mut freelist, thread.fragidx);
+                    }
+                }
                 Opcode::Return { ntnameidx, nameidx } => {
+                    let at = match thread.sp {
+                        usize::MAX => first,
+                        sp => shared_stack.top(sp).1,
+                    };
+                    if precedence_conflict(cg, &fragments, thread.fragidx, thread.ip)
+                        || same_span_cycle(&fragments, thread.fragidx, ntnameidx, (at, tokidx)) {
+                        release(&mut fragments, &mut freelist, thread.fragidx);
+                        continue;
+                    }
                     // check if the thread has a return value
                     // or whether it is a top-level thread
                     if thread.sp != usize::MAX {
@@ -384,8 +883,11 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
                             refcount: 1,
                             value: FragmentType::RuleNonTerm {
                                 child: thread.fragidx,
-                                ntnameidx: ntnameidx,
+                                ntnameidx,
                                 ev_name: nameidx,
+                                retaddr: thread.ip,
+                                start: shared_stack.top(thread.sp).3,
+                                span: (at, tokidx),
                             },
                         };
 
@@ -402,7 +904,7 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
                             }
                         }
 
-                        let ret = shared_stack.top(thread.sp);
+                        let ret = shared_stack.top(thread.sp).0;
                         thread.sp = shared_stack.pop(thread.sp);
                         thread.ip = ret + 1;
                         thread.fragidx = fragment_idx;
@@ -420,38 +922,67 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
         assert_eq!(runnable.len(), 0);
 
         matchable.reverse();
-        for n in 0..cg.strings.len() {
-            matched[n] = 0;
+        for m in matched.iter_mut() {
+            *m = 0;
         }
-        if debug_level > 1 && matchable.len() > 0 {
+        if debug_level > 1 && !matchable.is_empty() {
             println!("matching {} threads at token index {}",
                      matchable.len(), tokidx);
         }
-        let mut prev_validx = usize::MAX;
-        while matchable.len() > 0 {
-            let tuple = matchable.pop().unwrap();
+        let mut prev_slot = usize::MAX;
+        while let Some(tuple) = matchable.pop() {
             // check that the matchable array is sorted
-            assert!(prev_validx == usize::MAX ||
-                    prev_validx <= tuple.0);
-            prev_validx = tuple.0;
+            assert!(prev_slot == usize::MAX ||
+                    prev_slot <= tuple.0);
+            prev_slot = tuple.0;
+            let slot = tuple.0;
             let mut thread = tuple.1;
 
             match cg.at(thread.ip) {
-                Opcode::Match { validx, nameidx } => {
+                op @ Opcode::Match { .. } | op @ Opcode::MatchRegex { .. } | op @ Opcode::MatchClass { .. } => {
                     let match_result;
                     // reuse previous match result if there is one
-                    if matched[validx] == 1 {
+                    if matched[slot] == 1 {
                         match_result = true;
-                    } else if matched[validx] == -1 {
+                    } else if matched[slot] == -1 {
                         match_result = false;
                     } else {
-                        match_result = match_fn(&cg.strings[validx], tokidx);
+                        match_result = match op {
+                            Opcode::Match { validx, fold, .. } => {
+                                match_literal(cg, input, &cg.strings[validx], fold, tokidx)
+                            }
+                            Opcode::MatchRegex { reidx, .. } => {
+                                match input.text(tokidx) {
+                                    Some(text) if cg.normalization().nfc => cg.regex(reidx).is_match(&nfc(text)),
+                                    Some(text) => cg.regex(reidx).is_match(text),
+                                    None => false,
+                                }
+                            }
+                            Opcode::MatchClass { classidx, .. } => {
+                                // registered matchers before %token declarations,
+                                // whose name the lexer recorded
+                                let token = cg.lexer().token(&classes[classidx]);
+                                match (class_fns[classidx], input.lexeme(tokidx), input.text(tokidx)) {
+                                    (Some(f), _, Some(text)) => f(text),
+                                    (None, Some(l), _) if token.is_some() =>
+                                        l.token.as_deref() == Some(classes[classidx].as_str()),
+                                    (None, _, Some(text)) => token.is_some_and(|re| re.is_match(text)),
+                                    _ => false,
+                                }
+                            }
+                            _ => false,
+                        };
                         if match_result {
-                            matched[validx] = 1;
+                            matched[slot] = 1;
                         } else {
-                            matched[validx] = -1;
+                            matched[slot] = -1;
                         }
                     }
+                    let nameidx = match op {
+                        Opcode::Match { nameidx, .. } | Opcode::MatchRegex { nameidx, .. } |
+                        Opcode::MatchClass { nameidx, .. } => nameidx,
+                        _ => None,
+                    };
 
                     if match_result {
                         // allow this thread to proceed
@@ -462,7 +993,7 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
                             refcount: 1,
                             value: FragmentType::RuleTermValue {
                                 prev: prev_fragidx,
-                                tokidx: tokidx,
+                                tokidx,
                                 name: nameidx,
                             },
                         };
@@ -484,24 +1015,7 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
                         runnable.push(thread);
                     } else {
                         // thread terminated, release all his fragments
-                        let mut fragidx = thread.fragidx;
-                        while fragidx != usize::MAX {
-                            assert!(fragments[fragidx].refcount > 0);
-                            fragments[fragidx].refcount -= 1;
-                            if fragments[fragidx].refcount == 0 {
-                                // maintain a sorted freelist
-                                match freelist.binary_search(&fragidx) {
-                                    Ok(pos) => freelist.insert(pos, fragidx),
-                                    Err(pos) => freelist.insert(pos, fragidx),
-                                }
-                                // continue to the prev element
-                                fragidx = prev_fragment(&fragments, fragidx, usize::MAX);
-                            } else {
-                                // element (and all his prev elements) not garbage
-                                // collectable due to rc > 0
-                                break;
-                            }
-                        }
+                        release(&mut fragments, &mut freelist, thread.fragidx);
                     }
                 },
                 _ => {
@@ -512,6 +1026,7 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
         assert_eq!(matchable.len(), 0);
 
         tokidx += 1;
+        predicates.clear();
 
         if debug_level > 4 {
             println!("GC total {} runnable {} freelist {}",
@@ -519,5 +1034,5 @@ pub fn run<F>(nt_start : &str, cg : &CompiledGrammar, match_fn: F, min_match: us
         }
     }
 
-    ParsedTrees::new(fragments, tails, cg.strings.clone())
+    ParsedTrees::new(fragments, tails, cg.strings.clone(), cg.hidden())
 }
@@ -0,0 +1,462 @@
+/*!
+ * Yacc/Bison front-end
+ *
+ * Reads a .y file (or only its rule section) into a Grammar:
+ *
+ *   %token NUMBER
+ *   %left '+'
+ *   %%
+ *   expr : expr '+' expr   { $$ = $1 + $3; }
+ *        | NUMBER
+ *        ;
+ *   %%
+ *
+ * Action code, %{ %} blocks and the section after the second %% are
+ * skipped. Names declared with %token (or %left, %right, %nonassoc,
+ * %precedence) are terminals matched by their name, 'c' literals are
+ * terminals matched by the character. The %start nonterminal, or the
+ * first rule, is the start symbol. %left, %right and %nonassoc
+ * (%precedence as %nonassoc) become precedence levels, %prec X gives
+ * a production the level of X. Rule names with '.' have it replaced
+ * by '_'.
+ */
+
+use std::collections::HashSet;
+
+use grammar::{Grammar,GrammarError,Production,Component,RuleId,Assoc,identifiers};
+use tokenize::{Position, unescape};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Ident(String),
+    // 'c' or "alias", without the quotes
+    Literal(String),
+    // %token, %prec, ...
+    Directive(String),
+    // %%
+    Sections,
+    Colon,
+    Pipe,
+    Semi,
+    // <type>
+    Tag,
+    Number,
+    // { code }
+    Action,
+    Other(char),
+}
+
+struct Token {
+    tok : Tok,
+    pos : Position,
+}
+
+struct Lexer<'a> {
+    chars : ::std::iter::Peekable<::std::str::Chars<'a>>,
+    pos : Position,
+    errors : Vec<GrammarError>,
+}
+
+impl<'a> Lexer<'a> {
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().cloned()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if let Some(ch) = c {
+            self.pos.update(ch);
+        }
+        c
+    }
+
+    // skip up to and including 'end' (e.g. "*/" or "%}")
+    fn skip_past(&mut self, end : &str, pos : &Position) {
+        let end : Vec<char> = end.chars().collect();
+        let mut last : Vec<char> = Vec::new();
+        while let Some(c) = self.bump() {
+            last.push(c);
+            if last.ends_with(&end) {
+                return;
+            }
+        }
+        let expected = format!("'{}'", end.iter().collect::<String>());
+        self.errors.push(GrammarError::new(pos.clone(), &expected, "end of input"));
+    }
+
+    // after the opening quote, returns the text without the quotes
+    // and with the escapes decoded
+    fn quoted(&mut self, quote : char, pos : &Position) -> String {
+        let mut text = String::new();
+        loop {
+            match self.bump() {
+                Some(c) if c == quote => {
+                    return match unescape(&text, '\\') {
+                        Ok(value) => value,
+                        Err(e) => {
+                            let found = format!("'{}'", e.sequence);
+                            self.errors.push(GrammarError::new(pos.clone(), "escape sequence", found));
+                            text
+                        }
+                    };
+                }
+                Some('\\') => {
+                    text.push('\\');
+                    if let Some(c) = self.bump() {
+                        text.push(c);
+                    }
+                }
+                Some('\n') | None => {
+                    let expected = format!("{} to end the literal", quote);
+                    self.errors.push(GrammarError::new(pos.clone(), &expected, "end of line"));
+                    return text;
+                }
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    // after '{': skip C code up to the matching '}'
+    fn action(&mut self, pos : &Position) {
+        let mut depth = 1;
+        while let Some(c) = self.bump() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return;
+                    }
+                }
+                '"' | '\'' => {
+                    let p = self.pos.clone();
+                    self.quoted(c, &p);
+                }
+                '/' if self.peek() == Some('*') => self.skip_past("*/", pos),
+                '/' if self.peek() == Some('/') => self.skip_past("\n", pos),
+                _ => {}
+            }
+        }
+        self.errors.push(GrammarError::new(pos.clone(), "'}' to end the action", "end of input"));
+    }
+
+    // tokens up to the second %%
+    fn lex(mut self) -> (Vec<Token>, Vec<GrammarError>) {
+        let mut tokens : Vec<Token> = Vec::new();
+        let mut sections = 0;
+        while let Some(c) = self.peek() {
+            let pos = self.pos.clone();
+            self.bump();
+            let tok = match c {
+                ' ' | '\t' | '\r' | '\n' => continue,
+                '/' if self.peek() == Some('*') => {
+                    self.skip_past("*/", &pos);
+                    continue;
+                }
+                '/' if self.peek() == Some('/') => {
+                    self.skip_past("\n", &pos);
+                    continue;
+                }
+                '%' if self.peek() == Some('{') => {
+                    self.skip_past("%}", &pos);
+                    continue;
+                }
+                '%' if self.peek() == Some('%') => {
+                    self.bump();
+                    sections += 1;
+                    if sections == 2 {
+                        break;
+                    }
+                    Tok::Sections
+                }
+                '%' => {
+                    let mut name = String::from("%");
+                    while let Some(c) = self.peek() {
+                        if !c.is_alphanumeric() && c != '_' && c != '-' {
+                            break;
+                        }
+                        name.push(c);
+                        self.bump();
+                    }
+                    Tok::Directive(name)
+                }
+                '\'' | '"' => Tok::Literal(self.quoted(c, &pos)),
+                '{' => {
+                    self.action(&pos);
+                    Tok::Action
+                }
+                '<' => {
+                    self.skip_past(">", &pos);
+                    Tok::Tag
+                }
+                ':' => Tok::Colon,
+                '|' => Tok::Pipe,
+                ';' => Tok::Semi,
+                c if c.is_ascii_digit() => {
+                    while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                        self.bump();
+                    }
+                    Tok::Number
+                }
+                c if c.is_alphabetic() || c == '_' || c == '.' => {
+                    let mut name = c.to_string();
+                    while let Some(c) = self.peek() {
+                        if !c.is_alphanumeric() && c != '_' && c != '.' {
+                            break;
+                        }
+                        name.push(c);
+                        self.bump();
+                    }
+                    Tok::Ident(name)
+                }
+                c => Tok::Other(c),
+            };
+            tokens.push(Token { tok, pos });
+        }
+        (tokens, self.errors)
+    }
+}
+
+// a symbol of a production: name or literal, position
+type Symbol = (Tok, Position);
+
+struct Parser {
+    tokens : Vec<Token>,
+    next : usize,
+    errors : Vec<GrammarError>,
+    // terminal names from the declarations
+    declared : HashSet<String>,
+    start : Option<(String, Position)>,
+    // precedence levels, lowest first
+    precedence : Vec<(Assoc, Vec<String>)>,
+    // nonterminal, symbols and %prec symbol of each production in order
+    rules : Vec<(String, Vec<Symbol>, Option<Symbol>)>,
+}
+
+impl Parser {
+
+    fn peek_at(&self, n : usize) -> Option<&Tok> {
+        self.tokens.get(self.next + n).map(|t| &t.tok)
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.peek_at(0)
+    }
+
+    fn error(&mut self, expected : &str) {
+        let e = match self.tokens.get(self.next) {
+            Some(t) => {
+                let found = match t.tok {
+                    Tok::Ident(ref n) => format!("'{}'", n),
+                    Tok::Literal(ref l) => format!("'{}'", l),
+                    Tok::Directive(ref d) => format!("'{}'", d),
+                    Tok::Sections => "'%%'".to_string(),
+                    Tok::Colon => "':'".to_string(),
+                    Tok::Pipe => "'|'".to_string(),
+                    Tok::Semi => "';'".to_string(),
+                    Tok::Tag => "type tag".to_string(),
+                    Tok::Number => "number".to_string(),
+                    Tok::Action => "action".to_string(),
+                    Tok::Other(c) => format!("'{}'", c),
+                };
+                GrammarError::new(t.pos.clone(), expected, found)
+            }
+            None => {
+                let pos = self.tokens.last().map_or(Position::new(), |t| t.pos.clone());
+                GrammarError::new(pos, expected, "end of input")
+            }
+        };
+        self.errors.push(e);
+    }
+
+    // true at 'name :', the start of the next rule
+    fn at_rule(&self) -> bool {
+        matches!((self.peek(), self.peek_at(1)), (Some(&Tok::Ident(_)), Some(&Tok::Colon)))
+    }
+
+    // %token, %left, ... lines before the first %%
+    fn declarations(&mut self) {
+        let mut directive = String::new();
+        while let Some(tok) = self.peek().cloned() {
+            let pos = self.tokens[self.next].pos.clone();
+            self.next += 1;
+            match tok {
+                Tok::Sections => return,
+                Tok::Directive(d) => {
+                    let assoc = match d.as_str() {
+                        "%left" => Some(Assoc::Left),
+                        "%right" => Some(Assoc::Right),
+                        "%nonassoc" | "%precedence" => Some(Assoc::NonAssoc),
+                        _ => None,
+                    };
+                    if let Some(a) = assoc {
+                        self.precedence.push((a, Vec::new()));
+                    }
+                    directive = d;
+                }
+                Tok::Ident(name) => {
+                    match directive.as_str() {
+                        "%token" => {
+                            self.declared.insert(name);
+                        }
+                        "%left" | "%right" | "%nonassoc" | "%precedence" => {
+                            self.declared.insert(name.clone());
+                            self.precedence.last_mut().unwrap().1.push(name);
+                        }
+                        "%start" => self.start = Some((name, pos)),
+                        _ => {}
+                    }
+                }
+                Tok::Literal(l) => {
+                    if let "%left" | "%right" | "%nonassoc" | "%precedence" = directive.as_str() {
+                        self.precedence.last_mut().unwrap().1.push(l);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn rule_section(&mut self) {
+        while self.peek().is_some() {
+            if !self.at_rule() {
+                self.error("rule name and ':'");
+                // skip to the next rule
+                self.next += 1;
+                while self.peek().is_some() && !self.at_rule() {
+                    self.next += 1;
+                }
+                continue;
+            }
+            let name = match self.peek() {
+                Some(Tok::Ident(n)) => n.clone(),
+                _ => unreachable!(),
+            };
+            self.next += 2;
+            self.alternatives(&name);
+        }
+    }
+
+    // alternatives of a rule, up to ';' or the next rule
+    fn alternatives(&mut self, name : &str) {
+        let mut symbols : Vec<Symbol> = Vec::new();
+        let mut prec : Option<Symbol> = None;
+        loop {
+            if self.at_rule() {
+                self.rules.push((name.to_string(), symbols, prec));
+                return;
+            }
+            let tok = match self.peek() {
+                Some(t) => t.clone(),
+                None => {
+                    self.rules.push((name.to_string(), symbols, prec));
+                    return;
+                }
+            };
+            let pos = self.tokens[self.next].pos.clone();
+            self.next += 1;
+            match tok {
+                Tok::Semi => {
+                    self.rules.push((name.to_string(), symbols, prec));
+                    return;
+                }
+                Tok::Pipe => {
+                    self.rules.push((name.to_string(), symbols, prec.take()));
+                    symbols = Vec::new();
+                }
+                Tok::Ident(_) | Tok::Literal(_) => symbols.push((tok, pos)),
+                Tok::Directive(ref d) if d == "%prec" => {
+                    // the precedence symbol is not part of the production
+                    match self.peek().cloned() {
+                        Some(t @ Tok::Ident(_)) | Some(t @ Tok::Literal(_)) => {
+                            prec = Some((t, self.tokens[self.next].pos.clone()));
+                            self.next += 1;
+                        }
+                        _ => self.error("token after %prec"),
+                    }
+                }
+                Tok::Action | Tok::Tag | Tok::Number | Tok::Directive(_) => {}
+                _ => {
+                    self.next -= 1;
+                    self.error("symbol, action, '|' or ';'");
+                    self.next += 1;
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Load a yacc/bison grammar
+ */
+pub fn load_yacc_str(input : &str) -> Result<Grammar, Vec<GrammarError>> {
+    let lexer = Lexer { chars : input.chars().peekable(), pos : Position::new(), errors : Vec::new() };
+    let (tokens, errors) = lexer.lex();
+    let has_sections = tokens.iter().any(|t| t.tok == Tok::Sections);
+    let mut p = Parser {
+        tokens,
+        next : 0,
+        errors,
+        declared : HashSet::new(),
+        start : None,
+        precedence : Vec::new(),
+        rules : Vec::new(),
+    };
+    if has_sections {
+        p.declarations();
+    }
+    p.rule_section();
+
+    let defined : HashSet<&String> = p.rules.iter().map(|r| &r.0).collect();
+    let ids = identifiers(p.rules.iter().map(|r| &r.0));
+    let mut g = Grammar::new();
+    let mut errors = p.errors.clone();
+    let mut prods : Vec<(String, Production)> = Vec::new();
+    for (nonterm, symbols, prec) in &p.rules {
+        let mut prod = Production::new();
+        if let Some((Tok::Ident(ref n), ref pos)) | Some((Tok::Literal(ref n), ref pos)) = *prec {
+            if p.precedence.iter().any(|level| level.1.contains(n)) {
+                prod.precedence = Some(n.clone());
+            } else {
+                errors.push(GrammarError::new(pos.clone(), "token with a precedence level", format!("'{}'", n)));
+            }
+        }
+        for (tok, pos) in symbols {
+            let rule = match *tok {
+                Tok::Ident(ref n) if defined.contains(n) => RuleId::Nonterminal(ids[n].clone()),
+                Tok::Ident(ref n) if p.declared.contains(n) || n == "error" => RuleId::Terminal(n.clone()),
+                Tok::Ident(ref n) => {
+                    errors.push(GrammarError::new(pos.clone(), "declared token or rule", format!("'{}'", n)));
+                    continue;
+                }
+                Tok::Literal(ref l) => RuleId::Terminal(l.clone()),
+                _ => continue,
+            };
+            prod.components.push(Component::new(rule));
+        }
+        prods.push((ids[nonterm].clone(), prod));
+    }
+    let first_rule = p.rules.first().map(|r| ids[&r.0].clone());
+    match p.start {
+        Some((ref name, ref pos)) if !defined.contains(name) => {
+            errors.push(GrammarError::new(pos.clone(), "defined start nonterminal", format!("'{}'", name)));
+        }
+        Some((ref name, _)) => g.add_start(&ids[name]),
+        None => {
+            if let Some(ref name) = first_rule {
+                g.add_start(name);
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    for (nonterm, prod) in prods {
+        g.add_rule(&nonterm, prod);
+    }
+    for (assoc, terminals) in &p.precedence {
+        g.add_precedence(*assoc, terminals);
+    }
+    Ok(g)
+}